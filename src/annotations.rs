@@ -1,7 +1,10 @@
-use lopdf::{Document, Object, Dictionary, StringFormat};
-use std::collections::{HashMap};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, SubsecRound};
+use lopdf::{dictionary, Document, IncrementalDocument, Object, ObjectId, Dictionary, Stream, StringFormat};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::str;
 use uuid::Uuid;
+use crate::outline::{self, OutlineItem};
 use std::{time};
 
 // 外部で使うために pub をつける
@@ -12,8 +15,447 @@ pub struct AnnotationData {
     pub y: f64,          // UI座標 (Top-Left 0,0)
     pub content: String,
     pub font_size: Option<f32>,
-    pub id: String,      // UIでの識別用ID
+    pub id: String,      // UIでの識別用ID (PDFの /NM として保存される)
     pub object_id: Option<(u32, u16)>, 
+    pub kind: AnnotationKind,
+    pub color: Option<(f64, f64, f64)>, // /C (RGB)
+    // FreeText の枠の幅と高さ (UI座標)。None なら内容に合わせて伸び縮みする
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    // FreeText の文字・背景・枠線の見た目
+    pub text_style: TextStyle,
+    // 作成者 (/T) と作成日時 (/CreationDate)・更新日時 (/M)。他のビューアで作られた注釈には無いこともある
+    pub author: Option<String>,
+    pub created: Option<DateTime<FixedOffset>>,
+    pub modified: Option<DateTime<FixedOffset>>,
+    // この注釈への返信と状態の変更 (古い順)
+    pub replies: Vec<Reply>,
+    // 実際に描画された範囲 (UI座標の x, y, 幅, 高さ)。描画時に記録し、当たり判定・選択枠・/Rect に使う
+    // 内容や位置を変えたら None に戻して再計算させる
+    pub bounds: Cell<Option<(f64, f64, f64, f64)>>,
+}
+
+// FreeText の見た目
+// 文字色とフォントは /DA と /DS、背景は /C (空の配列なら透明)、枠線は /BS、不透明度は /CA に保存する
+// 不透明度を指定していなければ /CA は書かず、背景だけを DEFAULT_BACKGROUND_ALPHA で少し透かして描く
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextStyle {
+    pub font_family: FontFamily,
+    pub text_color: (f64, f64, f64),
+    pub background: Option<(f64, f64, f64)>, // None なら背景なし
+    pub border_width: f64,                    // 0 なら枠線なし (枠線は文字色で描く)
+    pub opacity: Option<f64>,                 // None なら指定なし (背景だけ少し透かす)
+}
+
+pub const DEFAULT_BACKGROUND: (f64, f64, f64) = (1.0, 1.0, 0.8);
+pub const DEFAULT_BACKGROUND_ALPHA: f64 = 0.8;
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            font_family: FontFamily::Serif,
+            text_color: (0.0, 0.0, 0.0),
+            background: Some(DEFAULT_BACKGROUND),
+            border_width: 0.0,
+            opacity: None,
+        }
+    }
+}
+
+// 注釈の書体。MathJax でも描けるよう、総称ファミリーの3種類だけを扱う
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FontFamily {
+    Serif,
+    Sans,
+    Monospace,
+}
+
+impl FontFamily {
+    pub const ALL: [FontFamily; 3] = [FontFamily::Serif, FontFamily::Sans, FontFamily::Monospace];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            FontFamily::Serif => "Serif",
+            FontFamily::Sans => "Sans",
+            FontFamily::Monospace => "Monospace",
+        }
+    }
+
+    // /DA で参照する標準フォントのリソース名
+    fn da_font(&self) -> &'static str {
+        match self {
+            FontFamily::Serif => "TiRo",
+            FontFamily::Sans => "Helv",
+            FontFamily::Monospace => "Cour",
+        }
+    }
+
+    // /DS (CSS) のフォント名
+    fn css_family(&self) -> &'static str {
+        match self {
+            FontFamily::Serif => "Times",
+            FontFamily::Sans => "Helvetica",
+            FontFamily::Monospace => "Courier",
+        }
+    }
+
+    fn from_css_family(family: &str) -> Self {
+        let family = family.to_ascii_lowercase();
+        if family.contains("courier") || family.contains("mono") {
+            FontFamily::Monospace
+        } else if family.contains("times") || (family.contains("serif") && !family.contains("sans")) {
+            FontFamily::Serif
+        } else {
+            FontFamily::Sans
+        }
+    }
+}
+
+// 注釈への返信。PDF では /IRT で親の注釈を指す Text 注釈として保存する
+// 状態 (/State) を持つものは、レビューの状態を変えた記録
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub id: String, // /NM
+    pub object_id: Option<(u32, u16)>,
+    pub author: Option<String>,
+    pub content: String,
+    pub created: Option<DateTime<FixedOffset>>,
+    pub modified: Option<DateTime<FixedOffset>>,
+    pub state: Option<ReviewState>,
+    // 返信先の返信の id (/NM)。None なら注釈そのものへの返信
+    pub parent: Option<String>,
+}
+
+// 注釈の状態 (/State)。Review と Marked の2つの状態モデル (/StateModel) がある
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewState {
+    Accepted,
+    Rejected,
+    Cancelled,
+    Completed,
+    None,
+    Marked,
+    Unmarked,
+}
+
+impl ReviewState {
+    // サイドバーで選べる状態 (Review モデル)
+    pub const REVIEW: [ReviewState; 5] = [
+        ReviewState::Accepted,
+        ReviewState::Rejected,
+        ReviewState::Cancelled,
+        ReviewState::Completed,
+        ReviewState::None,
+    ];
+    const ALL: [ReviewState; 7] = [
+        ReviewState::Accepted,
+        ReviewState::Rejected,
+        ReviewState::Cancelled,
+        ReviewState::Completed,
+        ReviewState::None,
+        ReviewState::Marked,
+        ReviewState::Unmarked,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReviewState::Accepted => "Accepted",
+            ReviewState::Rejected => "Rejected",
+            ReviewState::Cancelled => "Cancelled",
+            ReviewState::Completed => "Completed",
+            ReviewState::None => "None",
+            ReviewState::Marked => "Marked",
+            ReviewState::Unmarked => "Unmarked",
+        }
+    }
+
+    fn model(&self) -> &'static str {
+        match self {
+            ReviewState::Marked | ReviewState::Unmarked => "Marked",
+            _ => "Review",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.label().eq_ignore_ascii_case(name))
+    }
+}
+
+impl AnnotationData {
+    // 現在のレビューの状態 (最後に変えられた Review モデルの状態。"None" に戻されていれば None)
+    pub fn review_state(&self) -> Option<ReviewState> {
+        self.replies.iter()
+            .rev()
+            .filter_map(|r| r.state)
+            .find(|s| s.model() == "Review")
+            .filter(|s| *s != ReviewState::None)
+    }
+}
+
+// アノテーションの種類と、種類ごとの形状データ
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotationKind {
+    FreeText,
+    // テキストマークアップ。rects は選択範囲を行ごとに分けた矩形 (UI座標: x, y, 幅, 高さ)
+    Markup { style: MarkupStyle, rects: Vec<(f64, f64, f64, f64)> },
+    // 手書き。strokes は線ごとの点列 (UI座標 x, y と筆圧 0.0-1.0)、width は基準の線幅
+    Ink { strokes: Vec<Vec<(f64, f64, f64)>>, width: f64 },
+    // 図形。points は Square/Circle では外枠の対角2点、Line では始点と終点、
+    // Polygon/PolyLine では頂点 (いずれもUI座標)。fill は内部の色 (/IC)、opacity は /CA
+    Shape {
+        shape: ShapeKind,
+        points: Vec<(f64, f64)>,
+        width: f64,
+        fill: Option<(f64, f64, f64)>,
+        opacity: f64,
+        line_endings: (LineEnding, LineEnding), // Line/PolyLine の両端 (/LE)
+    },
+    // 付箋 (/Text)。(x, y) にアイコンを置き、開いているときは popup (UI座標 x, y, 幅, 高さ) に本文を出す
+    // ポップアップは /Popup 注釈として別に保存する
+    Note { icon: NoteIcon, open: bool, popup: (f64, f64, f64, f64) },
+}
+
+// 付箋のアイコン (/Name)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteIcon {
+    Comment,
+    Key,
+    Note,
+    Help,
+    NewParagraph,
+    Paragraph,
+    Insert,
+}
+
+impl NoteIcon {
+    const ALL: [NoteIcon; 7] = [
+        NoteIcon::Comment,
+        NoteIcon::Key,
+        NoteIcon::Note,
+        NoteIcon::Help,
+        NoteIcon::NewParagraph,
+        NoteIcon::Paragraph,
+        NoteIcon::Insert,
+    ];
+
+    fn name(&self) -> &'static [u8] {
+        match self {
+            NoteIcon::Comment => b"Comment",
+            NoteIcon::Key => b"Key",
+            NoteIcon::Note => b"Note",
+            NoteIcon::Help => b"Help",
+            NoteIcon::NewParagraph => b"NewParagraph",
+            NoteIcon::Paragraph => b"Paragraph",
+            NoteIcon::Insert => b"Insert",
+        }
+    }
+
+    // 指定が無い・知らない名前なら /Note (PDF の既定値)
+    fn from_name(name: &[u8]) -> Self {
+        NoteIcon::ALL.into_iter().find(|i| i.name() == name).unwrap_or(NoteIcon::Note)
+    }
+}
+
+// 付箋のアイコンの大きさ (Acrobat と同じ 20pt 四方)
+pub const NOTE_ICON_SIZE: f64 = 20.0;
+pub const NOTE_COLOR: (f64, f64, f64) = (1.0, 0.85, 0.2);
+
+// /Popup が無い付箋のポップアップの位置と大きさ (アイコンの右隣)
+pub fn default_note_popup(x: f64, y: f64) -> (f64, f64, f64, f64) {
+    (x + NOTE_ICON_SIZE + 4.0, y, 180.0, 120.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShapeKind {
+    Square,
+    Circle,
+    Line,
+    Polygon,
+    PolyLine,
+}
+
+impl ShapeKind {
+    const ALL: [ShapeKind; 5] = [
+        ShapeKind::Square,
+        ShapeKind::Circle,
+        ShapeKind::Line,
+        ShapeKind::Polygon,
+        ShapeKind::PolyLine,
+    ];
+
+    fn subtype(&self) -> &'static [u8] {
+        match self {
+            ShapeKind::Square => b"Square",
+            ShapeKind::Circle => b"Circle",
+            ShapeKind::Line => b"Line",
+            ShapeKind::Polygon => b"Polygon",
+            ShapeKind::PolyLine => b"PolyLine",
+        }
+    }
+
+    fn from_subtype(subtype: &[u8]) -> Option<Self> {
+        ShapeKind::ALL.into_iter().find(|s| s.subtype() == subtype)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ShapeKind::Square => "Rectangle",
+            ShapeKind::Circle => "Ellipse",
+            ShapeKind::Line => "Line",
+            ShapeKind::Polygon => "Polygon",
+            ShapeKind::PolyLine => "Polyline",
+        }
+    }
+
+    // 塗りつぶしできる (閉じた) 図形か
+    pub fn is_closed(&self) -> bool {
+        matches!(self, ShapeKind::Square | ShapeKind::Circle | ShapeKind::Polygon)
+    }
+}
+
+// 線の端の形 (/LE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    None,
+    Square,
+    Circle,
+    Diamond,
+    OpenArrow,
+    ClosedArrow,
+    Butt,
+    ROpenArrow,
+    RClosedArrow,
+    Slash,
+}
+
+impl LineEnding {
+    const ALL: [LineEnding; 10] = [
+        LineEnding::None,
+        LineEnding::Square,
+        LineEnding::Circle,
+        LineEnding::Diamond,
+        LineEnding::OpenArrow,
+        LineEnding::ClosedArrow,
+        LineEnding::Butt,
+        LineEnding::ROpenArrow,
+        LineEnding::RClosedArrow,
+        LineEnding::Slash,
+    ];
+
+    fn name(&self) -> &'static [u8] {
+        match self {
+            LineEnding::None => b"None",
+            LineEnding::Square => b"Square",
+            LineEnding::Circle => b"Circle",
+            LineEnding::Diamond => b"Diamond",
+            LineEnding::OpenArrow => b"OpenArrow",
+            LineEnding::ClosedArrow => b"ClosedArrow",
+            LineEnding::Butt => b"Butt",
+            LineEnding::ROpenArrow => b"ROpenArrow",
+            LineEnding::RClosedArrow => b"RClosedArrow",
+            LineEnding::Slash => b"Slash",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> LineEnding {
+        LineEnding::ALL.into_iter().find(|e| e.name() == name).unwrap_or(LineEnding::None)
+    }
+}
+
+// 線の端の飾り (矢印など) の大きさ
+pub fn line_ending_size(width: f64) -> f64 {
+    (width * 4.0).max(6.0)
+}
+
+// 図形全体を囲む矩形 (UI座標)。線の太さと端の飾りの分も含める
+pub fn shape_bounds(points: &[(f64, f64)], width: f64, line_endings: (LineEnding, LineEnding)) -> Option<(f64, f64, f64, f64)> {
+    let mut margin = width / 2.0;
+    if line_endings != (LineEnding::None, LineEnding::None) {
+        margin += line_ending_size(width);
+    }
+    let rects: Vec<_> = points
+        .iter()
+        .map(|&(x, y)| (x - margin, y - margin, margin * 2.0, margin * 2.0))
+        .collect();
+    bounding_rect(&rects)
+}
+
+// 筆圧が取れない入力 (マウスなど) の筆圧。この値のとき線幅がちょうど基準の太さになる
+pub const DEFAULT_PRESSURE: f64 = 0.5;
+
+// 筆圧から実際の線幅を求める
+pub fn ink_line_width(width: f64, pressure: f64) -> f64 {
+    width * (0.5 + pressure)
+}
+
+// 手書きの線全体を囲む矩形 (UI座標)。線の太さの分も含める
+pub fn ink_bounds(strokes: &[Vec<(f64, f64, f64)>], width: f64) -> Option<(f64, f64, f64, f64)> {
+    let margin = ink_line_width(width, 1.0) / 2.0;
+    let rects: Vec<_> = strokes
+        .iter()
+        .flatten()
+        .map(|&(x, y, _)| (x - margin, y - margin, margin * 2.0, margin * 2.0))
+        .collect();
+    bounding_rect(&rects)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkupStyle {
+    Highlight,
+    Underline,
+    StrikeOut,
+    Squiggly,
+}
+
+impl MarkupStyle {
+    pub const ALL: [MarkupStyle; 4] = [
+        MarkupStyle::Highlight,
+        MarkupStyle::Underline,
+        MarkupStyle::StrikeOut,
+        MarkupStyle::Squiggly,
+    ];
+
+    // PDFの /Subtype
+    fn subtype(&self) -> &'static [u8] {
+        match self {
+            MarkupStyle::Highlight => b"Highlight",
+            MarkupStyle::Underline => b"Underline",
+            MarkupStyle::StrikeOut => b"StrikeOut",
+            MarkupStyle::Squiggly => b"Squiggly",
+        }
+    }
+
+    fn from_subtype(subtype: &[u8]) -> Option<Self> {
+        MarkupStyle::ALL.into_iter().find(|s| s.subtype() == subtype)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            MarkupStyle::Highlight => "Highlight",
+            MarkupStyle::Underline => "Underline",
+            MarkupStyle::StrikeOut => "Strikeout",
+            MarkupStyle::Squiggly => "Squiggly",
+        }
+    }
+
+    pub fn default_color(&self) -> (f64, f64, f64) {
+        match self {
+            MarkupStyle::Highlight => (1.0, 0.9, 0.0),
+            MarkupStyle::Underline => (0.0, 0.6, 0.2),
+            MarkupStyle::StrikeOut => (0.9, 0.1, 0.1),
+            MarkupStyle::Squiggly => (0.2, 0.4, 1.0),
+        }
+    }
+}
+
+// 保存時に埋め込む外観ストリーム (/AP /N) の元データ
+// 描画結果を1ページのPDFとして受け取り、Form XObject に変換して使う
+pub struct AppearanceStream {
+    pub x: f64,
+    pub y: f64, // 描画範囲の左上 (UI座標)。/Rect の位置になる
+    pub width: f64,
+    pub height: f64,
+    pub pdf: Vec<u8>,
 }
 
 // ヘルパー関数: Objectからf64を取り出す
@@ -25,6 +467,180 @@ fn get_f64(obj: &Object) -> f64 {
     }
 }
 
+// --- PDFテキスト文字列 (Contents, NM など) のエンコード/デコード ---
+// PDFDocEncoding で表せる文字だけならそのまま、それ以外は UTF-16BE (BOM付き) で書く
+
+// PDFDocEncoding のうち Latin-1 と異なる部分 (0x18-0x1F, 0x80-0xA0)
+const PDF_DOC_SPECIAL: [(u8, char); 40] = [
+    (0x18, '\u{02D8}'), (0x19, '\u{02C7}'), (0x1A, '\u{02C6}'), (0x1B, '\u{02D9}'),
+    (0x1C, '\u{02DD}'), (0x1D, '\u{02DB}'), (0x1E, '\u{02DA}'), (0x1F, '\u{02DC}'),
+    (0x80, '\u{2022}'), (0x81, '\u{2020}'), (0x82, '\u{2021}'), (0x83, '\u{2026}'),
+    (0x84, '\u{2014}'), (0x85, '\u{2013}'), (0x86, '\u{0192}'), (0x87, '\u{2044}'),
+    (0x88, '\u{2039}'), (0x89, '\u{203A}'), (0x8A, '\u{2212}'), (0x8B, '\u{2030}'),
+    (0x8C, '\u{201E}'), (0x8D, '\u{201C}'), (0x8E, '\u{201D}'), (0x8F, '\u{2018}'),
+    (0x90, '\u{2019}'), (0x91, '\u{201A}'), (0x92, '\u{2122}'), (0x93, '\u{FB01}'),
+    (0x94, '\u{FB02}'), (0x95, '\u{0141}'), (0x96, '\u{0152}'), (0x97, '\u{0160}'),
+    (0x98, '\u{0178}'), (0x99, '\u{017D}'), (0x9A, '\u{0131}'), (0x9B, '\u{0142}'),
+    (0x9C, '\u{0153}'), (0x9D, '\u{0161}'), (0x9E, '\u{017E}'), (0xA0, '\u{20AC}'),
+];
+
+fn pdf_doc_decode_byte(b: u8) -> Option<char> {
+    if let Some((_, c)) = PDF_DOC_SPECIAL.iter().find(|(code, _)| *code == b) {
+        return Some(*c);
+    }
+    match b {
+        0x7F | 0x9F | 0xAD => None, // 未定義
+        _ => Some(b as char),       // それ以外は Latin-1 と同じ
+    }
+}
+
+fn pdf_doc_encode_char(c: char) -> Option<u8> {
+    if let Some((code, _)) = PDF_DOC_SPECIAL.iter().find(|(_, ch)| *ch == c) {
+        return Some(*code);
+    }
+    match c as u32 {
+        0x09 | 0x0A | 0x0D => Some(c as u8),
+        0x20..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF => Some(c as u8),
+        _ => None,
+    }
+}
+
+pub fn encode_text_string(text: &str) -> Object {
+    let pdf_doc: Option<Vec<u8>> = text.chars().map(pdf_doc_encode_char).collect();
+    match pdf_doc {
+        Some(bytes) => Object::String(bytes, StringFormat::Literal),
+        None => {
+            let mut bytes = vec![0xFE, 0xFF];
+            bytes.extend(text.encode_utf16().flat_map(|u| u.to_be_bytes()));
+            Object::String(bytes, StringFormat::Hexadecimal)
+        }
+    }
+}
+
+pub fn decode_text_string(bytes: &[u8]) -> String {
+    if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        // UTF-16BE
+        let units: Vec<u16> = rest.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&units)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        // UTF-16LE (規格外だが、これで書き出すソフトもある)
+        let units: Vec<u16> = rest.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&units)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        // UTF-8 (PDF 2.0)
+        String::from_utf8_lossy(rest).to_string()
+    } else {
+        bytes.iter().filter_map(|b| pdf_doc_decode_byte(*b)).collect()
+    }
+}
+
+// 注釈に記録する現在時刻 (PDF の日付は秒まで)
+pub fn now() -> DateTime<FixedOffset> {
+    Local::now().trunc_subsecs(0).fixed_offset()
+}
+
+// PDF の日付文字列 "D:YYYYMMDDHHmmSS+HH'mm'" を読む
+// 年より後ろは省略でき、時差が無ければ UTC とみなす
+pub fn parse_pdf_date(text: &str) -> Option<DateTime<FixedOffset>> {
+    let text = text.trim();
+    let text = text.strip_prefix("D:").unwrap_or(text);
+    let digits_len = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (digits, zone) = text.split_at(digits_len);
+    let field = |from: usize, len: usize, default: u32| match digits.get(from..from + len) {
+        Some(d) => d.parse::<u32>().ok(),
+        None => Some(default),
+    };
+    let year = digits.get(0..4)?.parse::<i32>().ok()?;
+    let date = NaiveDate::from_ymd_opt(year, field(4, 2, 1)?, field(6, 2, 1)?)?;
+    let time = date.and_hms_opt(field(8, 2, 0)?, field(10, 2, 0)?, field(12, 2, 0)?)?;
+
+    // "+09'00'" / "-0500" / "Z"
+    let offset_digits: String = zone.chars().skip(1).filter(|c| c.is_ascii_digit()).collect();
+    let hours = offset_digits.get(0..2).and_then(|h| h.parse::<i32>().ok()).unwrap_or(0);
+    let minutes = offset_digits.get(2..4).and_then(|m| m.parse::<i32>().ok()).unwrap_or(0);
+    let seconds = hours * 3600 + minutes * 60;
+    let offset = match zone.chars().next() {
+        Some('-') => FixedOffset::west_opt(seconds)?,
+        Some('+') => FixedOffset::east_opt(seconds)?,
+        _ => FixedOffset::east_opt(0)?,
+    };
+    time.and_local_timezone(offset).single()
+}
+
+pub fn format_pdf_date(date: &DateTime<FixedOffset>) -> String {
+    let offset = date.offset().local_minus_utc();
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{}{}{:02}'{:02}'", date.format("D:%Y%m%d%H%M%S"), sign, offset / 3600, offset % 3600 / 60)
+}
+
+fn get_pdf_date(dict: &Dictionary, key: &[u8]) -> Option<DateTime<FixedOffset>> {
+    dict.get(key).and_then(|o| o.as_str()).ok().and_then(|b| parse_pdf_date(&decode_text_string(b)))
+}
+
+// 日付を書き込む。同じ日時が別の書き方で入っていれば、元の文字列を残す
+fn set_pdf_date(dict: &mut Dictionary, key: &[u8], date: Option<DateTime<FixedOffset>>) {
+    let Some(date) = date else { return };
+    if get_pdf_date(dict, key) != Some(date) {
+        dict.set(key, Object::String(format_pdf_date(&date).into_bytes(), StringFormat::Literal));
+    }
+}
+
+// /DA の "r g b rg" (または "g g" のグレー) から文字色を取り出す
+fn parse_text_color_from_da(da: &str) -> Option<(f64, f64, f64)> {
+    let parts: Vec<&str> = da.split_whitespace().collect();
+    let number = |s: &str| s.parse::<f64>().ok();
+    for (i, part) in parts.iter().enumerate() {
+        match *part {
+            "rg" if i >= 3 => return Some((number(parts[i - 3])?, number(parts[i - 2])?, number(parts[i - 1])?)),
+            "g" if i >= 1 => return number(parts[i - 1]).map(|g| (g, g, g)),
+            _ => {}
+        }
+    }
+    None
+}
+
+// /DS (CSS 風の既定スタイル文字列) の font 指定からフォントファミリーを取り出す
+// 例: "font: bold 12pt Helvetica,sans-serif; color:#FF0000"
+fn parse_font_family_from_ds(ds: &str) -> Option<FontFamily> {
+    let font = ds.split(';')
+        .filter_map(|decl| decl.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("font"))?
+        .1;
+    // サイズ (pt/px の付いた語) より後ろがファミリー
+    let family = font.split_whitespace()
+        .skip_while(|word| !word.ends_with("pt") && !word.ends_with("px"))
+        .skip(1)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let first = family.split(',').next()?.trim().trim_matches(|c| c == '\'' || c == '"');
+    (!first.is_empty()).then(|| FontFamily::from_css_family(first))
+}
+
+fn parse_text_style(dict: &Dictionary) -> TextStyle {
+    let string_of = |key: &[u8]| dict.get(key).and_then(|o| o.as_str()).map(|b| String::from_utf8_lossy(b).to_string()).ok();
+    let da = string_of(b"DA").unwrap_or_default();
+    let ds = string_of(b"DS").unwrap_or_default();
+    TextStyle {
+        // 以前の Margium は /DS を書かず、MathJax の既定 (Serif) で描いていた
+        font_family: parse_font_family_from_ds(&ds).unwrap_or(FontFamily::Serif),
+        text_color: parse_text_color_from_da(&da).unwrap_or((0.0, 0.0, 0.0)),
+        // /C が無い (以前の Margium) なら既定の背景、空の配列なら透明
+        background: match dict.get(b"C").and_then(|o| o.as_array()) {
+            Ok(c) if c.is_empty() => None,
+            Ok(_) => parse_color(dict, b"C"),
+            Err(_) => Some(DEFAULT_BACKGROUND),
+        },
+        border_width: dict.get(b"BS").and_then(|o| o.as_dict()).and_then(|bs| bs.get(b"W")).map(get_f64).unwrap_or(0.0),
+        opacity: dict.get(b"CA").map(get_f64).ok(),
+    }
+}
+
+fn color_hex((r, g, b): (f64, f64, f64)) -> String {
+    let byte = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    format!("#{:02X}{:02X}{:02X}", byte(r), byte(g), byte(b))
+}
+
 fn parse_font_size_from_da(da: &str) -> Option<f32> {
     let parts: Vec<&str> = da.split_whitespace().collect();
     for (i, part) in parts.iter().enumerate() {
@@ -37,6 +653,371 @@ fn parse_font_size_from_da(da: &str) -> Option<f32> {
     None
 }
 
+// 返信 (/IRT を持つ Text 注釈) なら、返信先のオブジェクトIDを返す
+// /RT が /Group のものは返信ではなく、注釈をまとめるためのものなので除く
+fn reply_target(dict: &Dictionary) -> Option<ObjectId> {
+    let subtype = dict.get(b"Subtype").and_then(|o| o.as_name()).unwrap_or(&[]);
+    let is_reply = dict.get(b"RT").and_then(|o| o.as_name()).map_or(true, |rt| rt == b"R");
+    if subtype != b"Text" || !is_reply {
+        return None;
+    }
+    dict.get(b"IRT").and_then(|o| o.as_reference()).ok()
+}
+
+// 保存時に Margium が書き直すアノテーションかどうか
+// 返信は、返信先をたどった先の注釈が Margium のものなら一緒に扱う
+// 付箋のポップアップも、付箋と一緒に書き直す
+fn is_managed_in(doc: &Document, dict: &Dictionary) -> bool {
+    if dict.get(b"Subtype").and_then(|o| o.as_name()).is_ok_and(|s| s == b"Popup") {
+        return dict.get(b"Parent")
+            .and_then(|o| o.as_reference())
+            .and_then(|id| doc.get_dictionary(id))
+            .is_ok_and(is_note);
+    }
+    let mut dict = dict;
+    for _ in 0..MAX_REPLY_DEPTH {
+        let Some(parent) = reply_target(dict) else { return is_managed_annotation(dict) };
+        let Ok(parent_dict) = doc.get_dictionary(parent) else { return false };
+        dict = parent_dict;
+    }
+    false
+}
+
+// 返信をたどる深さの上限 (/IRT が循環しているファイル対策)
+const MAX_REPLY_DEPTH: usize = 32;
+
+fn parse_reply(dict: &Dictionary, object_id: Option<ObjectId>, used_ids: &mut HashSet<String>) -> Reply {
+    let text_of = |key: &[u8]| dict.get(key).and_then(|o| o.as_str()).ok().map(decode_text_string);
+    let id = text_of(b"NM")
+        .map(|nm| nm.trim().to_string())
+        .filter(|nm| !nm.is_empty() && !used_ids.contains(nm))
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    used_ids.insert(id.clone());
+    // /State は本来テキスト文字列だが、名前で書くソフトもある
+    let state = dict.get(b"State").ok().and_then(|o| match o {
+        Object::Name(name) => Some(String::from_utf8_lossy(name).to_string()),
+        other => other.as_str().ok().map(decode_text_string),
+    });
+    Reply {
+        id,
+        object_id,
+        author: text_of(b"T").filter(|t| !t.is_empty()),
+        content: text_of(b"Contents").unwrap_or_default(),
+        created: get_pdf_date(dict, b"CreationDate"),
+        modified: get_pdf_date(dict, b"M"),
+        state: state.and_then(|s| ReviewState::from_name(&s)),
+        parent: None,
+    }
+}
+
+// 返信を、返信先 (parent。注釈か、返信への返信なら元の返信) を /IRT で指す Text 注釈として書き込み、そのオブジェクトIDを返す
+// 返信はサイドバーのスレッドに出すもので、ページ上には描かない
+fn write_reply(doc: &Document, update: &mut Document, reply: &Reply, parent: ObjectId, rect: &Object) -> ObjectId {
+    let old = reply.object_id.and_then(|id| doc.get_dictionary(id).ok());
+    let mut dict = old.cloned().unwrap_or_default();
+    dict.set("Type", Object::Name(b"Annot".to_vec()));
+    dict.set("Subtype", Object::Name(b"Text".to_vec()));
+    dict.set("IRT", Object::Reference(parent));
+    dict.set("RT", Object::Name(b"R".to_vec()));
+    dict.set("Rect", rect.clone());
+    // /F 26 = Hidden | NoZoom | NoRotate
+    dict.set("F", Object::Integer(26));
+    dict.set("Contents", encode_text_string(&reply.content));
+    dict.set("NM", encode_text_string(&reply.id));
+    if let Some(author) = &reply.author {
+        dict.set("T", encode_text_string(author));
+    }
+    set_pdf_date(&mut dict, b"CreationDate", reply.created);
+    set_pdf_date(&mut dict, b"M", reply.modified);
+    match reply.state {
+        Some(state) => {
+            dict.set("State", encode_text_string(state.label()));
+            dict.set("StateModel", encode_text_string(state.model()));
+        }
+        None => {
+            dict.remove(b"State");
+            dict.remove(b"StateModel");
+        }
+    }
+
+    match reply.object_id {
+        Some(id) if old.is_some_and(|old| is_same_dictionary(old, &dict)) => id,
+        Some(id) => {
+            update.objects.insert(id, Object::Dictionary(dict));
+            id
+        }
+        None => update.add_object(dict),
+    }
+}
+
+// 付箋 (返信ではない /Text 注釈) かどうか
+fn is_note(dict: &Dictionary) -> bool {
+    let subtype = dict.get(b"Subtype").and_then(|o| o.as_name()).unwrap_or(&[]);
+    subtype == b"Text"
+        && reply_target(dict).is_none()
+        && dict.get(b"Rect").and_then(|o| o.as_array()).is_ok_and(|r| r.len() >= 4)
+}
+
+// 付箋のポップアップの位置と大きさ (UI座標) と、開いているかどうか
+fn parse_note_popup(doc: &Document, dict: &Dictionary, page_height: f64) -> Option<((f64, f64, f64, f64), bool)> {
+    let popup = dict.get(b"Popup").and_then(|o| o.as_reference()).and_then(|id| doc.get_dictionary(id)).ok()?;
+    let rect: Vec<f64> = popup.get(b"Rect").and_then(|o| o.as_array()).ok()?.iter().map(get_f64).collect();
+    if rect.len() < 4 {
+        return None;
+    }
+    let (x1, x2) = (rect[0].min(rect[2]), rect[0].max(rect[2]));
+    let (y1, y2) = (rect[1].min(rect[3]), rect[1].max(rect[3]));
+    let open = popup.get(b"Open").and_then(|o| o.as_bool()).unwrap_or(false);
+    Some(((x1, page_height - y2, x2 - x1, y2 - y1), open))
+}
+
+// 付箋のポップアップを、付箋 (parent) の子の /Popup 注釈として popup_id に書き込む
+fn write_note_popup(
+    doc: &Document,
+    update: &mut Document,
+    popup_id: ObjectId,
+    parent: ObjectId,
+    (x, y, width, height): (f64, f64, f64, f64),
+    open: bool,
+    page_height: f64,
+) {
+    let old = doc.get_dictionary(popup_id).ok();
+    let mut dict = old.cloned().unwrap_or_default();
+    dict.set("Type", Object::Name(b"Annot".to_vec()));
+    dict.set("Subtype", Object::Name(b"Popup".to_vec()));
+    dict.set("Parent", Object::Reference(parent));
+    dict.set("Rect", Object::Array(vec![
+        Object::Real(x as f32),
+        Object::Real((page_height - y - height) as f32),
+        Object::Real((x + width) as f32),
+        Object::Real((page_height - y) as f32),
+    ]));
+    dict.set("Open", Object::Boolean(open));
+    // /F 28 = Print | NoZoom | NoRotate
+    dict.set("F", Object::Integer(28));
+    if !old.is_some_and(|old| is_same_dictionary(old, &dict)) {
+        update.objects.insert(popup_id, Object::Dictionary(dict));
+    }
+}
+
+// Margium が読み込んで編集対象にするアノテーションかどうか
+// read_annotations で拾う条件と一致させること (それ以外は保存時にそのまま残す)
+fn is_managed_annotation(dict: &Dictionary) -> bool {
+    let subtype = dict.get(b"Subtype").and_then(|o| o.as_name()).unwrap_or(&[]);
+    if is_note(dict) {
+        return true;
+    }
+    if MarkupStyle::from_subtype(subtype).is_some() {
+        return dict.get(b"QuadPoints").and_then(|o| o.as_array()).is_ok_and(|q| q.len() >= 8);
+    }
+    if subtype == b"Ink" {
+        return dict.get(b"InkList").and_then(|o| o.as_array()).is_ok();
+    }
+    match ShapeKind::from_subtype(subtype) {
+        Some(ShapeKind::Square | ShapeKind::Circle) => {
+            return dict.get(b"Rect").and_then(|o| o.as_array()).is_ok_and(|r| r.len() >= 4);
+        }
+        Some(ShapeKind::Line) => {
+            return dict.get(b"L").and_then(|o| o.as_array()).is_ok_and(|l| l.len() >= 4);
+        }
+        Some(ShapeKind::Polygon | ShapeKind::PolyLine) => {
+            return dict.get(b"Vertices").and_then(|o| o.as_array()).is_ok_and(|v| v.len() >= 4);
+        }
+        None => {}
+    }
+    subtype == b"FreeText"
+        && dict.get(b"Contents").is_ok()
+        && dict.get(b"Rect").and_then(|o| o.as_array()).is_ok()
+}
+
+// /QuadPoints (4点 x 2座標 を1組とする) を、組ごとの外接矩形 (UI座標) に変換する
+fn quad_points_to_rects(quads: &[Object], page_height: f64) -> Vec<(f64, f64, f64, f64)> {
+    quads
+        .chunks_exact(8)
+        .map(|q| {
+            let xs = [0, 2, 4, 6].map(|i| get_f64(&q[i]));
+            let ys = [1, 3, 5, 7].map(|i| get_f64(&q[i]));
+            let x_min = xs.iter().cloned().fold(f64::INFINITY, f64::min);
+            let x_max = xs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let y_min = ys.iter().cloned().fold(f64::INFINITY, f64::min);
+            let y_max = ys.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            (x_min, page_height - y_max, x_max - x_min, y_max - y_min)
+        })
+        .collect()
+}
+
+// 矩形 (UI座標) を /QuadPoints の並び (左上, 右上, 左下, 右下) に変換する
+fn rects_to_quad_points(rects: &[(f64, f64, f64, f64)], page_height: f64) -> Vec<Object> {
+    rects
+        .iter()
+        .flat_map(|&(x, y, w, h)| {
+            let top = page_height - y;
+            let bottom = top - h;
+            [x, top, x + w, top, x, bottom, x + w, bottom]
+        })
+        .map(|v| Object::Real(v as f32))
+        .collect()
+}
+
+// 矩形群の外接矩形 (UI座標)
+pub fn bounding_rect(rects: &[(f64, f64, f64, f64)]) -> Option<(f64, f64, f64, f64)> {
+    let first = rects.first()?;
+    let (mut x0, mut y0, mut x1, mut y1) = (first.0, first.1, first.0 + first.2, first.1 + first.3);
+    for &(x, y, w, h) in &rects[1..] {
+        x0 = x0.min(x);
+        y0 = y0.min(y);
+        x1 = x1.max(x + w);
+        y1 = y1.max(y + h);
+    }
+    Some((x0, y0, x1 - x0, y1 - y0))
+}
+
+// 線幅 (/BS /W、古い形式の /Border [h v w])。指定が無ければ 1
+fn parse_border_width(dict: &Dictionary) -> f64 {
+    if let Ok(w) = dict.get(b"BS").and_then(|o| o.as_dict()).and_then(|bs| bs.get(b"W")) {
+        return get_f64(w);
+    }
+    match dict.get(b"Border").and_then(|o| o.as_array()) {
+        Ok(border) if border.len() >= 3 => get_f64(&border[2]),
+        _ => 1.0,
+    }
+}
+
+// 手書きの線の筆圧を保存する独自キー (PDF には筆圧の標準の置き場所が無い)
+// /InkList と同じ形の配列で、線ごとに各点の筆圧 (0.0〜1.0) を並べる。例: /MargiumPressure [[0.4 0.55 0.6] [0.5 0.5]]
+// 他のビューアはこのキーを無視し、/AP に描かれた太さの変わる線をそのまま表示する
+// 無いとき (他のソフトで書いた線) や点の数が足りないときは、DEFAULT_PRESSURE とみなす
+const INK_PRESSURE_KEY: &[u8] = b"MargiumPressure";
+
+// /InkList を点列 (UI座標) に変換する (筆圧は INK_PRESSURE_KEY から読む)
+fn parse_ink_strokes(doc: &Document, dict: &Dictionary, page_height: f64) -> Vec<Vec<(f64, f64, f64)>> {
+    let resolve = |obj: &Object| -> Vec<Object> {
+        doc.dereference(obj).ok()
+            .and_then(|(_, o)| o.as_array().ok())
+            .cloned()
+            .unwrap_or_default()
+    };
+    let ink_list = dict.get(b"InkList").map(resolve).unwrap_or_default();
+    let pressures = dict.get(INK_PRESSURE_KEY).map(resolve).unwrap_or_default();
+
+    ink_list
+        .iter()
+        .enumerate()
+        .map(|(i, stroke)| {
+            let coords = resolve(stroke);
+            let stroke_pressures = pressures.get(i).map(resolve).unwrap_or_default();
+            coords
+                .chunks_exact(2)
+                .enumerate()
+                .map(|(j, c)| {
+                    let pressure = stroke_pressures.get(j).map(get_f64).unwrap_or(DEFAULT_PRESSURE);
+                    (get_f64(&c[0]), page_height - get_f64(&c[1]), pressure)
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|stroke| !stroke.is_empty())
+        .collect()
+}
+
+// 図形の座標を読む (points の意味は AnnotationKind::Shape を参照)
+fn parse_shape_points(dict: &Dictionary, shape: ShapeKind, width: f64, page_height: f64) -> Vec<(f64, f64)> {
+    let numbers = |key: &[u8]| -> Vec<f64> {
+        dict.get(key).and_then(|o| o.as_array())
+            .map(|a| a.iter().map(get_f64).collect())
+            .unwrap_or_default()
+    };
+    let to_points = |coords: Vec<f64>| -> Vec<(f64, f64)> {
+        coords.chunks_exact(2).map(|c| (c[0], page_height - c[1])).collect()
+    };
+
+    match shape {
+        ShapeKind::Square | ShapeKind::Circle => {
+            // 線は /Rect の内側に描かれる。/RD があればさらにその分だけ内側
+            let rect = numbers(b"Rect");
+            let rd = numbers(b"RD");
+            let (l, t, r, b) = if rd.len() >= 4 { (rd[0], rd[1], rd[2], rd[3]) } else { (0.0, 0.0, 0.0, 0.0) };
+            let inset = width / 2.0;
+            let (x0, x1) = (rect[0].min(rect[2]), rect[0].max(rect[2]));
+            let (y0, y1) = (rect[1].min(rect[3]), rect[1].max(rect[3]));
+            vec![
+                (x0 + l + inset, page_height - (y1 - t - inset)),
+                (x1 - r - inset, page_height - (y0 + b + inset)),
+            ]
+        }
+        ShapeKind::Line => to_points(numbers(b"L").into_iter().take(4).collect()),
+        ShapeKind::Polygon | ShapeKind::PolyLine => to_points(numbers(b"Vertices")),
+    }
+}
+
+// 枠の大きさは /Rect に保存するが、内容に合わせて伸び縮みする辺は
+// 独自キー /MargiumAutoSize に [/Width /Height] のように記録しておく (他のビューアは /Rect の枠で表示する)
+fn parse_auto_size(dict: &Dictionary) -> (bool, bool) {
+    let names: Vec<&[u8]> = dict.get(b"MargiumAutoSize").and_then(|o| o.as_array())
+        .map(|a| a.iter().filter_map(|n| n.as_name().ok()).collect())
+        .unwrap_or_default();
+    (names.contains(&b"Width".as_slice()), names.contains(&b"Height".as_slice()))
+}
+
+fn parse_line_endings(dict: &Dictionary) -> (LineEnding, LineEnding) {
+    let names: Vec<LineEnding> = dict.get(b"LE").and_then(|o| o.as_array())
+        .map(|a| a.iter().map(|n| LineEnding::from_name(n.as_name().unwrap_or(b"None"))).collect())
+        .unwrap_or_default();
+    match names.as_slice() {
+        [start, end, ..] => (*start, *end),
+        _ => (LineEnding::None, LineEnding::None),
+    }
+}
+
+fn color_array((r, g, b): (f64, f64, f64)) -> Object {
+    Object::Array(vec![
+        Object::Real(r as f32),
+        Object::Real(g as f32),
+        Object::Real(b as f32),
+    ])
+}
+
+// /C (色) を RGB として読む。グレー・CMYK も RGB に直す
+fn parse_color(dict: &Dictionary, key: &[u8]) -> Option<(f64, f64, f64)> {
+    let c: Vec<f64> = dict.get(key).and_then(|o| o.as_array()).ok()?.iter().map(get_f64).collect();
+    match c.len() {
+        1 => Some((c[0], c[0], c[0])),
+        3 => Some((c[0], c[1], c[2])),
+        4 => {
+            let k = 1.0 - c[3];
+            Some(((1.0 - c[0]) * k, (1.0 - c[1]) * k, (1.0 - c[2]) * k))
+        }
+        _ => None,
+    }
+}
+
+// ページの /Annots 配列の中身 (間接参照の配列も解決する)
+fn page_annotations(doc: &Document, page_id: ObjectId) -> Vec<Object> {
+    let Ok(page_dict) = doc.get_dictionary(page_id) else { return Vec::new() };
+
+    let annots_list = match page_dict.get(b"Annots") {
+        Ok(Object::Reference(id)) => doc.get_object(*id).and_then(|o| o.as_array()).ok(),
+        Ok(Object::Array(arr)) => Some(arr),
+        _ => None,
+    };
+    annots_list.cloned().unwrap_or_default()
+}
+
+// /Annots のうち、Margium が扱わないもの (リンク、ハイライト、フォーム等) を返す
+fn foreign_annotations(doc: &Document, annots: &[Object]) -> Vec<Object> {
+    annots
+        .iter()
+        .filter(|entry| {
+            let dict = match entry {
+                Object::Reference(id) => doc.get_dictionary(*id).ok(),
+                other => other.as_dict().ok(),
+            };
+            // 中身を確認できないもの (壊れた参照など) も消さずに残す
+            dict.is_none_or(|d| !is_managed_in(doc, d))
+        })
+        .cloned()
+        .collect()
+}
+
 fn get_page_height(doc: &Document, page_id: lopdf::ObjectId) -> Option<f32> {
     let page_obj = doc.get_object(page_id).ok()?;
     let page_dict = page_obj.as_dict().ok()?;
@@ -52,15 +1033,45 @@ fn get_page_height(doc: &Document, page_id: lopdf::ObjectId) -> Option<f32> {
     }
 }
 
-pub fn load_annotations(path: String) -> Result<Vec<AnnotationData>, String> {
+// 読み込んだ注釈が PDF 内のどれだったか。Poppler の描画から外す注釈を選ぶのに使う
+// Poppler からはオブジェクトIDが読めないので、/NM があれば名前で、無ければ /Rect で突き合わせる
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceAnnot {
+    pub page: u32, // 1-based index
+    pub name: Option<String>,
+    // PDF座標の /Rect (左下 x, y, 右上 x, y)。Poppler に合わせて CropBox の原点からの位置にしてある
+    pub rect: Option<(f64, f64, f64, f64)>,
+}
+
+// 読み込んだアノテーションと、その元になった PDF 内の注釈 (返信も含む)
+#[derive(Debug, Default)]
+pub struct LoadedAnnotations {
+    pub annotations: Vec<AnnotationData>,
+    pub sources: Vec<SourceAnnot>,
+}
+
+// アノテーションと目次は、ここで読み込んだ1つの Document から読む (大きなファイルを何度も解析しない)
+pub fn load_document(path: &str) -> Result<Document, String> {
     // ignore_xref_streams=true にすると、一部の不正なPDFで高速になる場合がありますが、
     // 基本は load() でOKです。lopdfはデフォルトで遅延ロードを行います。
     let now = time::Instant::now();
 
-    let doc = Document::load(&path).map_err(|e| e.to_string())?;
-    let mut annotations = Vec::new();
-
+    let doc = Document::load(path).map_err(|e| e.to_string())?;
     println!("Loaded document in {:?}", now.elapsed());
+    Ok(doc)
+}
+
+// 結び付ける前の返信 (自身のオブジェクトID, 返信先のオブジェクトID, 辞書, 元の注釈としての目印)
+type PendingReply<'a> = (Option<ObjectId>, ObjectId, &'a Dictionary, SourceAnnot);
+
+// 文書内の Margium が扱うアノテーションをすべて読み込む
+pub fn read_annotations(doc: &Document) -> Result<LoadedAnnotations, String> {
+    let mut annotations = Vec::new();
+    let mut sources = Vec::new();
+    // 文書内で一意なIDを保証するため、使用済みのIDを記録
+    let mut used_ids: HashSet<String> = HashSet::new();
+    // 返信 (返信先と結び付ける前のもの)
+    let mut replies: Vec<PendingReply> = Vec::new();
 
     for (page_num, page_id) in doc.get_pages() {
         let page_dict = doc.get_object(page_id).and_then(|o| o.as_dict()).map_err(|e| e.to_string())?;
@@ -70,6 +1081,12 @@ pub fn load_annotations(path: String) -> Result<Vec<AnnotationData>, String> {
             .map(|a| a.iter().map(|f| get_f64(f)).collect::<Vec<f64>>())
             .unwrap_or(vec![0.0, 0.0, 595.0, 842.0]);
         let page_height = media_box[3];
+        // Poppler は注釈の位置を CropBox の原点からの位置で返す
+        let crop_origin = page_dict.get(b"CropBox")
+            .and_then(|o| o.as_array())
+            .ok()
+            .filter(|a| a.len() >= 4)
+            .map_or((media_box[0], media_box[1]), |a| (get_f64(&a[0]), get_f64(&a[1])));
 
         if let Ok(annots_obj) = page_dict.get(b"Annots") {
             // Annotsが配列か参照かを解決
@@ -91,121 +1108,1053 @@ pub fn load_annotations(path: String) -> Result<Vec<AnnotationData>, String> {
                         _ => (Ok(annot_ref), None)
                     };
 
-                    if let Ok(annot_obj) = annot_obj_result {
-                        if let Ok(annot_dict) = annot_obj.as_dict() {
-                            if let (Ok(subtype), Ok(contents), Ok(rect)) = (
-                                annot_dict.get(b"Subtype"),
-                                annot_dict.get(b"Contents"),
-                                annot_dict.get(b"Rect")
-                            ) {
-                                if subtype.as_name().unwrap_or(&[]) == b"FreeText" {
-                                    let content_bytes = contents.as_str().unwrap_or(b"");
-                                    let text = String::from_utf8_lossy(content_bytes).to_string();
-
-                                    let mut font_size = None;
-                                    if let Ok(da_obj) = annot_dict.get(b"DA") {
-                                        let da_str = String::from_utf8_lossy(da_obj.as_str().unwrap_or(b""));
-                                        font_size = parse_font_size_from_da(&da_str);
-                                    }
-
-                                    if let Ok(rect_arr) = rect.as_array() {
-                                        let x_pdf = get_f64(&rect_arr[0]);
-                                        let y_pdf_top = get_f64(&rect_arr[3]);
-                                        let y_web = page_height - y_pdf_top;
-
-                                        annotations.push(AnnotationData {
-                                            page: page_num,
-                                            x: x_pdf,      
-                                            y: y_web,      
-                                            content: text,
-                                            font_size: font_size,
-                                            id: Uuid::nil().to_string(), // UI用ID（既存のものがあればそれを使う）
-                                            object_id: obj_id, // 【重要】PDF内部IDを保存
-                                        });
-                                    }
-                                }
-                            }
+                    let Ok(annot_dict) = annot_obj_result.and_then(|o| o.as_dict()) else { continue };
+                    // 返信は、返信先の注釈をすべて読み込んでから結び付ける
+                    if let Some(parent) = reply_target(annot_dict) {
+                        replies.push((obj_id, parent, annot_dict, source_annot(annot_dict, page_num, crop_origin)));
+                        continue;
+                    }
+                    if !is_managed_annotation(annot_dict) {
+                        continue;
+                    }
+
+                    let subtype = annot_dict.get(b"Subtype").and_then(|o| o.as_name()).unwrap_or(&[]);
+                    let mut box_size = (None, None);
+                    let mut text_style = TextStyle::default();
+                    let (kind, x, y) = if let Some(style) = MarkupStyle::from_subtype(subtype) {
+                        let Ok(quads) = annot_dict.get(b"QuadPoints").and_then(|o| o.as_array()) else { continue };
+                        let rects = quad_points_to_rects(quads, page_height);
+                        let Some((x, y, _, _)) = bounding_rect(&rects) else { continue };
+                        (AnnotationKind::Markup { style, rects }, x, y)
+                    } else if let Some(shape) = ShapeKind::from_subtype(subtype) {
+                        let width = parse_border_width(annot_dict);
+                        let points = parse_shape_points(annot_dict, shape, width, page_height);
+                        let line_endings = parse_line_endings(annot_dict);
+                        let Some((x, y, _, _)) = shape_bounds(&points, width, line_endings) else { continue };
+                        let kind = AnnotationKind::Shape {
+                            shape,
+                            points,
+                            width,
+                            fill: parse_color(annot_dict, b"IC"),
+                            opacity: annot_dict.get(b"CA").map(get_f64).unwrap_or(1.0),
+                            line_endings,
+                        };
+                        (kind, x, y)
+                    } else if subtype == b"Text" {
+                        let Ok(rect_arr) = annot_dict.get(b"Rect").and_then(|o| o.as_array()) else { continue };
+                        let x = get_f64(&rect_arr[0]).min(get_f64(&rect_arr[2]));
+                        let y = page_height - get_f64(&rect_arr[1]).max(get_f64(&rect_arr[3]));
+                        let (popup, popup_open) = parse_note_popup(doc, annot_dict, page_height)
+                            .unwrap_or((default_note_popup(x, y), false));
+                        let kind = AnnotationKind::Note {
+                            icon: NoteIcon::from_name(annot_dict.get(b"Name").and_then(|o| o.as_name()).unwrap_or(&[])),
+                            open: popup_open || annot_dict.get(b"Open").and_then(|o| o.as_bool()).unwrap_or(false),
+                            popup,
+                        };
+                        (kind, x, y)
+                    } else if subtype == b"Ink" {
+                        let strokes = parse_ink_strokes(doc, annot_dict, page_height);
+                        let width = parse_border_width(annot_dict);
+                        let Some((x, y, _, _)) = ink_bounds(&strokes, width) else { continue };
+                        (AnnotationKind::Ink { strokes, width }, x, y)
+                    } else {
+                        let Ok(rect_arr) = annot_dict.get(b"Rect").and_then(|o| o.as_array()) else { continue };
+                        if rect_arr.len() < 4 {
+                            continue;
                         }
+                        let x_pdf = get_f64(&rect_arr[0]);
+                        let y_pdf_top = get_f64(&rect_arr[3]);
+                        let (auto_width, auto_height) = parse_auto_size(annot_dict);
+                        box_size = (
+                            (!auto_width).then(|| (get_f64(&rect_arr[2]) - x_pdf).abs()),
+                            (!auto_height).then(|| (y_pdf_top - get_f64(&rect_arr[1])).abs()),
+                        );
+                        text_style = parse_text_style(annot_dict);
+                        (AnnotationKind::FreeText, x_pdf, page_height - y_pdf_top)
+                    };
+
+                    // マークアップは /Contents (コメント) が無いこともある
+                    let content_bytes = annot_dict.get(b"Contents").and_then(|o| o.as_str()).unwrap_or(b"");
+                    let text = decode_text_string(content_bytes);
+
+                    let mut font_size = None;
+                    if let Ok(da_obj) = annot_dict.get(b"DA") {
+                        let da_str = String::from_utf8_lossy(da_obj.as_str().unwrap_or(b""));
+                        font_size = parse_font_size_from_da(&da_str);
                     }
+
+                    // /NM (注釈名) をIDとして使う。無い・重複している場合は新規発行
+                    let id = annot_dict.get(b"NM").ok()
+                        .and_then(|o| o.as_str().ok())
+                        .map(|b| decode_text_string(b).trim().to_string())
+                        .filter(|nm| !nm.is_empty() && !used_ids.contains(nm))
+                        .unwrap_or_else(|| Uuid::new_v4().to_string());
+                    used_ids.insert(id.clone());
+
+                    sources.push(source_annot(annot_dict, page_num, crop_origin));
+                    annotations.push(AnnotationData {
+                        page: page_num,
+                        x,
+                        y,
+                        content: text,
+                        font_size: font_size,
+                        id, // UI用ID (/NM があればそれを使う)
+                        object_id: obj_id, // 【重要】PDF内部IDを保存
+                        kind,
+                        color: parse_color(annot_dict, b"C"),
+                        width: box_size.0,
+                        height: box_size.1,
+                        text_style,
+                        author: annot_dict.get(b"T").and_then(|o| o.as_str()).ok().map(decode_text_string).filter(|t| !t.is_empty()),
+                        created: get_pdf_date(annot_dict, b"CreationDate"),
+                        modified: get_pdf_date(annot_dict, b"M"),
+                        replies: Vec::new(),
+                        bounds: Cell::new(None),
+                    });
                 }
             }
         }
     }
 
-    println!("Loaded annotations in {:?}", now.elapsed());
-    Ok(annotations)
+    attach_replies(&mut annotations, replies, &mut used_ids, &mut sources);
+    Ok(LoadedAnnotations { annotations, sources })
 }
 
-pub fn save_pdf_with_annotations(path: String, annotations: Vec<AnnotationData>) -> Result<(), String> {
-    // 1. PDFを読み込む
-    let mut doc = Document::load(&path).map_err(|e| e.to_string())?;
+// Poppler 側で同じ注釈を見つけるための目印
+fn source_annot(dict: &Dictionary, page: u32, crop_origin: (f64, f64)) -> SourceAnnot {
+    let rect = dict.get(b"Rect")
+        .and_then(|o| o.as_array())
+        .ok()
+        .filter(|r| r.len() >= 4)
+        .map(|r| {
+            let (x1, y1, x2, y2) = (get_f64(&r[0]), get_f64(&r[1]), get_f64(&r[2]), get_f64(&r[3]));
+            let (ox, oy) = crop_origin;
+            (x1.min(x2) - ox, y1.min(y2) - oy, x1.max(x2) - ox, y1.max(y2) - oy)
+        });
+    SourceAnnot {
+        page,
+        name: dict.get(b"NM").and_then(|o| o.as_str()).ok()
+            .map(|b| decode_text_string(b).trim().to_string())
+            .filter(|nm| !nm.is_empty()),
+        rect,
+    }
+}
 
-    // 2. グループ化
-    let mut annots_by_page: HashMap<u32, Vec<AnnotationData>> = HashMap::new();
+// 返信を、返信先をたどった先の注釈のスレッドに加える (返信への返信も同じスレッドにまとめる)
+// 返信先が Margium の注釈でないものは読まない (保存時にもそのまま残る)
+// 結び付けた返信の目印は sources に加える (Margium が描く注釈の返信だけを Poppler の描画から外すため)
+fn attach_replies(
+    annotations: &mut [AnnotationData],
+    mut replies: Vec<PendingReply>,
+    used_ids: &mut HashSet<String>,
+    sources: &mut Vec<SourceAnnot>,
+) {
+    // オブジェクトID → (スレッドの注釈, 返信ならその id)
+    let mut owners: HashMap<ObjectId, (usize, Option<String>)> = annotations.iter()
+        .enumerate()
+        .filter_map(|(i, a)| a.object_id.map(|id| (id, (i, None))))
+        .collect();
+    loop {
+        let remaining = replies.len();
+        replies.retain(|(object_id, parent, dict, source)| {
+            let object_id = *object_id;
+            let Some((owner, parent_reply)) = owners.get(parent).cloned() else { return true };
+            let mut reply = parse_reply(dict, object_id, used_ids);
+            reply.parent = parent_reply;
+            if let Some(id) = object_id {
+                owners.insert(id, (owner, Some(reply.id.clone())));
+            }
+            annotations[owner].replies.push(reply);
+            sources.push(source.clone());
+            false
+        });
+        if replies.len() == remaining {
+            break;
+        }
+    }
     for ann in annotations {
-        annots_by_page.entry(ann.page as u32).or_default().push(ann);
+        ann.replies.sort_by_key(|r| r.created);
     }
+}
+
+// クリップボード用に、アノテーションだけを載せた1ページのPDFを作る
+// 保存と同じ形式で書き出すので、別のウィンドウ (別プロセス) でも read_annotations でそのまま読み戻せる
+pub fn annotations_to_clipboard_pdf(annotations: &[AnnotationData], page_size: (f64, f64)) -> Result<Vec<u8>, String> {
+    let mut doc = Document::with_version("1.7");
+    let pages_id = doc.new_object_id();
+    let page_id = doc.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "MediaBox" => vec![0.into(), 0.into(), Object::Real(page_size.0 as f32), Object::Real(page_size.1 as f32)],
+    });
+    doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+        "Type" => "Pages",
+        "Kids" => vec![page_id.into()],
+        "Count" => 1,
+    }));
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
 
-    // 3. 各ページ処理
+    // 元の文書のオブジェクト番号は、このPDFでは意味を持たない
+    let mut copies: Vec<AnnotationData> = annotations.iter().cloned()
+        .map(|mut ann| {
+            ann.page = 1;
+            ann.object_id = None;
+            // 返信は元の文書でのやり取りなので載せない
+            ann.replies.clear();
+            ann
+        })
+        .collect();
+    let mut update = Document::new_from_prev(&doc);
+    write_annotation_updates(&doc, &mut update, &mut copies, &HashMap::new());
+    doc.max_id = doc.max_id.max(update.max_id);
+    doc.objects.extend(update.objects);
+
+    let mut bytes = Vec::new();
+    doc.save_to(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+pub fn annotations_from_clipboard_pdf(bytes: &[u8]) -> Result<Vec<AnnotationData>, String> {
+    let doc = Document::load_mem(bytes).map_err(|e| e.to_string())?;
+    read_annotations(&doc).map(|loaded| loaded.annotations)
+}
+
+// incremental = true なら元ファイルの後ろに変更分だけを追記する (増分更新)
+// 電子署名のあるファイルは署名を壊さないよう、指定に関わらず常に追記で保存する
+// outline は目次を編集したときだけ渡す (None なら元の /Outlines をそのまま残す)
+// appearances に無い既存の注釈は、前回保存したときの外観 (/AP) をそのまま使う
+// 戻り値の2つ目は、署名があるために incremental = false でも追記で保存したかどうか
+pub fn save_pdf_with_annotations(
+    path: String,
+    output_path: String,
+    mut annotations: Vec<AnnotationData>,
+    appearances: &HashMap<String, AppearanceStream>,
+    outline: Option<&[OutlineItem]>,
+    incremental: bool,
+) -> Result<(Vec<AnnotationData>, bool), String> {
+    // 1. PDFを読み込む (追記保存では元のバイト列をそのまま使うので手元に残す)
+    let bytes = std::fs::read(&path).map_err(|e| e.to_string())?;
+    let mut doc = Document::load_mem(&bytes).map_err(|e| e.to_string())?;
+
+    // 2. 変更のあったオブジェクトだけを update に集める
+    let mut update = Document::new_from_prev(&doc);
+    write_annotation_updates(&doc, &mut update, &mut annotations, appearances);
+    if let Some(items) = outline {
+        outline::write_outline(&doc, &mut update, items)?;
+    }
+
+    // 3. 保存
+    // 表示中のPopplerが元ファイルを読んでいるため、一時ファイルに書いてから置き換える
+    let forced_incremental = !incremental && is_signed(&doc);
+    if incremental || forced_incremental {
+        let mut inc = IncrementalDocument::create_from(bytes, doc);
+        inc.new_document = update;
+        write_atomically(&output_path, |tmp| inc.save(tmp))?;
+    } else {
+        // 全体を書き直す (内部構造の整理を行いながら保存)
+        doc.max_id = doc.max_id.max(update.max_id);
+        doc.objects.extend(update.objects);
+        // 置き換え前の外観ストリームなど、どこからも参照されなくなったオブジェクトを捨てる
+        doc.prune_objects();
+        write_atomically(&output_path, |tmp| doc.save(tmp))?;
+    }
+    Ok((annotations, forced_incremental))
+}
+
+// doc (保存前の状態) と比べて変わったアノテーション・ページのオブジェクトを update に書き込む
+fn write_annotation_updates(
+    doc: &Document,
+    update: &mut Document,
+    annotations: &mut [AnnotationData],
+    appearances: &HashMap<String, AppearanceStream>,
+) {
+    // グループ化 (保存後にオブジェクトIDを書き戻すため、インデックスで持つ)
+    let mut annots_by_page: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, ann) in annotations.iter().enumerate() {
+        annots_by_page.entry(ann.page as u32).or_default().push(i);
+    }
+
+    // 各ページ処理
     for (page_num, page_id) in doc.get_pages() {
         // このページに紐づくUI上のアノテーションリスト
         let page_annots = annots_by_page.remove(&page_num).unwrap_or_default();
-        
+
         // 最終的にこのページに含まれるべきアノテーションの参照IDリスト
-        let mut final_annot_refs = Vec::new();
-        
+        // Margium が扱わない既存のアノテーションは、そのまま引き継ぐ
+        let current_annots = page_annotations(doc, page_id);
+        let mut final_annot_refs = foreign_annotations(doc, &current_annots);
+
+        // Margium のアノテーションが元々なく、今もないページは触らない
+        if page_annots.is_empty() && final_annot_refs.len() == current_annots.len() {
+            continue;
+        }
+
         // ページ情報の取得
-        let page_height = get_page_height(&doc, page_id).unwrap_or(842.0);
+        let page_height = get_page_height(doc, page_id).unwrap_or(842.0);
 
-        for ann in page_annots {
+        for idx in page_annots {
+            let ann = &mut annotations[idx];
             let pdf_y = page_height - ann.y as f32;
             let font_size = ann.font_size.unwrap_or(14.0);
 
             // 辞書データの作成
-            let mut annot_dict = Dictionary::new();
+            // 既存の注釈は元の辞書を土台にして、Margium が扱わないキー (/Popup など) を残す
+            let old_dict = ann.object_id.and_then(|id| doc.get_dictionary(id).ok());
+            let mut annot_dict = old_dict.cloned().unwrap_or_default();
+            annot_dict.remove(b"AP");
             annot_dict.set("Type", Object::Name(b"Annot".to_vec()));
-            annot_dict.set("Subtype", Object::Name(b"FreeText".to_vec()));
-            
-            let da_str = format!("0 0 0 rg /Helv {} Tf", font_size);
-            annot_dict.set("DA", Object::String(da_str.into_bytes(), StringFormat::Literal));
-            
-            annot_dict.set("Contents", Object::String(ann.content.clone().into_bytes(), StringFormat::Literal));
+
+            annot_dict.set("Contents", encode_text_string(&ann.content));
+            // 次回読み込み時に同じIDを復元できるよう /NM に保存
+            annot_dict.set("NM", encode_text_string(&ann.id));
             
-            annot_dict.set("Rect", Object::Array(vec![
-                Object::Real(ann.x as f32),
-                Object::Real(pdf_y - (font_size * 1.5)), 
-                Object::Real(ann.x as f32 + 200.0),       
-                Object::Real(pdf_y)
-            ]));
-
-            // 【高速化】IDを持っている(=既存の注釈)なら、そのオブジェクトIDを再利用して上書き
-            let object_id = if let Some(id) = ann.object_id {
-                // 既存オブジェクトを置換 (doc.objects BTreeMapを直接更新)
-                doc.objects.insert(id, Object::Dictionary(annot_dict));
-                id
-            } else {
-                // 新規なら新しいIDを発行して追加
-                doc.add_object(annot_dict)
+            // 印刷時にも表示する (/F 4 = Print)
+            annot_dict.set("F", Object::Integer(4));
+
+            if let Some(author) = &ann.author {
+                annot_dict.set("T", encode_text_string(author));
+            }
+            set_pdf_date(&mut annot_dict, b"CreationDate", ann.created);
+            set_pdf_date(&mut annot_dict, b"M", ann.modified);
+
+            if let Some(color) = ann.color {
+                annot_dict.set("C", color_array(color));
+            }
+
+            // 外観ストリームか描画時に記録した範囲があれば、実際の描画範囲で /Rect を決める
+            let appearance = appearances.get(&ann.id);
+            let rendered = appearance.map(|ap| (ap.x, ap.y, ap.width, ap.height)).or(ann.bounds.get());
+            let (x, top, width, height) = match (&ann.kind, rendered) {
+                (_, Some((bx, by, bw, bh))) => (bx as f32, page_height - by as f32, bw as f32, bh as f32),
+                (AnnotationKind::FreeText, None) => {
+                    (ann.x as f32, pdf_y, ann.width.unwrap_or(200.0) as f32, ann.height.map_or(font_size * 1.5, |h| h as f32))
+                }
+                (AnnotationKind::Markup { rects, .. }, None) => {
+                    let (bx, by, bw, bh) = bounding_rect(rects).unwrap_or((ann.x, ann.y, 0.0, 0.0));
+                    (bx as f32, page_height - by as f32, bw as f32, bh as f32)
+                }
+                (AnnotationKind::Ink { strokes, width }, None) => {
+                    let (bx, by, bw, bh) = ink_bounds(strokes, *width).unwrap_or((ann.x, ann.y, 0.0, 0.0));
+                    (bx as f32, page_height - by as f32, bw as f32, bh as f32)
+                }
+                (AnnotationKind::Shape { points, width, line_endings, .. }, None) => {
+                    let (bx, by, bw, bh) = shape_bounds(points, *width, *line_endings).unwrap_or((ann.x, ann.y, 0.0, 0.0));
+                    (bx as f32, page_height - by as f32, bw as f32, bh as f32)
+                }
+                (AnnotationKind::Note { .. }, None) => (ann.x as f32, pdf_y, NOTE_ICON_SIZE as f32, NOTE_ICON_SIZE as f32),
+            };
+            let rect = Object::Array(vec![
+                Object::Real(x),
+                Object::Real(top - height),
+                Object::Real(x + width),
+                Object::Real(top)
+            ]);
+            // 外観を作り直さなかった (前回の保存から見た目の変わっていない) 注釈は、元の /AP と /Rect を使い続ける
+            let kept_appearance = old_dict
+                .filter(|_| appearance.is_none())
+                .and_then(|old| old.get(b"AP").ok().zip(old.get(b"Rect").ok()));
+            let rect = match kept_appearance {
+                Some((_, old_rect)) => old_rect.clone(),
+                None => rect,
+            };
+            annot_dict.set("Rect", rect.clone());
+
+            // 付箋のポップアップ (オブジェクトID, 位置と大きさ, 開いているか)。付箋を書き込んでから書く
+            let mut note_popup = None;
+            match &ann.kind {
+                AnnotationKind::Note { icon, open, popup } => {
+                    annot_dict.set("Subtype", Object::Name(b"Text".to_vec()));
+                    annot_dict.set("Name", Object::Name(icon.name().to_vec()));
+                    annot_dict.set("Open", Object::Boolean(*open));
+                    // 拡大・回転してもアイコンの大きさと向きを変えない (/F 28 = Print | NoZoom | NoRotate)
+                    annot_dict.set("F", Object::Integer(28));
+                    // 付箋と /Popup は互いを参照するので、ポップアップのオブジェクトIDを先に決めておく
+                    let popup_id = annot_dict.get(b"Popup").and_then(|o| o.as_reference())
+                        .ok()
+                        .filter(|id| doc.get_dictionary(*id).is_ok())
+                        .unwrap_or_else(|| update.new_object_id());
+                    annot_dict.set("Popup", Object::Reference(popup_id));
+                    note_popup = Some((popup_id, *popup, *open));
+                }
+                AnnotationKind::FreeText => {
+                    annot_dict.set("Subtype", Object::Name(b"FreeText".to_vec()));
+                    let style = &ann.text_style;
+                    let (r, g, b) = style.text_color;
+                    let da_str = format!("{} {} {} rg /{} {} Tf", r as f32, g as f32, b as f32, style.font_family.da_font(), font_size);
+                    annot_dict.set("DA", Object::String(da_str.into_bytes(), StringFormat::Literal));
+                    let ds_str = format!("font: {}pt {}; color:{}", font_size, style.font_family.css_family(), color_hex(style.text_color));
+                    annot_dict.set("DS", Object::String(ds_str.into_bytes(), StringFormat::Literal));
+                    // FreeText の /C は背景色。空の配列で透明を表す
+                    annot_dict.set("C", style.background.map_or(Object::Array(vec![]), color_array));
+                    match style.opacity {
+                        Some(opacity) => annot_dict.set("CA", Object::Real(opacity as f32)),
+                        None => {
+                            annot_dict.remove(b"CA");
+                        }
+                    }
+                    let mut bs = annot_dict.get(b"BS").and_then(|o| o.as_dict()).cloned().unwrap_or_default();
+                    bs.set("W", Object::Real(style.border_width as f32));
+                    annot_dict.set("BS", Object::Dictionary(bs));
+                    annot_dict.remove(b"Border");
+                    // リッチテキスト (/RC) が残っていると、他のビューアでは古い内容が表示される
+                    annot_dict.remove(b"RC");
+                    let auto_size: Vec<Object> = [(ann.width, "Width"), (ann.height, "Height")].iter()
+                        .filter(|(size, _)| size.is_none())
+                        .map(|(_, name)| Object::Name(name.as_bytes().to_vec()))
+                        .collect();
+                    if auto_size.is_empty() {
+                        annot_dict.remove(b"MargiumAutoSize");
+                    } else {
+                        annot_dict.set("MargiumAutoSize", Object::Array(auto_size));
+                    }
+                }
+                AnnotationKind::Markup { style, rects } => {
+                    annot_dict.set("Subtype", Object::Name(style.subtype().to_vec()));
+                    annot_dict.set("QuadPoints", Object::Array(rects_to_quad_points(rects, page_height as f64)));
+                }
+                AnnotationKind::Ink { strokes, width } => {
+                    annot_dict.set("Subtype", Object::Name(b"Ink".to_vec()));
+                    let ink_list = strokes.iter().map(|stroke| {
+                        Object::Array(stroke.iter().flat_map(|&(x, y, _)| {
+                            [Object::Real(x as f32), Object::Real((page_height as f64 - y) as f32)]
+                        }).collect())
+                    }).collect();
+                    annot_dict.set("InkList", Object::Array(ink_list));
+                    let pressures = strokes.iter().map(|stroke| {
+                        Object::Array(stroke.iter().map(|&(_, _, p)| Object::Real(p as f32)).collect())
+                    }).collect();
+                    annot_dict.set(INK_PRESSURE_KEY, Object::Array(pressures));
+                    let mut bs = annot_dict.get(b"BS").and_then(|o| o.as_dict()).cloned().unwrap_or_default();
+                    bs.set("W", Object::Real(*width as f32));
+                    annot_dict.set("BS", Object::Dictionary(bs));
+                    annot_dict.remove(b"Border");
+                }
+                AnnotationKind::Shape { shape, points, width, fill, opacity, line_endings } => {
+                    annot_dict.set("Subtype", Object::Name(shape.subtype().to_vec()));
+                    let coords: Vec<Object> = points.iter()
+                        .flat_map(|&(x, y)| [Object::Real(x as f32), Object::Real((page_height as f64 - y) as f32)])
+                        .collect();
+                    match shape {
+                        // /Rect は線の太さ分だけ外側に取っているので、/RD は不要
+                        ShapeKind::Square | ShapeKind::Circle => {
+                            annot_dict.remove(b"RD");
+                        }
+                        ShapeKind::Line => annot_dict.set("L", Object::Array(coords)),
+                        ShapeKind::Polygon | ShapeKind::PolyLine => annot_dict.set("Vertices", Object::Array(coords)),
+                    }
+                    if matches!(shape, ShapeKind::Line | ShapeKind::PolyLine) {
+                        annot_dict.set("LE", Object::Array(vec![
+                            Object::Name(line_endings.0.name().to_vec()),
+                            Object::Name(line_endings.1.name().to_vec()),
+                        ]));
+                    }
+                    match fill {
+                        Some(fill) => annot_dict.set("IC", color_array(*fill)),
+                        None => {
+                            annot_dict.remove(b"IC");
+                        }
+                    }
+                    annot_dict.set("CA", Object::Real(*opacity as f32));
+                    let mut bs = annot_dict.get(b"BS").and_then(|o| o.as_dict()).cloned().unwrap_or_default();
+                    bs.set("W", Object::Real(*width as f32));
+                    annot_dict.set("BS", Object::Dictionary(bs));
+                    annot_dict.remove(b"Border");
+                }
+            }
+
+            // 前回保存時から何も変わっていなければ、既存のオブジェクトをそのまま使う
+            let unchanged = old_dict.is_some_and(|old| is_same_annotation(old, &annot_dict));
+
+            let object_id = match ann.object_id {
+                Some(id) if unchanged => id,
+                _ => {
+                    if let Some(ap_id) = appearance.and_then(|ap| import_appearance(update, ap).ok()) {
+                        let mut ap_dict = Dictionary::new();
+                        ap_dict.set("N", Object::Reference(ap_id));
+                        annot_dict.set("AP", Object::Dictionary(ap_dict));
+                    } else if let Some((old_ap, _)) = kept_appearance {
+                        annot_dict.set("AP", old_ap.clone());
+                    }
+
+                    // 【高速化】IDを持っている(=既存の注釈)なら、そのオブジェクトIDを再利用して上書き
+                    if let Some(id) = ann.object_id {
+                        update.objects.insert(id, Object::Dictionary(annot_dict));
+                        id
+                    } else {
+                        // 新規なら新しいIDを発行して追加
+                        update.add_object(annot_dict)
+                    }
+                }
             };
 
+            // 次回の保存で同じオブジェクトを上書きできるように記録
+            ann.object_id = Some(object_id);
             final_annot_refs.push(Object::Reference(object_id));
+
+            if let Some((popup_id, popup, open)) = note_popup {
+                write_note_popup(doc, update, popup_id, object_id, popup, open, page_height as f64);
+                final_annot_refs.push(Object::Reference(popup_id));
+            }
+
+            // 返信への返信は返信先のオブジェクトを /IRT で指すので、新しい返信にも先にIDを振っておく
+            let reply_ids: HashMap<String, ObjectId> = ann.replies.iter_mut()
+                .map(|reply| (reply.id.clone(), *reply.object_id.get_or_insert_with(|| update.new_object_id())))
+                .collect();
+            for reply in &ann.replies {
+                let parent = reply.parent.as_ref()
+                    .and_then(|id| reply_ids.get(id))
+                    .copied()
+                    .unwrap_or(object_id);
+                let reply_id = write_reply(doc, update, reply, parent, &rect);
+                final_annot_refs.push(Object::Reference(reply_id));
+            }
         }
 
-        // 4. ページの "Annots" 配列を更新
-        // UI上で削除されたアノテーションは final_annot_refs に含まれないため、
+        // ページの "Annots" 配列を更新
+        // UI上で削除された (Margium管理の) アノテーションは final_annot_refs に含まれないため、
         // ページ辞書から参照が消え、実質的に削除される（ファイルサイズ圧縮時に消えるゴミになる）
-        if let Ok(page_obj) = doc.get_object_mut(page_id) {
-            if let Ok(page_dict) = page_obj.as_dict_mut() {
-                if final_annot_refs.is_empty() {
-                    page_dict.remove(b"Annots");
-                } else {
-                    page_dict.set("Annots", Object::Array(final_annot_refs));
-                }
+        if final_annot_refs == current_annots {
+            continue;
+        }
+        if let Ok(page_dict) = doc.get_dictionary(page_id) {
+            let mut page_dict = page_dict.clone();
+            if final_annot_refs.is_empty() {
+                page_dict.remove(b"Annots");
+            } else {
+                page_dict.set("Annots", Object::Array(final_annot_refs));
             }
+            update.objects.insert(page_id, Object::Dictionary(page_dict));
         }
     }
+}
+
+// 外観ストリーム以外が一致していれば、同じ内容のアノテーションとみなす
+fn is_same_annotation(old: &Dictionary, new: &Dictionary) -> bool {
+    if !old.has(b"AP") || old.len() != new.len() + 1 {
+        return false;
+    }
+    new.iter().all(|(key, value)| old.get(key).is_ok_and(|o| is_same_object(o, value)))
+}
+
+// 数値は整数/実数の書き方の違い (保存すると 50.0 が 50 になる等) を無視して比べる
+fn is_same_object(a: &Object, b: &Object) -> bool {
+    match (a, b) {
+        (Object::Integer(_) | Object::Real(_), Object::Integer(_) | Object::Real(_)) => {
+            (get_f64(a) - get_f64(b)).abs() < 1e-3
+        }
+        (Object::Array(x), Object::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(p, q)| is_same_object(p, q))
+        }
+        (Object::Dictionary(x), Object::Dictionary(y)) => is_same_dictionary(x, y),
+        _ => a == b,
+    }
+}
+
+fn is_same_dictionary(x: &Dictionary, y: &Dictionary) -> bool {
+    x.len() == y.len() && y.iter().all(|(key, value)| x.get(key).is_ok_and(|o| is_same_object(o, value)))
+}
 
-    // 保存 (内部構造の整理を行いながら保存)
-    doc.save(path).map_err(|e| e.to_string())?;
-    Ok(())
-}
\ No newline at end of file
+// 電子署名が含まれているか (/AcroForm の SigFlags の SignaturesExist ビット、または /Perms)
+fn is_signed(doc: &Document) -> bool {
+    let Ok(catalog) = doc.catalog() else { return false };
+
+    let sig_flags = catalog.get(b"AcroForm").ok()
+        .and_then(|o| doc.dereference(o).ok())
+        .and_then(|(_, o)| o.as_dict().ok())
+        .and_then(|form| form.get(b"SigFlags").and_then(|o| o.as_i64()).ok())
+        .unwrap_or(0);
+
+    sig_flags & 1 != 0 || catalog.has(b"Perms")
+}
+
+// 外観用PDF(1ページ)を読み込み、そのページを Form XObject として doc に取り込む
+fn import_appearance(doc: &mut Document, ap: &AppearanceStream) -> Result<ObjectId, String> {
+    let src = Document::load_mem(&ap.pdf).map_err(|e| e.to_string())?;
+    let page_id = *src.get_pages().values().next().ok_or("Appearance has no page")?;
+    let content = src.get_page_content(page_id).map_err(|e| e.to_string())?;
+    let page_dict = src.get_dictionary(page_id).map_err(|e| e.to_string())?;
+
+    // フォントや透明度などのリソースは参照先ごとコピーする
+    let mut id_map = HashMap::new();
+    let resources = match page_dict.get(b"Resources") {
+        Ok(obj) => copy_object(&src, doc, obj, &mut id_map),
+        Err(_) => Object::Dictionary(Dictionary::new()),
+    };
+
+    let mut form = Dictionary::new();
+    form.set("Type", Object::Name(b"XObject".to_vec()));
+    form.set("Subtype", Object::Name(b"Form".to_vec()));
+    form.set("BBox", Object::Array(vec![
+        Object::Real(0.0),
+        Object::Real(0.0),
+        Object::Real(ap.width as f32),
+        Object::Real(ap.height as f32),
+    ]));
+    form.set("Resources", resources);
+
+    let mut stream = Stream::new(form, content);
+    let _ = stream.compress();
+    Ok(doc.add_object(stream))
+}
+
+// src 内のオブジェクトを、参照をたどりながら dst にコピーする (IDは振り直す)
+fn copy_object(src: &Document, dst: &mut Document, obj: &Object, id_map: &mut HashMap<ObjectId, ObjectId>) -> Object {
+    match obj {
+        Object::Reference(id) => {
+            if let Some(new_id) = id_map.get(id) {
+                return Object::Reference(*new_id);
+            }
+            let new_id = dst.new_object_id();
+            id_map.insert(*id, new_id);
+            let copied = match src.get_object(*id) {
+                Ok(target) => copy_object(src, dst, target, id_map),
+                Err(_) => Object::Null,
+            };
+            dst.objects.insert(new_id, copied);
+            Object::Reference(new_id)
+        }
+        Object::Array(arr) => {
+            Object::Array(arr.iter().map(|o| copy_object(src, dst, o, id_map)).collect())
+        }
+        Object::Dictionary(dict) => Object::Dictionary(copy_dictionary(src, dst, dict, id_map)),
+        Object::Stream(stream) => {
+            let dict = copy_dictionary(src, dst, &stream.dict, id_map);
+            Object::Stream(Stream::new(dict, stream.content.clone()).with_compression(stream.allows_compression))
+        }
+        other => other.clone(),
+    }
+}
+
+fn copy_dictionary(src: &Document, dst: &mut Document, dict: &Dictionary, id_map: &mut HashMap<ObjectId, ObjectId>) -> Dictionary {
+    let mut copied = Dictionary::new();
+    for (key, value) in dict.iter() {
+        copied.set(key.clone(), copy_object(src, dst, value, id_map));
+    }
+    copied
+}
+
+fn write_atomically(
+    output_path: &str,
+    save: impl FnOnce(&str) -> std::io::Result<std::fs::File>,
+) -> Result<(), String> {
+    let tmp_path = format!("{}.margium-tmp", output_path);
+    if let Err(e) = save(&tmp_path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.to_string());
+    }
+    std::fs::rename(&tmp_path, output_path).map_err(|e| e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(text: &str) -> (Vec<u8>, String) {
+        let Object::String(bytes, _) = encode_text_string(text) else { panic!("not a string") };
+        let decoded = decode_text_string(&bytes);
+        (bytes, decoded)
+    }
+
+    #[test]
+    fn ascii_is_written_as_pdf_doc_encoding() {
+        let (bytes, decoded) = round_trip("Hello, world");
+        assert_eq!(bytes, b"Hello, world");
+        assert_eq!(decoded, "Hello, world");
+    }
+
+    #[test]
+    fn pdf_doc_special_characters_round_trip() {
+        let (bytes, decoded) = round_trip("\u{2022} \u{20AC}\u{2122} \u{0141}\u{02DC}");
+        assert_eq!(bytes, [0x80, b' ', 0xA0, 0x92, b' ', 0x95, 0x1F]);
+        assert_eq!(decoded, "\u{2022} \u{20AC}\u{2122} \u{0141}\u{02DC}");
+    }
+
+    #[test]
+    fn cjk_is_written_as_utf16be() {
+        let (bytes, decoded) = round_trip("注釈 note");
+        assert_eq!(&bytes[..4], [0xFE, 0xFF, 0x6C, 0xE8]);
+        assert_eq!(decoded, "注釈 note");
+    }
+
+    #[test]
+    fn utf8_with_bom_is_decoded() {
+        let mut bytes = vec![0xEF, 0xBB, 0xBF];
+        bytes.extend("注釈 \u{2022}".as_bytes());
+        assert_eq!(decode_text_string(&bytes), "注釈 \u{2022}");
+    }
+
+    #[test]
+    fn utf16le_with_bom_is_decoded() {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend("注釈 note".encode_utf16().flat_map(|u| u.to_le_bytes()));
+        assert_eq!(decode_text_string(&bytes), "注釈 note");
+    }
+
+    #[test]
+    fn empty_string_round_trips() {
+        let (bytes, decoded) = round_trip("");
+        assert!(bytes.is_empty());
+        assert_eq!(decoded, "");
+    }
+    // --- 日付 ---
+
+    fn date(rfc3339: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap()
+    }
+
+    // 時刻だけでなく時差も同じか (DateTime の == は時差を見ない)
+    fn assert_same_date(parsed: Option<DateTime<FixedOffset>>, expected: &str) {
+        let parsed = parsed.expect("not parsed");
+        let expected = date(expected);
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.offset(), expected.offset());
+    }
+
+    #[test]
+    fn full_date_with_prefix_is_parsed() {
+        assert_same_date(parse_pdf_date("D:20240102030405+09'00'"), "2024-01-02T03:04:05+09:00");
+    }
+
+    #[test]
+    fn date_without_prefix_is_parsed() {
+        assert_same_date(parse_pdf_date("20240102030405+09'00'"), "2024-01-02T03:04:05+09:00");
+    }
+
+    #[test]
+    fn truncated_dates_use_defaults() {
+        assert_same_date(parse_pdf_date("D:2024"), "2024-01-01T00:00:00Z");
+        assert_same_date(parse_pdf_date("D:202403"), "2024-03-01T00:00:00Z");
+        assert_same_date(parse_pdf_date("D:20240315"), "2024-03-15T00:00:00Z");
+        assert_same_date(parse_pdf_date("D:2024031512"), "2024-03-15T12:00:00Z");
+        assert_same_date(parse_pdf_date("D:202403151234"), "2024-03-15T12:34:00Z");
+    }
+
+    #[test]
+    fn utc_date_is_parsed() {
+        assert_same_date(parse_pdf_date("D:20240102030405Z"), "2024-01-02T03:04:05Z");
+        assert_same_date(parse_pdf_date("D:20240102030405Z00'00'"), "2024-01-02T03:04:05Z");
+    }
+
+    #[test]
+    fn offsets_are_parsed() {
+        assert_same_date(parse_pdf_date("D:20240102030405+05'30'"), "2024-01-02T03:04:05+05:30");
+        assert_same_date(parse_pdf_date("D:20240102030405-0500"), "2024-01-02T03:04:05-05:00");
+        assert_same_date(parse_pdf_date("D:20240102030405-08'00"), "2024-01-02T03:04:05-08:00");
+        assert_same_date(parse_pdf_date("D:20240102030405+09"), "2024-01-02T03:04:05+09:00");
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        assert_eq!(parse_pdf_date(""), None);
+        assert_eq!(parse_pdf_date("D:"), None);
+        assert_eq!(parse_pdf_date("D:abcd"), None);
+        assert_eq!(parse_pdf_date("D:20241301"), None);
+        assert_eq!(parse_pdf_date("D:20240230"), None);
+        assert_eq!(parse_pdf_date("D:20240102250000Z"), None);
+    }
+
+    #[test]
+    fn formatted_date_parses_back() {
+        for text in ["2024-01-02T03:04:05+09:00", "2024-12-31T23:59:59-05:00", "2024-06-15T00:00:00Z", "2024-06-15T08:30:00+05:30"] {
+            let date = date(text);
+            let formatted = format_pdf_date(&date);
+            assert_same_date(parse_pdf_date(&formatted), text);
+        }
+        assert_eq!(format_pdf_date(&date("2024-01-02T03:04:05+09:00")), "D:20240102030405+09'00'");
+        assert_eq!(format_pdf_date(&date("2024-01-02T03:04:05-05:30")), "D:20240102030405-05'30'");
+        assert_eq!(format_pdf_date(&date("2024-01-02T03:04:05Z")), "D:20240102030405+00'00'");
+    }
+
+    // --- 保存 ---
+
+    // テストごとに別の一時ファイル
+    fn temp_pdf(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("margium-test-{}-{}.pdf", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // A4 1ページの PDF。annots をそのページの /Annots に入れる (返り値はそれぞれのオブジェクトID)
+    fn one_page_pdf(annots: Vec<Dictionary>, catalog_extra: Dictionary) -> (Document, Vec<ObjectId>) {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let page_id = doc.new_object_id();
+        let annot_ids: Vec<ObjectId> = annots.into_iter()
+            .map(|mut annot| {
+                annot.set("P", Object::Reference(page_id));
+                doc.add_object(annot)
+            })
+            .collect();
+        let content_id = doc.add_object(Stream::new(Dictionary::new(), b"0 0 1 rg 10 10 50 50 re f".to_vec()));
+        let mut page = dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            "Contents" => content_id,
+            "Resources" => Dictionary::new(),
+        };
+        if !annot_ids.is_empty() {
+            page.set("Annots", Object::Array(annot_ids.iter().map(|&id| Object::Reference(id)).collect()));
+        }
+        doc.objects.insert(page_id, Object::Dictionary(page));
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1,
+        }));
+        let mut catalog = dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        };
+        catalog.extend(&catalog_extra);
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", catalog_id);
+        (doc, annot_ids)
+    }
+
+    fn write_pdf(path: &str, annots: Vec<Dictionary>, catalog_extra: Dictionary) -> Vec<ObjectId> {
+        let (mut doc, annot_ids) = one_page_pdf(annots, catalog_extra);
+        doc.save(path).unwrap();
+        annot_ids
+    }
+
+    // 外観ストリームの元になる 1ページの PDF (描画結果の代わり)
+    fn appearance(x: f64, y: f64) -> AppearanceStream {
+        let (mut doc, _) = one_page_pdf(Vec::new(), Dictionary::new());
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+        AppearanceStream { x, y, width: 120.0, height: 24.0, pdf }
+    }
+
+    fn free_text(id: &str, content: &str, y: f64) -> AnnotationData {
+        let date = parse_pdf_date("D:20240102030405+09'00'");
+        AnnotationData {
+            page: 1,
+            x: 100.0,
+            y,
+            content: content.to_string(),
+            font_size: Some(12.0),
+            id: id.to_string(),
+            object_id: None,
+            kind: AnnotationKind::FreeText,
+            color: None,
+            width: Some(120.0),
+            height: Some(24.0),
+            text_style: TextStyle::default(),
+            author: Some("Tester".to_string()),
+            created: date,
+            modified: date,
+            replies: Vec::new(),
+            bounds: Cell::new(None),
+        }
+    }
+
+    fn load(path: &str) -> LoadedAnnotations {
+        read_annotations(&load_document(path).unwrap()).unwrap()
+    }
+
+    // bytes に id のオブジェクトが書かれているか ("12 0 obj" を "2 0 obj" と取り違えないようにする)
+    fn writes_object(bytes: &[u8], id: ObjectId) -> bool {
+        let header = format!("{} {} obj", id.0, id.1);
+        bytes.windows(header.len())
+            .enumerate()
+            .any(|(i, window)| window == header.as_bytes() && (i == 0 || !bytes[i - 1].is_ascii_digit()))
+    }
+
+    fn annotation_ids(annotations: &[AnnotationData]) -> Vec<(String, Option<ObjectId>)> {
+        annotations.iter().map(|a| (a.id.clone(), a.object_id)).collect()
+    }
+
+    #[test]
+    fn incremental_save_appends_only_changes() {
+        let path = temp_pdf("incremental");
+        write_pdf(&path, Vec::new(), Dictionary::new());
+        let original = std::fs::read(&path).unwrap();
+
+        // 1回目: 新しい注釈を2つ追加する
+        let annotations = vec![free_text("first", "First", 100.0), free_text("second", "Second", 200.0)];
+        let appearances = HashMap::from([
+            ("first".to_string(), appearance(100.0, 100.0)),
+            ("second".to_string(), appearance(100.0, 200.0)),
+        ]);
+        let (saved, forced) = save_pdf_with_annotations(path.clone(), path.clone(), annotations, &appearances, None, true).unwrap();
+        assert!(!forced);
+        let first_save = std::fs::read(&path).unwrap();
+        assert!(first_save.len() > original.len());
+        assert_eq!(&first_save[..original.len()], &original[..]);
+
+        // 読み戻すと同じ注釈が同じオブジェクトで見つかる
+        let mut loaded = load(&path).annotations;
+        assert_eq!(annotation_ids(&loaded), annotation_ids(&saved));
+        let first_id = loaded[0].object_id.unwrap();
+        let second_id = loaded[1].object_id.unwrap();
+
+        // 2回目: 2つ目だけ編集する
+        loaded[1].content = "Edited".to_string();
+        let appearances = HashMap::from([("second".to_string(), appearance(100.0, 200.0))]);
+        save_pdf_with_annotations(path.clone(), path.clone(), loaded, &appearances, None, true).unwrap();
+        let second_save = std::fs::read(&path).unwrap();
+        assert_eq!(&second_save[..first_save.len()], &first_save[..]);
+        let appended = &second_save[first_save.len()..];
+        assert!(writes_object(appended, second_id));
+        assert!(!writes_object(appended, first_id));
+
+        // 編集した方は新しい版が読まれ、編集していない方はそのまま
+        let reloaded = load(&path).annotations;
+        assert_eq!(reloaded[0].content, "First");
+        assert_eq!(reloaded[0].object_id, Some(first_id));
+        assert_eq!(reloaded[1].content, "Edited");
+        assert_eq!(reloaded[1].object_id, Some(second_id));
+
+        // 3回目: 何も変えずに保存しても、注釈は書き直さない
+        save_pdf_with_annotations(path.clone(), path.clone(), reloaded, &HashMap::new(), None, true).unwrap();
+        let third_save = std::fs::read(&path).unwrap();
+        assert_eq!(&third_save[..second_save.len()], &second_save[..]);
+        let appended = &third_save[second_save.len()..];
+        assert!(!writes_object(appended, first_id));
+        assert!(!writes_object(appended, second_id));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn signed_file_is_always_saved_incrementally() {
+        let path = temp_pdf("signed");
+        write_pdf(&path, Vec::new(), dictionary! { "AcroForm" => dictionary! { "Fields" => Vec::<Object>::new(), "SigFlags" => 3 } });
+        let original = std::fs::read(&path).unwrap();
+
+        let annotations = vec![free_text("note", "Note", 100.0)];
+        let (_, forced) = save_pdf_with_annotations(path.clone(), path.clone(), annotations, &HashMap::new(), None, false).unwrap();
+        assert!(forced);
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!(&saved[..original.len()], &original[..]);
+        assert_eq!(load(&path).annotations.len(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn page_annots(doc: &Document) -> Vec<Object> {
+        let page_id = *doc.get_pages().values().next().unwrap();
+        page_annotations(doc, page_id)
+    }
+
+    #[test]
+    fn foreign_annotations_survive_saving() {
+        let path = temp_pdf("foreign");
+        let rect = || vec![100.into(), 700.into(), 200.into(), 720.into()];
+        let ids = write_pdf(&path, vec![
+            dictionary! {
+                "Type" => "Annot",
+                "Subtype" => "Link",
+                "Rect" => rect(),
+                "A" => dictionary! { "S" => "URI", "URI" => Object::string_literal("https://example.com") },
+            },
+            // 他のビューアで付けたハイライト (Margium でも編集できる)
+            dictionary! {
+                "Type" => "Annot",
+                "Subtype" => "Highlight",
+                "Rect" => rect(),
+                "QuadPoints" => vec![100.into(), 720.into(), 200.into(), 720.into(), 100.into(), 700.into(), 200.into(), 700.into()],
+                "C" => vec![1.into(), 1.into(), 0.into()],
+            },
+            // QuadPoints のないハイライトは Margium では扱えないので、そのまま残す
+            dictionary! {
+                "Type" => "Annot",
+                "Subtype" => "Highlight",
+                "Rect" => rect(),
+            },
+        ], Dictionary::new());
+        let (link_id, highlight_id, broken_id) = (ids[0], ids[1], ids[2]);
+
+        // 読み込むのは Margium が扱えるハイライトだけ
+        let mut annotations = load(&path).annotations;
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].object_id, Some(highlight_id));
+
+        // 注釈を追加して、全体を書き直す
+        annotations.push(free_text("added", "Added", 300.0));
+        save_pdf_with_annotations(path.clone(), path.clone(), annotations, &HashMap::new(), None, false).unwrap();
+        let doc = load_document(&path).unwrap();
+        let annots = page_annots(&doc);
+        assert!(annots.contains(&Object::Reference(link_id)));
+        assert!(annots.contains(&Object::Reference(highlight_id)));
+        assert!(annots.contains(&Object::Reference(broken_id)));
+        assert_eq!(annots.len(), 4);
+        let link = doc.get_dictionary(link_id).unwrap();
+        assert_eq!(link.get(b"Subtype").unwrap().as_name().unwrap(), b"Link");
+
+        // Margium の注釈をすべて消しても、扱えない注釈は残る
+        save_pdf_with_annotations(path.clone(), path.clone(), Vec::new(), &HashMap::new(), None, false).unwrap();
+        let doc = load_document(&path).unwrap();
+        assert_eq!(page_annots(&doc), [Object::Reference(link_id), Object::Reference(broken_id)]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn annotation_names_are_unique_and_stable() {
+        let path = temp_pdf("names");
+        let square = |name: Option<&str>| {
+            let mut dict = dictionary! {
+                "Type" => "Annot",
+                "Subtype" => "Square",
+                "Rect" => vec![100.into(), 600.into(), 200.into(), 700.into()],
+            };
+            if let Some(name) = name {
+                dict.set("NM", Object::string_literal(name));
+            }
+            dict
+        };
+        // 名前の重複したものと、名前のないもの
+        write_pdf(&path, vec![square(Some("shared")), square(Some("shared")), square(None)], Dictionary::new());
+
+        let first = load(&path).annotations;
+        let names: Vec<&str> = first.iter().map(|a| a.id.as_str()).collect();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "shared");
+        assert!(names[1] != "shared" && !names[1].is_empty());
+        assert!(!names[2].is_empty());
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), 3);
+
+        // 保存すると /NM に書かれ、次に読んでも同じ名前になる
+        save_pdf_with_annotations(path.clone(), path.clone(), first.clone(), &HashMap::new(), None, false).unwrap();
+        let second = load(&path).annotations;
+        assert_eq!(annotation_ids(&second), annotation_ids(&first));
+        save_pdf_with_annotations(path.clone(), path.clone(), second.clone(), &HashMap::new(), None, true).unwrap();
+        let third = load(&path).annotations;
+        assert_eq!(annotation_ids(&third), annotation_ids(&first));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unchanged_annotation_is_recognized() {
+        let annotations = vec![free_text("note", "Note", 100.0)];
+        let (doc, _) = one_page_pdf(Vec::new(), Dictionary::new());
+        let mut saved = annotations.clone();
+        let mut update = Document::new_from_prev(&doc);
+        let appearances = HashMap::from([("note".to_string(), appearance(100.0, 100.0))]);
+        write_annotation_updates(&doc, &mut update, &mut saved, &appearances);
+        let id = saved[0].object_id.unwrap();
+        let written = update.get_dictionary(id).unwrap();
+
+        // /AP 以外が同じなら同じ注釈、内容が違えば別の注釈
+        let mut same = written.clone();
+        same.remove(b"AP");
+        assert!(is_same_annotation(written, &same));
+        // 保存で 12.0 が 12 になっても同じとみなす
+        same.set("F", Object::Real(4.0));
+        assert!(is_same_annotation(written, &same));
+        let mut edited = same.clone();
+        edited.set("Contents", encode_text_string("Edited"));
+        assert!(!is_same_annotation(written, &edited));
+        // 外観のない古い注釈は、常に書き直す
+        assert!(!is_same_annotation(&same, &same));
+    }
+}
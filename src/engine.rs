@@ -1,34 +1,964 @@
 use poppler::{Document};
 use std::path::PathBuf;
 use cairo::Context;
-use crate::annotations::{AnnotationData};
+use crate::outline::OutlineItem;
+use crate::annotations::{self, AnnotationData, AnnotationKind, AppearanceStream, FontFamily, LineEnding, LoadedAnnotations, MarkupStyle, NoteIcon, Reply, ReviewState, ShapeKind, SourceAnnot, TextStyle, NOTE_COLOR, NOTE_ICON_SIZE};
 
 
-use std::fs::File;
-use std::io::Write;
-use std::env;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use mathjax_svg::convert_to_svg;
 use rsvg::{Loader, CairoRenderer};
 use gtk4::gdk;
+use gtk4::gio;
 use gtk4::glib;
+use gtk4::glib::translate::{from_glib_none, ToGlibPtr};
+use poppler::prelude::*;
 use poppler::Rectangle;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 use rsvg::SvgHandle;
 
 
+// タイトルが変わったときに呼ぶコールバック
+type TitleListener = Box<dyn Fn(&str)>;
+
+// ページ内のリンクの範囲 (上を 0 にした PDF座標の 左, 上, 右, 下) と行き先
+type PageLink = ((f64, f64, f64, f64), Option<LinkTarget>);
+
 pub struct PdfEngine {
     doc: Option<Document>,
     lo_doc: Option<lopdf::Document>,
+    // 開いた文書の番号。ファイルを開くたびに増やし、前のファイルの読み込み結果 (別スレッド) を捨てるのに使う
+    generation: u64,
     filename: String,
     current_page: i32,
     total_pages: i32,
+    // 各ページの大きさ (PDF座標)。ページの配置の計算に使うので、開いたときにまとめて取っておく
+    page_sizes: Vec<(f64, f64)>,
     filepath: Option<PathBuf>,
+    // ページの並べ方
+    pub view_mode: ViewMode,
 
     pub annotations: Vec<AnnotationData>,
-    pub highlight_rects: Vec<Rectangle>,
     pub search_results_cache: HashMap<i32, Vec<Rectangle>>,
     pub active_annotation_id: Option<String>,
+    // マウスで選択中のテキスト範囲 (UI座標の矩形: x, y, 幅, 高さ) と、その文字列
+    text_selection: Vec<(f64, f64, f64, f64)>,
+    selected_text: String,
+    // ペンを離す前の手書きの線と、直前に手書きを追加したアノテーション (続けて書いた線をまとめる)
+    ink_stroke: Option<InkStroke>,
+    last_ink_stroke: Option<(String, Instant)>,
+    // ドラッグで作成中の図形 (多角形は頂点を置き終わるまで)
+    shape_draft: Option<AnnotationData>,
+    // MathJax で変換・解析した SVG のキャッシュ (キーは内容とフォントサイズ)
+    // 描画のたびに変換し直さないよう &self からも更新できるようにしている
+    svg_cache: RefCell<HashMap<SvgCacheKey, Option<Rc<CachedSvg>>>>,
+    // 折り返しのために文字の幅を測る cairo の文脈 (測るたびに作り直さない)
+    measure_context: Option<Context>,
+    // リンクの範囲と行き先 (ページ番号と一緒に持つ)。マウスが動くたびに Poppler から読み直さないためのもの
+    // ページを移ったときと、別の文書を開いたときに捨てる
+    link_cache: RefCell<Option<(i32, Vec<PageLink>)>>,
+    // 読み込んだ注釈の、PDF 内での元の注釈。Margium が描くので Poppler 側の描画からは外す
+    // 外すのはページを初めて描くときで、外し終えたページを hidden_pages に記録する
+    source_annots: Vec<SourceAnnot>,
+    hidden_pages: RefCell<HashSet<i32>>,
+    // 未保存の変更があるかどうか (タイトルの "*" と終了時の確認に使う)
+    dirty: bool,
+    // ウィンドウタイトル (ファイル名と "*") が変わったときに呼ぶ
+    title_listener: Option<TitleListener>,
+    // 保存時にファイル全体を書き直さず、変更分だけを追記する (署名済みPDFは常に追記)
+    pub incremental_save: bool,
+    // 署名済みのため追記で保存したことを、このファイルでもう知らせたか
+    signed_save_notified: bool,
+    // 読み込み・保存の後に変更した注釈のID。保存時にはこれらの外観 (/AP) だけを作り直す
+    edited_ids: HashSet<String>,
+    // 新しく作る注釈の作成者 (/T)。入力を確定したら設定ファイルにも保存して、次の起動でも使う
+    pub author: String,
+    // 元に戻す・やり直しの履歴 (古いものは undo_stack の先頭から捨てる)
+    undo_stack: VecDeque<EditCommand>,
+    redo_stack: Vec<EditCommand>,
+    // 最後に読み込み・保存したときの undo_stack の長さ。そこまで戻したら未保存の変更は無い
+    // (その時点が履歴から押し出されたり、やり直せなくなったりしたら None)
+    saved_undo_len: Option<usize>,
+    // ドラッグ中など、ひとまとめにして記録している途中の変更
+    pending_edit: Option<Vec<EditCommand>>,
+    // リンクや目次で移動する前の表示位置 (戻る・進む)
+    back_history: VecDeque<ViewPosition>,
+    forward_history: Vec<ViewPosition>,
+    // 目次 (しおり)。編集したときだけ保存時に /Outlines を書き直す
+    outline: Vec<OutlineItem>,
+    outline_edited: bool,
+    // outline が文字の大きさから推測した目次か (受け入れるか編集するまで保存しない)
+    outline_suggested: bool,
+}
+
+// ページの並べ方 (ツールバーで切り替える)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewMode {
+    SinglePage, // 現在のページだけを表示する
+    Continuous, // 全ページを縦に並べて、スクロールで読む
+    TwoPage,    // 見開き (1・2ページ、3・4ページ... と奇数ページを左に並べる)
+    Book,       // 本の見開き (表紙だけを1枚で表示し、2・3ページ、4・5ページ... と並べる)
+}
+
+impl ViewMode {
+    pub const ALL: [ViewMode; 4] = [ViewMode::SinglePage, ViewMode::Continuous, ViewMode::TwoPage, ViewMode::Book];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ViewMode::SinglePage => "Single Page",
+            ViewMode::Continuous => "Continuous",
+            ViewMode::TwoPage => "Two Pages",
+            ViewMode::Book => "Book",
+        }
+    }
+}
+
+// ページの上下の余白と、連続表示でのページの間隔 (画面上のピクセル)
+pub const PAGE_MARGIN: f64 = 20.0;
+const PAGE_GAP: f64 = 20.0;
+// 見開きの左右のページの間隔 (画面上のピクセル)
+const SPREAD_GAP: f64 = 8.0;
+
+// 画面上でのページの位置と大きさ (DrawingArea の座標、ピクセル)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageSlot {
+    pub page: i32,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl PageSlot {
+    // 画面上の点を、このページのPDF座標 (左上が原点) に変換する
+    pub fn to_pdf(self, x: f64, y: f64, scale: f64) -> (f64, f64) {
+        ((x - self.x) / scale, (y - self.y) / scale)
+    }
+}
+
+// 戻る・進むの履歴に残す表示位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewPosition {
+    pub page: i32,
+    pub scroll: f64, // PDFエリアの縦スクロール量 (画面上のピクセル)
+    pub scale: f64,
+}
+
+// 目次の項目 index の子孫の範囲の終わり (子孫は index+1..end)
+fn outline_subtree_end(items: &[OutlineItem], index: usize) -> usize {
+    let level = items[index].level;
+    items[index + 1..].iter()
+        .position(|item| item.level <= level)
+        .map_or(items.len(), |offset| index + 1 + offset)
+}
+
+// 項目 from を子項目ごと、項目 target を落とした位置へ動かす (動かした先の位置を返す)
+// 下へ動かすときは target の子項目の後ろ、上へ動かすときは target の前に入れる
+// (target とその子項目の間に入れると、target の子項目が動かした項目の子になってしまう)
+fn move_outline_subtree(items: &mut Vec<OutlineItem>, from: usize, target: usize) -> Option<usize> {
+    if from >= items.len() || target >= items.len() || from == target {
+        return None;
+    }
+    let end = outline_subtree_end(items, from);
+    // 自分の子項目の上へは動かせない
+    if target > from && target < end {
+        return None;
+    }
+    let to = if target > from { outline_subtree_end(items, target) } else { target };
+    let moved: Vec<OutlineItem> = items.drain(from..end).collect();
+    let insert_at = if target > from { to - moved.len() } else { to };
+    items.splice(insert_at..insert_at, moved);
+    Some(insert_at)
+}
+
+// 項目を子項目ごと delta 段深くする。深くできるのは直前の項目の子になれるときだけ
+fn indent_outline_subtree(items: &mut [OutlineItem], index: usize, delta: i32) -> bool {
+    let Some(item) = items.get(index) else { return false };
+    let level = item.level + delta;
+    let max_level = index.checked_sub(1).map_or(0, |prev| items[prev].level + 1);
+    if level < 0 || level > max_level {
+        return false;
+    }
+    let end = outline_subtree_end(items, index);
+    for item in &mut items[index..end] {
+        item.level += delta;
+    }
+    true
+}
+
+// 項目を削除し、子項目は1段浅くして残す
+fn remove_outline_entry(items: &mut Vec<OutlineItem>, index: usize) -> bool {
+    if index >= items.len() {
+        return false;
+    }
+    let end = outline_subtree_end(items, index);
+    for item in &mut items[index + 1..end] {
+        item.level -= 1;
+    }
+    items.remove(index);
+    true
+}
+
+// どの項目も直前の項目の高々1段下になるよう階層を詰める
+fn clamp_outline_levels(items: &mut [OutlineItem]) {
+    let mut max_level = 0;
+    for item in items {
+        item.level = item.level.clamp(0, max_level);
+        max_level = item.level + 1;
+    }
+}
+
+// 元に戻せる変更の単位 (変更前後の注釈をまるごと覚えておく)
+#[derive(Debug, Clone)]
+enum EditCommand {
+    Add { index: usize, ann: AnnotationData },
+    Remove { index: usize, ann: AnnotationData },
+    Modify { before: AnnotationData, after: AnnotationData },
+    Group(Vec<EditCommand>),
+}
+
+// アノテーション枠の内側の左右余白
+const ANNOTATION_PADDING: f64 = 5.0;
+
+// テキスト選択範囲を取得するときの倍率
+// (選択範囲は整数座標の cairo::Region で返るため、拡大して精度を上げる)
+const SELECTION_SCALE: f64 = 4.0;
+
+// この時間内に書き始めた線は、直前の手書きアノテーションに追加する
+const INK_MERGE_INTERVAL: Duration = Duration::from_millis(1500);
+// これより近い点は間引く (PDF座標)
+const INK_MIN_DISTANCE: f64 = 0.5;
+
+// 当たり判定で描画範囲の外側に許す余白 (PDF座標)
+const HIT_MARGIN: f64 = 3.0;
+
+// 付箋のポップアップの文字の大きさ (PDF座標)
+const NOTE_POPUP_FONT_SIZE: f64 = 10.0;
+
+// 枠の大きさを変えるつまみの大きさ (PDF座標)
+const RESIZE_HANDLE_SIZE: f64 = 6.0;
+
+// 元に戻せる操作の数
+const UNDO_LIMIT: usize = 200;
+
+// 戻れる移動の数
+const HISTORY_LIMIT: usize = 100;
+
+// 複製・貼り付けで元の注釈と重ならないようにずらす量 (PDF座標)
+const PASTE_OFFSET: f64 = 10.0;
+
+// 描画中 (ペンを離す前) の手書きの線
+struct InkStroke {
+    points: Vec<(f64, f64, f64)>, // x, y, 筆圧
+    color: (f64, f64, f64),
+    width: f64,
+}
+
+// 手書きの点列を平滑化する (両端を残して前後の点と平均を取る)
+fn smooth_stroke(points: &[(f64, f64, f64)]) -> Vec<(f64, f64, f64)> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let mut smoothed = vec![points[0]];
+    for w in points.windows(3) {
+        smoothed.push((
+            (w[0].0 + w[1].0 * 2.0 + w[2].0) / 4.0,
+            (w[0].1 + w[1].1 * 2.0 + w[2].1) / 4.0,
+            (w[0].2 + w[1].2 * 2.0 + w[2].2) / 4.0,
+        ));
+    }
+    smoothed.push(points[points.len() - 1]);
+    smoothed
+}
+
+// アプリの設定ファイル (~/.config/margium/settings.ini)
+const SETTINGS_GROUP: &str = "general";
+const SETTINGS_AUTHOR_KEY: &str = "author";
+
+fn settings_path() -> PathBuf {
+    glib::user_config_dir().join("margium").join("settings.ini")
+}
+
+// 保存しておいた作成者。設定が無ければ OS のユーザー名を使う
+fn load_author() -> String {
+    let key_file = glib::KeyFile::new();
+    key_file.load_from_file(settings_path(), glib::KeyFileFlags::NONE).ok()
+        .and_then(|_| key_file.string(SETTINGS_GROUP, SETTINGS_AUTHOR_KEY).ok())
+        .map(|author| author.to_string())
+        .unwrap_or_else(default_author)
+}
+
+fn save_author(author: &str) -> Result<(), String> {
+    let path = settings_path();
+    let key_file = glib::KeyFile::new();
+    // 他の設定を消さないように、読めるなら読んでから書き換える
+    let _ = key_file.load_from_file(&path, glib::KeyFileFlags::KEEP_COMMENTS);
+    key_file.set_string(SETTINGS_GROUP, SETTINGS_AUTHOR_KEY, author);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    key_file.save_to_file(&path).map_err(|e| e.to_string())
+}
+
+// 作成者の初期値 (OS のユーザーのフルネーム。無ければログイン名)
+fn default_author() -> String {
+    let real_name = glib::real_name().to_string_lossy().to_string();
+    if real_name.is_empty() || real_name == "Unknown" {
+        glib::user_name().to_string_lossy().to_string()
+    } else {
+        real_name
+    }
+}
+
+// SVG キャッシュのキー (SVG の見た目を変える値をすべて含める。浮動小数点はビット列で持つ)
+// 枠の幅は、折り返した後のテキストとして含める (幅を変えても改行位置が同じなら変換し直さない)
+#[derive(PartialEq, Eq, Hash)]
+struct SvgCacheKey {
+    text: String,
+    font_size: u32,
+    font_family: FontFamily,
+    text_color: [u64; 3],
+}
+
+// MathJax で変換した SVG の解析結果と、その元の大きさ
+struct CachedSvg {
+    handle: SvgHandle,
+    width: f64,
+    height: f64,
+    lines: usize, // 折り返した後の行数
+}
+
+// layout_annotation の計算結果
+struct AnnotationLayout {
+    svg: Option<Rc<CachedSvg>>,
+    scale: f64,
+    width: f64,  // 背景を含めた幅
+    height: f64,
+}
+
+// 行を max_width に収まるように折り返す
+// 幅は cairo で同じ系統の書体で測った目安 (MathJax の実際の幅とのずれは layout_annotation の縮小で吸収する)
+fn wrap_text(context: &Context, content: &str, family: FontFamily, font_size: f64, max_width: f64) -> String {
+    context.select_font_face(cairo_font_family(family), cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+    context.set_font_size(font_size);
+    let measure = |text: &str| context.text_extents(text).map(|e| e.x_advance()).unwrap_or(0.0);
+
+    let mut lines = Vec::new();
+    for paragraph in content.split('\n') {
+        let mut line = String::new();
+        for token in wrap_tokens(paragraph) {
+            let candidate = format!("{}{}", line, token);
+            if !line.trim().is_empty() && measure(candidate.trim_end()) > max_width {
+                lines.push(line.trim_end().to_string());
+                line = token.trim_start().to_string();
+            } else {
+                line = candidate;
+            }
+        }
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
+}
+
+// 折り返し位置で区切る: 単語 (後ろの空白を含む)、日本語などの全角文字は1文字ずつ
+// $...$ の数式の途中では区切らない
+fn wrap_tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_math = false;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '$' {
+            in_math = !in_math;
+        }
+        let next = chars.peek().map(|&(_, n)| n);
+        let at_break = match next {
+            None => true,
+            _ if in_math => false,
+            Some(n) => (c.is_whitespace() && !n.is_whitespace()) || is_wide_char(c) || is_wide_char(n),
+        };
+        if at_break {
+            let end = i + c.len_utf8();
+            tokens.push(&text[start..end]);
+            start = end;
+        }
+    }
+    tokens
+}
+
+// 日本語・中国語などの全角文字 (CJK 以降の文字) か
+fn is_wide_char(c: char) -> bool {
+    c >= '\u{2E80}'
+}
+
+// cairo (fontconfig) の総称フォント名
+fn cairo_font_family(family: FontFamily) -> &'static str {
+    match family {
+        FontFamily::Serif => "Serif",
+        FontFamily::Sans => "Sans",
+        FontFamily::Monospace => "Monospace",
+    }
+}
+
+// 複数行のテキストを LaTeX の \begin{array}{l} (左揃え) に展開する
+// 書体は \text (明朝系) / \textsf (ゴシック系) / \texttt (等幅) で切り替える
+fn annotation_latex(content: &str, family: FontFamily) -> String {
+    let text_command = match family {
+        FontFamily::Serif => "\\text",
+        FontFamily::Sans => "\\textsf",
+        FontFamily::Monospace => "\\texttt",
+    };
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut latex = String::from("\\begin{array}{l}\n");
     
+    for (i, line) in lines.iter().enumerate() {
+        // 空行対策（空行だと高さが潰れるためダミー文字を見えなくして置く）
+        if line.trim().is_empty() {
+            latex.push_str(&format!("{}{{\\phantom{{A}}}}", text_command));
+        } else {
+            latex.push_str(&format!("{}{{{}}}", text_command, line));
+        }
+        
+        // 最後の行以外は改行記号 \\ をつける
+        if i < lines.len() - 1 {
+            latex.push_str(" \\\\\n");
+        } else {
+            latex.push_str("\n");
+        }
+    }
+    latex.push_str("\\end{array}");
+    latex
+}
+
+// Poppler の注釈が、Margium が読み込んだ注釈と同じものか
+// /NM があれば名前で、無ければ /Rect で見分ける
+fn is_source_annot(source: &SourceAnnot, annot: &poppler::Annot) -> bool {
+    let drawable = matches!(
+        annot.annot_type(),
+        poppler::AnnotType::FreeText
+            | poppler::AnnotType::Highlight
+            | poppler::AnnotType::Underline
+            | poppler::AnnotType::StrikeOut
+            | poppler::AnnotType::Squiggly
+            | poppler::AnnotType::Ink
+            | poppler::AnnotType::Square
+            | poppler::AnnotType::Circle
+            | poppler::AnnotType::Line
+            | poppler::AnnotType::Polygon
+            | poppler::AnnotType::PolyLine
+            | poppler::AnnotType::Text
+    );
+    if !drawable {
+        return false;
+    }
+    if let Some(name) = &source.name {
+        return annot.name().is_some_and(|n| n.as_str() == name);
+    }
+    let Some((x1, y1, x2, y2)) = source.rect else { return false };
+    let rect = annot.rectangle();
+    let close = |a: f64, b: f64| (a - b).abs() < 0.01;
+    close(rect.x1().min(rect.x2()), x1)
+        && close(rect.y1().min(rect.y2()), y1)
+        && close(rect.x1().max(rect.x2()), x2)
+        && close(rect.y1().max(rect.y2()), y2)
+}
+
+// ページ内のリンクの行き先
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkTarget {
+    // 移動先のページ (0始まり) と、そこで画面の上端に合わせる位置 (PDF座標、上が 0。指定がなければ None)
+    Page(i32, Option<f64>),
+    // 外部の URI (ブラウザなどで開く)
+    Uri(String),
+}
+
+// リンクの行き先を読む (ページ移動と URI 以外のアクションは扱わない)
+// poppler-rs は PopplerAction を公開していないので、構造体を直接読む
+fn link_target(doc: &Document, mapping: &poppler::LinkMapping) -> Option<LinkTarget> {
+    let raw: *const poppler::ffi::PopplerLinkMapping = mapping.to_glib_none().0;
+    // SAFETY: raw は mapping が持っている有効な PopplerLinkMapping を指し、mapping はこの関数の間生きている
+    let action = unsafe { (*raw).action };
+    if action.is_null() {
+        return None;
+    }
+    // SAFETY: action は null でないことを確かめた、mapping が持っている PopplerAction
+    // PopplerAction は共用体なので、type_ を見てから対応する方のメンバーだけを読む
+    match unsafe { (*action).type_ } {
+        poppler::ffi::POPPLER_ACTION_GOTO_DEST => {
+            // SAFETY: type_ が GOTO_DEST なので goto_dest が有効なメンバー
+            let dest = unsafe { (*action).goto_dest.dest };
+            if dest.is_null() {
+                return None;
+            }
+            // SAFETY: dest は null でなく、action (つまり mapping) が持っているので、この呼び出しの間は有効
+            dest_target(doc, unsafe { &*dest })
+        }
+        poppler::ffi::POPPLER_ACTION_URI => {
+            // SAFETY: type_ が URI なので uri が有効なメンバー
+            let uri = unsafe { (*action).uri.uri };
+            if uri.is_null() {
+                return None;
+            }
+            // SAFETY: uri は null でなく、Poppler が持っている NUL 終端の文字列。すぐに String に写す
+            let uri = unsafe { std::ffi::CStr::from_ptr(uri) }.to_string_lossy().into_owned();
+            Some(LinkTarget::Uri(uri))
+        }
+        _ => None,
+    }
+}
+
+// ページ内のリンクをすべて読む
+fn page_links(doc: &Document, page_index: i32) -> Option<Vec<PageLink>> {
+    let page = doc.page(page_index)?;
+    let (_, page_height) = page.size();
+    let links = page.link_mapping().iter()
+        .map(|mapping| {
+            let raw: *const poppler::ffi::PopplerLinkMapping = mapping.to_glib_none().0;
+            // SAFETY: raw は mapping が持っている有効な PopplerLinkMapping を指し、mapping はこの間生きている
+            let area = unsafe { (*raw).area };
+            // リンクの範囲は左下が原点なので、上を 0 にして持つ
+            ((area.x1, page_height - area.y2, area.x2, page_height - area.y1), link_target(doc, mapping))
+        })
+        .collect();
+    Some(links)
+}
+
+// 行き先のページと上端の位置 (名前付きの行き先は文書の /Dests から引き直す)
+fn dest_target(doc: &Document, dest: &poppler::ffi::PopplerDest) -> Option<LinkTarget> {
+    if dest.type_ == poppler::ffi::POPPLER_DEST_NAMED {
+        if dest.named_dest.is_null() {
+            return None;
+        }
+        // SAFETY: named_dest は null でないことを確かめた、dest が持っている NUL 終端の文字列。すぐに String に写す
+        let name = unsafe { std::ffi::CStr::from_ptr(dest.named_dest) }.to_string_lossy().into_owned();
+        let found = doc.find_dest(&name)?;
+        let raw: *const poppler::ffi::PopplerDest = found.to_glib_none().0;
+        // SAFETY: raw は found が持っている有効な PopplerDest を指し、found はこの関数の間生きている
+        let resolved = unsafe { &*raw };
+        if resolved.type_ == poppler::ffi::POPPLER_DEST_NAMED {
+            return None;
+        }
+        return dest_target(doc, resolved);
+    }
+
+    // page_num は 1 始まり
+    let page = dest.page_num - 1;
+    let (_, page_height) = doc.page(page)?.size();
+    // 上端を指定できる種類だけ位置を使う (/XYZ で上端が null のときは 0 が入る)
+    let top = match dest.type_ {
+        poppler::ffi::POPPLER_DEST_XYZ
+        | poppler::ffi::POPPLER_DEST_FITH
+        | poppler::ffi::POPPLER_DEST_FITBH
+        | poppler::ffi::POPPLER_DEST_FITR if dest.top > 0.0 => Some((page_height - dest.top).max(0.0)),
+        _ => None,
+    };
+    Some(LinkTarget::Page(page, top))
+}
+
+// FreeText の枠の大きさを変えるつまみ (選択枠の右辺・下辺・右下の角)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeHandle {
+    Right,
+    Bottom,
+    BottomRight,
+}
+
+// つまみの中心 (選択枠は描画範囲の 2.0 外側に描いている)
+fn resize_handle_points(x: f64, y: f64, w: f64, h: f64) -> [(ResizeHandle, (f64, f64)); 3] {
+    [
+        (ResizeHandle::Right, (x + w + 2.0, y + h / 2.0)),
+        (ResizeHandle::Bottom, (x + w / 2.0, y + h + 2.0)),
+        (ResizeHandle::BottomRight, (x + w + 2.0, y + h + 2.0)),
+    ]
+}
+
+fn draw_resize_handles(context: &Context, x: f64, y: f64, w: f64, h: f64) {
+    context.save().unwrap();
+    context.set_line_width(1.0);
+    for (_, (hx, hy)) in resize_handle_points(x, y, w, h) {
+        context.rectangle(hx - RESIZE_HANDLE_SIZE / 2.0, hy - RESIZE_HANDLE_SIZE / 2.0, RESIZE_HANDLE_SIZE, RESIZE_HANDLE_SIZE);
+        context.set_source_rgb(1.0, 1.0, 1.0);
+        context.fill_preserve().unwrap();
+        context.set_source_rgb(0.0, 0.5, 1.0);
+        context.stroke().unwrap();
+    }
+    context.restore().unwrap();
+}
+
+// 選択中のアノテーションを示す青い破線の枠
+fn draw_selection_frame(context: &Context, x: f64, y: f64, w: f64, h: f64) {
+    context.save().unwrap();
+    context.set_source_rgb(0.0, 0.5, 1.0);
+    context.set_line_width(2.0);
+    context.set_dash(&[4.0, 4.0], 0.0);
+    context.rectangle(x - 2.0, y - 2.0, w + 4.0, h + 4.0);
+    context.stroke().unwrap();
+    context.restore().unwrap();
+}
+
+// 手書きの線をページ座標で描画する
+// 点と点の中点を通る2次曲線でつなぎ、区間ごとに筆圧に応じた太さで描く
+fn paint_ink(context: &Context, strokes: &[Vec<(f64, f64, f64)>], width: f64, color: (f64, f64, f64)) {
+    let (r, g, b) = color;
+    context.set_source_rgb(r, g, b);
+    context.set_line_cap(cairo::LineCap::Round);
+    context.set_line_join(cairo::LineJoin::Round);
+
+    for stroke in strokes {
+        let Some(&(fx, fy, fp)) = stroke.first() else { continue };
+        if stroke.len() == 1 {
+            context.arc(fx, fy, annotations::ink_line_width(width, fp) / 2.0, 0.0, std::f64::consts::TAU);
+            context.fill().unwrap();
+            continue;
+        }
+
+        let mut start = (fx, fy);
+        for i in 1..stroke.len() {
+            let (cx, cy, pressure) = stroke[i];
+            let end = match stroke.get(i + 1) {
+                Some(&(nx, ny, _)) => ((cx + nx) / 2.0, (cy + ny) / 2.0),
+                None => (cx, cy),
+            };
+            // 2次ベジェ (制御点 = 元の点) を3次ベジェに直して描く
+            context.move_to(start.0, start.1);
+            context.curve_to(
+                start.0 + (cx - start.0) * 2.0 / 3.0,
+                start.1 + (cy - start.1) * 2.0 / 3.0,
+                end.0 + (cx - end.0) * 2.0 / 3.0,
+                end.1 + (cy - end.1) * 2.0 / 3.0,
+                end.0,
+                end.1,
+            );
+            context.set_line_width(annotations::ink_line_width(width, pressure));
+            context.stroke().unwrap();
+            start = end;
+        }
+    }
+}
+
+// 注釈を平行移動する (手書き・図形は点列ごと動かす)
+fn translate_annotation(ann: &mut AnnotationData, dx: f64, dy: f64) {
+    match &mut ann.kind {
+        AnnotationKind::Ink { strokes, .. } => {
+            for point in strokes.iter_mut().flatten() {
+                point.0 += dx;
+                point.1 += dy;
+            }
+        }
+        AnnotationKind::Shape { points, .. } => {
+            for point in points.iter_mut() {
+                point.0 += dx;
+                point.1 += dy;
+            }
+        }
+        AnnotationKind::Markup { rects, .. } => {
+            for rect in rects.iter_mut() {
+                rect.0 += dx;
+                rect.1 += dy;
+            }
+        }
+        AnnotationKind::Note { popup, .. } => {
+            popup.0 += dx;
+            popup.1 += dy;
+        }
+        AnnotationKind::FreeText => {}
+    }
+    ann.x += dx;
+    ann.y += dy;
+    ann.bounds.set(None);
+}
+
+// 点 (px, py) から線分 a-b までの距離
+fn distance_to_segment(px: f64, py: f64, a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 > 0.0 { (((px - a.0) * dx + (py - a.1) * dy) / len2).clamp(0.0, 1.0) } else { 0.0 };
+    (px - (a.0 + dx * t)).hypot(py - (a.1 + dy * t))
+}
+
+// 手書きの線が (x, y) から reach 以内を通るか
+// 速く書いた線は点がまばらなので、点ではなく点と点を結ぶ線分までの距離で判定する
+fn stroke_within(stroke: &[(f64, f64, f64)], x: f64, y: f64, reach: f64) -> bool {
+    match stroke {
+        [(px, py, _)] => (px - x).hypot(py - y) <= reach,
+        points => points.windows(2).any(|w| distance_to_segment(x, y, (w[0].0, w[0].1), (w[1].0, w[1].1)) <= reach),
+    }
+}
+
+// 図形の当たり判定 (tolerance は線からの許容距離)
+fn shape_hit(kind: &AnnotationKind, x: f64, y: f64, tolerance: f64) -> bool {
+    let AnnotationKind::Shape { shape, points, width, fill, .. } = kind else { return false };
+    let reach = width / 2.0 + tolerance;
+
+    match shape {
+        ShapeKind::Square | ShapeKind::Circle if fill.is_some() => {
+            annotations::bounding_rect(&points.iter().map(|&(px, py)| (px, py, 0.0, 0.0)).collect::<Vec<_>>())
+                .is_some_and(|(bx, by, bw, bh)| x >= bx && x <= bx + bw && y >= by && y <= by + bh)
+        }
+        ShapeKind::Square => {
+            let [(x0, y0), (x1, y1)] = [points[0], points[points.len() - 1]];
+            let corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)];
+            corners.windows(2).any(|w| distance_to_segment(x, y, w[0], w[1]) <= reach)
+        }
+        ShapeKind::Circle => {
+            let [(x0, y0), (x1, y1)] = [points[0], points[points.len() - 1]];
+            let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+            let (rx, ry) = (((x1 - x0) / 2.0).abs().max(0.1), ((y1 - y0) / 2.0).abs().max(0.1));
+            let r = ((x - cx) / rx).hypot((y - cy) / ry);
+            (r - 1.0).abs() * rx.min(ry) <= reach
+        }
+        ShapeKind::Line | ShapeKind::PolyLine | ShapeKind::Polygon => {
+            let mut path = points.clone();
+            if *shape == ShapeKind::Polygon {
+                if let Some(&first) = points.first() {
+                    path.push(first);
+                }
+            }
+            path.windows(2).any(|w| distance_to_segment(x, y, w[0], w[1]) <= reach)
+        }
+    }
+}
+
+// 線の端の飾り (/LE) を描く。tip が端の点、from は線の内側にある隣の点
+fn paint_line_ending(context: &Context, ending: LineEnding, tip: (f64, f64), from: (f64, f64), width: f64) {
+    let len = (tip.0 - from.0).hypot(tip.1 - from.1);
+    if len == 0.0 || ending == LineEnding::None {
+        return;
+    }
+    // d: 外向きの単位ベクトル, n: それに垂直な単位ベクトル
+    let d = ((tip.0 - from.0) / len, (tip.1 - from.1) / len);
+    let n = (-d.1, d.0);
+    let size = annotations::line_ending_size(width);
+    let at = |along: f64, across: f64| (tip.0 + d.0 * along + n.0 * across, tip.1 + d.1 * along + n.1 * across);
+
+    let polyline = |pts: &[(f64, f64)], close: bool| {
+        context.move_to(pts[0].0, pts[0].1);
+        for p in &pts[1..] {
+            context.line_to(p.0, p.1);
+        }
+        if close {
+            context.close_path();
+        }
+    };
+
+    match ending {
+        LineEnding::None => {}
+        LineEnding::OpenArrow => polyline(&[at(-size, size / 2.0), tip, at(-size, -size / 2.0)], false),
+        LineEnding::ClosedArrow => polyline(&[at(-size, size / 2.0), tip, at(-size, -size / 2.0)], true),
+        LineEnding::ROpenArrow => polyline(&[at(size, size / 2.0), tip, at(size, -size / 2.0)], false),
+        LineEnding::RClosedArrow => polyline(&[at(size, size / 2.0), tip, at(size, -size / 2.0)], true),
+        LineEnding::Butt => polyline(&[at(0.0, size / 2.0), at(0.0, -size / 2.0)], false),
+        LineEnding::Slash => polyline(&[at(size * 0.25, size / 2.0), at(-size * 0.25, -size / 2.0)], false),
+        LineEnding::Square => {
+            let h = size * 0.3;
+            polyline(&[at(-h, -h), at(h, -h), at(h, h), at(-h, h)], true);
+        }
+        LineEnding::Diamond => {
+            let h = size * 0.4;
+            polyline(&[at(-h, 0.0), at(0.0, -h), at(h, 0.0), at(0.0, h)], true);
+        }
+        LineEnding::Circle => {
+            context.new_sub_path();
+            context.arc(tip.0, tip.1, size * 0.3, 0.0, std::f64::consts::TAU);
+        }
+    }
+    context.stroke().unwrap();
+}
+
+// 図形をページ座標で描画する
+// 画面描画と、保存時の外観ストリーム(/AP)生成の両方で使う
+fn paint_shape(context: &Context, kind: &AnnotationKind, color: (f64, f64, f64)) {
+    let AnnotationKind::Shape { shape, points, width, fill, opacity, line_endings } = kind else { return };
+    let (Some(&first), Some(&last)) = (points.first(), points.last()) else { return };
+
+    // 線と塗りの重なりが濃くならないよう、まとめて描いてから透明度をかける
+    context.push_group();
+    context.set_line_width(*width);
+    context.set_line_cap(cairo::LineCap::Round);
+    context.set_line_join(cairo::LineJoin::Round);
+
+    match shape {
+        ShapeKind::Square => {
+            context.rectangle(first.0.min(last.0), first.1.min(last.1), (last.0 - first.0).abs(), (last.1 - first.1).abs());
+        }
+        ShapeKind::Circle => {
+            let (rx, ry) = ((last.0 - first.0).abs() / 2.0, (last.1 - first.1).abs() / 2.0);
+            if rx > 0.0 && ry > 0.0 {
+                context.save().unwrap();
+                context.translate((first.0 + last.0) / 2.0, (first.1 + last.1) / 2.0);
+                context.scale(rx, ry);
+                context.arc(0.0, 0.0, 1.0, 0.0, std::f64::consts::TAU);
+                context.restore().unwrap();
+            }
+        }
+        ShapeKind::Line | ShapeKind::Polygon | ShapeKind::PolyLine => {
+            context.move_to(first.0, first.1);
+            for p in &points[1..] {
+                context.line_to(p.0, p.1);
+            }
+            if *shape == ShapeKind::Polygon {
+                context.close_path();
+            }
+        }
+    }
+
+    if let (Some((r, g, b)), true) = (fill, shape.is_closed()) {
+        context.set_source_rgb(*r, *g, *b);
+        context.fill_preserve().unwrap();
+    }
+    let (r, g, b) = color;
+    context.set_source_rgb(r, g, b);
+    context.stroke().unwrap();
+
+    if points.len() >= 2 && !shape.is_closed() {
+        paint_line_ending(context, line_endings.0, first, points[1], *width);
+        paint_line_ending(context, line_endings.1, last, points[points.len() - 2], *width);
+    }
+
+    context.pop_group_to_source().unwrap();
+    context.paint_with_alpha(*opacity).unwrap();
+}
+
+// テキストマークアップをページ座標で描画する
+// 画面描画と、保存時の外観ストリーム(/AP)生成の両方で使う
+fn paint_markup(context: &Context, style: MarkupStyle, rects: &[(f64, f64, f64, f64)], color: (f64, f64, f64)) {
+    let (r, g, b) = color;
+    context.set_source_rgb(r, g, b);
+
+    for &(x, y, w, h) in rects {
+        // 線の太さは文字の高さに合わせる
+        let thickness = (h * 0.07).max(0.5);
+        context.set_line_width(thickness);
+
+        match style {
+            MarkupStyle::Highlight => {
+                // 乗算で重ねて、下の文字が隠れないようにする
+                context.set_operator(cairo::Operator::Multiply);
+                context.rectangle(x, y, w, h);
+                context.fill().unwrap();
+                context.set_operator(cairo::Operator::Over);
+            }
+            MarkupStyle::Underline => {
+                let line_y = y + h - thickness;
+                context.move_to(x, line_y);
+                context.line_to(x + w, line_y);
+                context.stroke().unwrap();
+            }
+            MarkupStyle::StrikeOut => {
+                let line_y = y + h * 0.55;
+                context.move_to(x, line_y);
+                context.line_to(x + w, line_y);
+                context.stroke().unwrap();
+            }
+            MarkupStyle::Squiggly => {
+                // 下端に沿ったジグザグ線
+                let amplitude = thickness * 1.5;
+                let step = (h / 6.0).max(1.0);
+                let base_y = y + h - thickness;
+                context.move_to(x, base_y);
+                let mut cx = x;
+                let mut up = true;
+                while cx < x + w {
+                    cx = (cx + step).min(x + w);
+                    context.line_to(cx, if up { base_y - amplitude } else { base_y });
+                    up = !up;
+                }
+                context.stroke().unwrap();
+            }
+        }
+    }
+}
+
+// 付箋のアイコンを (x, y) を左上にして描く
+// アイコンの種類 (/Name) は保存時に引き継ぐが、画面ではどれも同じ付箋の絵で描く
+fn paint_note_icon(context: &Context, x: f64, y: f64, color: (f64, f64, f64)) {
+    let s = NOTE_ICON_SIZE;
+    let fold = s * 0.3;
+    let (r, g, b) = color;
+    context.set_line_width(1.0);
+    context.move_to(x + 0.5, y + 0.5);
+    context.line_to(x + s - fold, y + 0.5);
+    context.line_to(x + s - 0.5, y + fold);
+    context.line_to(x + s - 0.5, y + s - 0.5);
+    context.line_to(x + 0.5, y + s - 0.5);
+    context.close_path();
+    context.set_source_rgb(r, g, b);
+    context.fill_preserve().unwrap();
+    context.set_source_rgb(0.3, 0.3, 0.3);
+    context.stroke().unwrap();
+
+    // 右上の折り返しと、本文の行
+    context.move_to(x + s - fold, y + 0.5);
+    context.line_to(x + s - fold, y + fold);
+    context.line_to(x + s - 0.5, y + fold);
+    for i in 0..3 {
+        let line_y = y + s * (0.45 + 0.17 * i as f64);
+        context.move_to(x + s * 0.2, line_y);
+        context.line_to(x + s * 0.8, line_y);
+    }
+    context.stroke().unwrap();
+}
+
+// 開いている付箋のポップアップ (作成者の見出しと、枠に合わせて折り返した本文)
+fn paint_note_popup(context: &Context, ann: &AnnotationData, (x, y, w, h): (f64, f64, f64, f64), color: (f64, f64, f64)) {
+    let (r, g, b) = color;
+    let header_h = NOTE_POPUP_FONT_SIZE * 1.8;
+
+    context.set_source_rgba(0.0, 0.0, 0.0, 0.3);
+    context.rectangle(x + 2.0, y + 2.0, w, h);
+    context.fill().unwrap();
+    // 本文の背景は付箋の色を薄くしたもの
+    context.set_source_rgb(1.0 - (1.0 - r) * 0.25, 1.0 - (1.0 - g) * 0.25, 1.0 - (1.0 - b) * 0.25);
+    context.rectangle(x, y, w, h);
+    context.fill().unwrap();
+    context.set_source_rgb(r, g, b);
+    context.rectangle(x, y, w, header_h);
+    context.fill().unwrap();
+    context.set_source_rgb(0.3, 0.3, 0.3);
+    context.set_line_width(1.0);
+    context.rectangle(x, y, w, h);
+    context.stroke().unwrap();
+
+    context.save().unwrap();
+    context.rectangle(x, y, w, h);
+    context.clip();
+    context.set_source_rgb(0.0, 0.0, 0.0);
+    context.set_font_size(NOTE_POPUP_FONT_SIZE);
+    context.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Bold);
+    context.move_to(x + ANNOTATION_PADDING, y + header_h * 0.7);
+    context.show_text(ann.author.as_deref().unwrap_or("Note")).unwrap();
+
+    context.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+    let text = wrap_text(context, &ann.content, FontFamily::Sans, NOTE_POPUP_FONT_SIZE, (w - ANNOTATION_PADDING * 2.0).max(NOTE_POPUP_FONT_SIZE));
+    for (i, line) in text.split('\n').enumerate() {
+        context.move_to(x + ANNOTATION_PADDING, y + header_h + NOTE_POPUP_FONT_SIZE * 1.4 * (i + 1) as f64);
+        context.show_text(line).unwrap();
+    }
+    context.restore().unwrap();
+}
+
+// テキストを MathJax で SVG に変換し、メモリ上で読み込む
+// MathJax の SVG は currentColor で描かれるので、文字色に置き換えておく
+fn render_math_svg(content: &str, style: &TextStyle) -> Option<CachedSvg> {
+    let svg_string = convert_to_svg(&annotation_latex(content, style.font_family)).ok()?;
+    let (r, g, b) = style.text_color;
+    let color = format!("rgb({},{},{})", (r * 255.0).round(), (g * 255.0).round(), (b * 255.0).round());
+    let svg_string = svg_string.replace("currentColor", &color);
+    let bytes = glib::Bytes::from(svg_string.as_bytes());
+    let stream = gio::MemoryInputStream::from_bytes(&bytes);
+    let handle = Loader::new()
+        .read_stream(&stream, None::<&gio::File>, None::<&gio::Cancellable>)
+        .ok()?;
+
+    let rect = CairoRenderer::new(&handle).intrinsic_dimensions();
+    Some(CachedSvg {
+        width: rect.width.length,
+        height: rect.height.length,
+        lines: content.split('\n').count(),
+        handle,
+    })
 }
 
 enum DrawPart {
@@ -41,62 +971,245 @@ impl PdfEngine {
         Self {
             doc: None,
             lo_doc: None,
+            generation: 0,
             filename: String::new(),
             current_page: 0,
             total_pages: 0,
+            page_sizes: Vec::new(),
             filepath: None,
+            view_mode: ViewMode::SinglePage,
             annotations: Vec::new(),
-            highlight_rects: Vec::new(),
             search_results_cache: HashMap::new(),
             active_annotation_id: None,
+            text_selection: Vec::new(),
+            selected_text: String::new(),
+            ink_stroke: None,
+            last_ink_stroke: None,
+            shape_draft: None,
+            svg_cache: RefCell::new(HashMap::new()),
+            measure_context: cairo::ImageSurface::create(cairo::Format::ARgb32, 1, 1).ok()
+                .and_then(|surface| Context::new(&surface).ok()),
+            source_annots: Vec::new(),
+            hidden_pages: RefCell::new(HashSet::new()),
+            link_cache: RefCell::new(None),
+            dirty: false,
+            title_listener: None,
+            incremental_save: false,
+            signed_save_notified: false,
+            edited_ids: HashSet::new(),
+            author: load_author(),
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            saved_undo_len: Some(0),
+            pending_edit: None,
+            back_history: VecDeque::new(),
+            forward_history: Vec::new(),
+            outline: Vec::new(),
+            outline_edited: false,
+            outline_suggested: false,
         }
     }
 
     pub fn jump_to_page(&mut self, page_index: i32) -> bool {
         if page_index >= 0 && page_index < self.total_pages {
+            // 作成途中の多角形は、ページを離れる前に確定しておく
+            self.finish_shape_draft();
             self.current_page = page_index;
-            self.update_highlights_for_current_page();
+            self.clear_text_selection();
+            self.link_cache.borrow_mut().take();
             return true;
         }
         false
     }
 
+    // 別のページへ移動し、移動前の表示位置を「戻る」の履歴に残す
+    // (scroll と scale は今の表示。同じページへの移動は履歴に残さない)
+    pub fn navigate_to(&mut self, page_index: i32, scroll: f64, scale: f64) -> bool {
+        let from = ViewPosition { page: self.current_page, scroll, scale };
+        if page_index == self.current_page || !self.jump_to_page(page_index) {
+            return false;
+        }
+        self.back_history.push_back(from);
+        if self.back_history.len() > HISTORY_LIMIT {
+            self.back_history.pop_front();
+        }
+        self.forward_history.clear();
+        true
+    }
+
+    // 直前の移動の前に戻る (戻した表示位置を返すので、UI側でズームとスクロールを合わせる)
+    pub fn go_back(&mut self, scroll: f64, scale: f64) -> Option<ViewPosition> {
+        let to = self.back_history.pop_back()?;
+        self.forward_history.push(ViewPosition { page: self.current_page, scroll, scale });
+        self.jump_to_page(to.page);
+        Some(to)
+    }
+
+    // 戻る前の位置へ進む
+    pub fn go_forward(&mut self, scroll: f64, scale: f64) -> Option<ViewPosition> {
+        let to = self.forward_history.pop()?;
+        self.back_history.push_back(ViewPosition { page: self.current_page, scroll, scale });
+        self.jump_to_page(to.page);
+        Some(to)
+    }
+
     pub fn load_file(&mut self, path: PathBuf) -> Result<(), String> {
         let uri = format!("file://{}", path.to_str().unwrap_or(""));
         
         match Document::from_file(&uri, None) {
             Ok(doc) => {
                 self.total_pages = doc.n_pages();
+                self.page_sizes = (0..self.total_pages)
+                    .map(|i| doc.page(i).map_or((0.0, 0.0), |page| page.size()))
+                    .collect();
                 self.filename = path.file_name().unwrap_or_default().to_string_lossy().to_string();
                 self.current_page = 0;
                 self.doc = Some(doc);
+                self.generation += 1;
                 self.filepath = Some(path);
+                // 前のファイルのアノテーションを持ち越さない
+                self.annotations.clear();
+                self.source_annots.clear();
+                self.hidden_pages.borrow_mut().clear();
+                self.link_cache.borrow_mut().take();
+                self.active_annotation_id = None;
+                self.clear_text_selection();
+                self.ink_stroke = None;
+                self.shape_draft = None;
+                self.svg_cache.borrow_mut().clear();
+                self.clear_edit_history();
+                self.back_history.clear();
+                self.forward_history.clear();
+                self.outline.clear();
+                self.outline_edited = false;
+                self.outline_suggested = false;
+                self.signed_save_notified = false;
+                self.dirty = false;
+                self.notify_title();
                 Ok(())
             }
             Err(e) => Err(format!("PDF Error: {}", e)),
         }
     }
 
-    pub fn set_annotations(&mut self, annots: Vec<AnnotationData>) {
-        self.annotations = annots;
+    pub fn set_annotations(&mut self, loaded: LoadedAnnotations) {
+        self.annotations = loaded.annotations;
+        // 読み込む前に描いたページも、次に描くときに外し直す
+        self.source_annots = loaded.sources;
+        self.hidden_pages.borrow_mut().clear();
+        self.edited_ids.clear();
+        self.clear_edit_history();
+        self.set_dirty(false);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // 未保存かどうかが変わったときだけ、タイトルを更新する
+    fn set_dirty(&mut self, dirty: bool) {
+        if self.dirty != dirty {
+            self.dirty = dirty;
+            self.notify_title();
+        }
+    }
+
+    // ウィンドウタイトルが変わったら呼ばれる関数を登録する
+    pub fn connect_title_changed(&mut self, listener: impl Fn(&str) + 'static) {
+        self.title_listener = Some(Box::new(listener));
+        self.notify_title();
+    }
+
+    fn notify_title(&self) {
+        if let Some(listener) = &self.title_listener {
+            listener(&self.window_title());
+        }
+    }
+
+    // 現在のファイルに上書き保存
+    // 署名済みのため追記で保存したことを、このファイルで初めて知らせるときは true を返す
+    pub fn save(&mut self) -> Result<bool, String> {
+        let path = self.filepath.clone().ok_or("No file is open")?;
+        self.save_as(path)
+    }
+
+    // 別名で保存 (保存後はそのファイルを開いている扱いにする)
+    pub fn save_as(&mut self, dest: PathBuf) -> Result<bool, String> {
+        let src = self.filepath.clone().ok_or("No file is open")?;
+
+        // 他のビューアでも同じ見た目になるよう、外観ストリームを生成して一緒に保存
+        // 作り直すのは新しく作った注釈と変更した注釈だけ (それ以外は前回の外観を使う)
+        let appearances: HashMap<String, AppearanceStream> = self.annotations.iter()
+            .filter(|ann| ann.object_id.is_none() || self.edited_ids.contains(&ann.id))
+            .filter_map(|ann| self.render_appearance(ann).map(|ap| (ann.id.clone(), ap)))
+            .collect();
+
+        let (saved, forced_incremental) = annotations::save_pdf_with_annotations(
+            src.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+            self.annotations.clone(),
+            &appearances,
+            self.outline_edited.then_some(self.outline.as_slice()),
+            self.incremental_save,
+        )?;
+
+        // 新規アノテーションに振られたオブジェクトIDを反映 (次回保存時に重複させない)
+        self.annotations = saved;
+        self.filename = dest.file_name().unwrap_or_default().to_string_lossy().to_string();
+        self.filepath = Some(dest);
+        self.outline_edited = false;
+        self.edited_ids.clear();
+        self.saved_undo_len = Some(self.undo_stack.len());
+        self.dirty = false;
+        // ファイル名も変わるので、未保存でなかったときもタイトルを更新する
+        self.notify_title();
+
+        let notify = forced_incremental && !self.signed_save_notified;
+        self.signed_save_notified |= forced_incremental;
+        Ok(notify)
     }
 
+    // 次のページへ (見開き表示では、次の見開きの左のページへ)
     pub fn next_page(&mut self) -> bool {
-        if self.current_page < self.total_pages - 1 {
-            self.jump_to_page(self.current_page + 1);
+        let next = self.spread(self.current_page).end;
+        if next < self.total_pages {
+            self.jump_to_page(next);
             return true;
         }
         false
     }
 
+    // 前のページへ (見開き表示では、前の見開きの左のページへ)
     pub fn prev_page(&mut self) -> bool {
-        if self.current_page > 0 {
-            self.jump_to_page(self.current_page - 1);
+        let first = self.spread(self.current_page).start;
+        if first > 0 {
+            self.jump_to_page(self.spread(first - 1).start);
             return true;
         }
         false
     }
 
+    // page_index を含む見開きのページ (見開き表示でなければそのページだけ)
+    fn spread(&self, page_index: i32) -> std::ops::Range<i32> {
+        let first = match self.view_mode {
+            ViewMode::SinglePage | ViewMode::Continuous => return page_index..page_index + 1,
+            ViewMode::TwoPage => page_index - page_index % 2,
+            // 表紙は1枚だけ
+            ViewMode::Book if page_index == 0 => return 0..1,
+            ViewMode::Book => page_index - (page_index - 1) % 2,
+        };
+        first..(first + 2).min(self.total_pages)
+    }
+
+    // ウィンドウタイトル (未保存なら先頭に "*")
+    pub fn window_title(&self) -> String {
+        if self.filename.is_empty() {
+            return "Margium".to_string();
+        }
+        let marker = if self.dirty { "*" } else { "" };
+        format!("{}{} - Margium", marker, self.filename)
+    }
+
     pub fn status_text(&self) -> String {
         if self.total_pages > 0 {
             format!("{}", self.filename)
@@ -174,115 +1287,460 @@ impl PdfEngine {
             font_size: Some(14.0),
             id: new_id.clone(),
             object_id: None,
+            kind: AnnotationKind::FreeText,
+            color: None,
+            width: None,
+            height: None,
+            text_style: TextStyle::default(),
+            author: None,
+            created: None,
+            modified: None,
+            replies: Vec::new(),
+            bounds: Cell::new(None),
         });
+        self.record_last_added();
 
         self.active_annotation_id = Some(new_id.clone());
+        self.set_dirty(true);
         Ok(())
     }
 
-    
+    // 付箋を (x, y) に置く。本文が見えるようにポップアップを開いておく
+    pub fn add_note(&mut self, text: &str, x: f64, y: f64) {
+        let new_id = uuid::Uuid::new_v4().to_string();
+        self.annotations.push(AnnotationData {
+            page: (self.current_page + 1) as u32,
+            x,
+            y,
+            content: text.to_string(),
+            font_size: None,
+            id: new_id.clone(),
+            object_id: None,
+            kind: AnnotationKind::Note { icon: NoteIcon::Comment, open: true, popup: annotations::default_note_popup(x, y) },
+            color: Some(NOTE_COLOR),
+            width: None,
+            height: None,
+            text_style: TextStyle::default(),
+            author: None,
+            created: None,
+            modified: None,
+            replies: Vec::new(),
+            bounds: Cell::new(None),
+        });
+        self.record_last_added();
 
-    // 検索結果を丸ごと受け取るメソッド
-    pub fn set_all_search_results(&mut self, results: HashMap<i32, Vec<Rectangle>>) {
-        self.search_results_cache = results;
-        // 現在のページに結果があれば即反映
-        self.update_highlights_for_current_page();
+        self.active_annotation_id = Some(new_id);
+        self.set_dirty(true);
     }
 
-    // 検索クリア
-    pub fn clear_search_results(&mut self) {
-        self.search_results_cache.clear();
-        self.highlight_rects.clear();
-    }
-    
-    // ヘルパーメソッド: 現在のページに対応するハイライトをセット
-    fn update_highlights_for_current_page(&mut self) {
-        if let Some(rects) = self.search_results_cache.get(&self.current_page) {
-            self.highlight_rects = rects.clone();
-        } else {
-            self.highlight_rects.clear();
+    // (x, y) が付箋のアイコンの上なら、ポップアップを開閉する
+    // 開閉は見た目の切り替えなので、元に戻す履歴には残さず未保存の変更にも数えない (保存時には /Open に書かれる)
+    pub fn toggle_note_at(&mut self, x: f64, y: f64) -> bool {
+        let Some(id) = self.hit_test_annotation(x, y) else { return false };
+        let Some(ann) = self.annotations.iter_mut().find(|a| a.id == id) else { return false };
+        let on_icon = x >= ann.x - HIT_MARGIN && x <= ann.x + NOTE_ICON_SIZE + HIT_MARGIN
+            && y >= ann.y - HIT_MARGIN && y <= ann.y + NOTE_ICON_SIZE + HIT_MARGIN;
+        match &mut ann.kind {
+            AnnotationKind::Note { open, .. } if on_icon => {
+                *open = !*open;
+                true
+            }
+            _ => false,
         }
     }
 
-    // PDF描画処理 
-    pub fn draw(&self, context: &Context, area_width: f64, area_height: f64, scale: f64) {
-        // 1. 背景をダークグレーで塗りつぶす
-        context.set_source_rgb(0.2, 0.2, 0.2);
-        context.paint().expect("Painting failed");
-
-        if let Some(doc) = &self.doc {
-            if let Some(page) = doc.page(self.current_page) {
-                let (pdf_w, pdf_h) = page.size();
-                
-                // 2. 描画後のサイズを計算
-                let draw_w = pdf_w * scale;
-                let draw_h = pdf_h * scale;
-
-                // 3. 中央寄せのためのオフセット計算 (X軸)
-                // 画面幅の方が広い場合のみ中央に寄せる。画面の方が狭いなら左端(0)から。
-                let offset_x = if area_width > draw_w {
-                    (area_width - draw_w) / 2.0
-                } else {
-                    0.0
-                };
-                
-                // 上部は少し余白(20px)を空ける
-                let offset_y = 20.0;
-
-                // 4. 座標系を変換 (移動 -> 拡大)
-                context.save().unwrap(); // 状態保存
-                
-                context.translate(offset_x, offset_y);
+    // 現在のテキスト選択範囲からマークアップ (ハイライト等) を作成する
+    pub fn add_markup_annotation(&mut self, style: MarkupStyle) -> Result<(), String> {
+        let rects = std::mem::take(&mut self.text_selection);
+        let (x, y, _, _) = annotations::bounding_rect(&rects).ok_or("No text selected")?;
 
-                // PDFの影を描画 (オプション: ちょっと立体的に見える)
-                context.set_source_rgba(0.0, 0.0, 0.0, 0.5);
-                context.rectangle(5.0, 5.0, draw_w, draw_h);
-                context.fill().unwrap();
+        let new_id = uuid::Uuid::new_v4().to_string();
+        self.annotations.push(AnnotationData {
+            page: (self.current_page + 1) as u32,
+            x,
+            y,
+            // 選択した文字列をコメントとして残す (サイドバーの一覧に表示される)
+            content: std::mem::take(&mut self.selected_text).trim().to_string(),
+            font_size: None,
+            id: new_id.clone(),
+            object_id: None,
+            kind: AnnotationKind::Markup { style, rects },
+            color: Some(style.default_color()),
+            width: None,
+            height: None,
+            text_style: TextStyle::default(),
+            author: None,
+            created: None,
+            modified: None,
+            replies: Vec::new(),
+            bounds: Cell::new(None),
+        });
+        self.record_last_added();
 
-                // 用紙の白背景を描画
-                context.set_source_rgb(1.0, 1.0, 1.0);
-                context.rectangle(0.0, 0.0, draw_w, draw_h);
-                context.fill().unwrap();
+        self.active_annotation_id = Some(new_id);
+        self.set_dirty(true);
+        Ok(())
+    }
 
-                // 拡大適用
-                context.scale(scale, scale);
-                
-                // PDFの中身を描画
-                page.render(context);
-
-                // アノテーションを描画
-                self.draw_custom_annotations(context, scale);
-
-                // 検索ハイライトを描画
-                if !self.highlight_rects.is_empty() {
-                    context.save().unwrap();
-                    context.set_source_rgba(1.0, 0.0, 0.0, 0.5); 
-                    
-                    // ページの本来の高さを取得（これで反転計算する）
-                    let (_, page_h) = page.size();
-
-                    for rect in &self.highlight_rects {
-                        // 1. PDF座標系での「上端」と「下端」を整理
-                        // (Popplerの矩形は y1 < y2 とは限らないため念のため min/max を使う)
-                        let pdf_y_bottom = rect.y1().min(rect.y2());
-                        let pdf_y_top = rect.y1().max(rect.y2());
-                        
-                        // 2. Cairo座標系へ変換
-                        // Cairoでの描画開始位置(Y) = ページ高さ - PDFでの上端
-                        let cairo_y = page_h - pdf_y_top;
-                        
-                        // 高さはそのまま差分
-                        let height = pdf_y_top - pdf_y_bottom;
-                        let width = (rect.x2() - rect.x1()).abs();
-
-                        // 3. 描画
-                        context.rectangle(rect.x1(), cairo_y, width, height);
-                        context.fill().unwrap();
+    // (x1, y1) から (x2, y2) までのテキストを選択する (UI座標)
+    pub fn select_text(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) {
+        let Some(page) = self.doc.as_ref().and_then(|d| d.page(self.current_page)) else { return };
+
+        let mut area = Rectangle::new();
+        area.set_x1(x1);
+        area.set_y1(y1);
+        area.set_x2(x2);
+        area.set_y2(y2);
+
+        self.text_selection = page
+            .selected_region(SELECTION_SCALE, poppler::SelectionStyle::Glyph, &mut area)
+            .map(|region| {
+                (0..region.num_rectangles())
+                    .map(|i| {
+                        let r = region.rectangle(i);
+                        (
+                            r.x() as f64 / SELECTION_SCALE,
+                            r.y() as f64 / SELECTION_SCALE,
+                            r.width() as f64 / SELECTION_SCALE,
+                            r.height() as f64 / SELECTION_SCALE,
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.selected_text = page
+            .selected_text(poppler::SelectionStyle::Glyph, &mut area)
+            .map(|s| s.to_string())
+            .unwrap_or_default();
+    }
+
+    // 手書きの線を書き始める (UI座標、筆圧 0.0-1.0)
+    pub fn begin_ink_stroke(&mut self, x: f64, y: f64, pressure: f64, color: (f64, f64, f64), width: f64) {
+        self.ink_stroke = Some(InkStroke { points: vec![(x, y, pressure)], color, width });
+    }
+
+    pub fn extend_ink_stroke(&mut self, x: f64, y: f64, pressure: f64) {
+        let Some(stroke) = self.ink_stroke.as_mut() else { return };
+        if let Some(&(lx, ly, _)) = stroke.points.last() {
+            if (x - lx).hypot(y - ly) < INK_MIN_DISTANCE {
+                return;
+            }
+        }
+        stroke.points.push((x, y, pressure));
+    }
+
+    // ペンを離したとき。直前の手書きに続けて書いた線なら同じアノテーションにまとめる
+    pub fn finish_ink_stroke(&mut self) {
+        let Some(stroke) = self.ink_stroke.take() else { return };
+        let points = smooth_stroke(&stroke.points);
+        let page = (self.current_page + 1) as u32;
+
+        let merge_id = self.last_ink_stroke.as_ref()
+            .filter(|(_, at)| at.elapsed() < INK_MERGE_INTERVAL)
+            .map(|(id, _)| id.clone());
+        let merge_target = merge_id.and_then(|id| {
+            self.annotations.iter_mut().find(|a| a.id == id && a.page == page && a.color == Some(stroke.color))
+        });
+
+        if let Some(ann) = merge_target {
+            let before = ann.clone();
+            if let AnnotationKind::Ink { strokes, width } = &mut ann.kind {
+                if *width == stroke.width {
+                    strokes.push(points);
+                    if let Some((x, y, _, _)) = annotations::ink_bounds(strokes, *width) {
+                        ann.x = x;
+                        ann.y = y;
                     }
-                    context.restore().unwrap();
+                    ann.bounds.set(None);
+                    self.last_ink_stroke = Some((ann.id.clone(), Instant::now()));
+                    let after = ann.clone();
+                    self.record_edit(EditCommand::Modify { before, after });
+                    self.set_dirty(true);
+                    return;
+                }
+            }
+        }
+
+        let strokes = vec![points];
+        let (x, y, _, _) = annotations::ink_bounds(&strokes, stroke.width).unwrap_or_default();
+        let id = uuid::Uuid::new_v4().to_string();
+        self.annotations.push(AnnotationData {
+            page,
+            x,
+            y,
+            content: String::new(),
+            font_size: None,
+            id: id.clone(),
+            object_id: None,
+            kind: AnnotationKind::Ink { strokes, width: stroke.width },
+            color: Some(stroke.color),
+            width: None,
+            height: None,
+            text_style: TextStyle::default(),
+            author: None,
+            created: None,
+            modified: None,
+            replies: Vec::new(),
+            bounds: Cell::new(None),
+        });
+        self.record_last_added();
+
+        self.last_ink_stroke = Some((id, Instant::now()));
+        self.set_dirty(true);
+    }
+
+    // 図形の作成を始める。kind の points には押した位置を2つ入れておく (2つ目がドラッグで動く)
+    pub fn begin_shape(&mut self, kind: AnnotationKind, color: (f64, f64, f64)) {
+        self.shape_draft = Some(AnnotationData {
+            page: (self.current_page + 1) as u32,
+            x: 0.0,
+            y: 0.0,
+            content: String::new(),
+            font_size: None,
+            id: uuid::Uuid::new_v4().to_string(),
+            object_id: None,
+            kind,
+            color: Some(color),
+            width: None,
+            height: None,
+            text_style: TextStyle::default(),
+            author: None,
+            created: None,
+            modified: None,
+            replies: Vec::new(),
+            bounds: Cell::new(None),
+        });
+    }
+
+    pub fn has_shape_draft(&self) -> bool {
+        self.shape_draft.is_some()
+    }
+
+    // 作成中の図形の最後の点 (ドラッグ中の点) を動かす
+    pub fn update_shape_draft(&mut self, x: f64, y: f64) {
+        if let Some(AnnotationKind::Shape { points, .. }) = self.shape_draft.as_mut().map(|d| &mut d.kind) {
+            if let Some(last) = points.last_mut() {
+                *last = (x, y);
+            }
+        }
+    }
+
+    // ドラッグを終えたとき。多角形なら頂点を確定して次の頂点を置き始め、それ以外は図形を確定する
+    // 多角形は最初の頂点の近く (close_distance 以内) で離すと閉じて確定する
+    pub fn place_shape_point(&mut self, close_distance: f64) {
+        let Some(AnnotationKind::Shape { shape, points, .. }) = self.shape_draft.as_mut().map(|d| &mut d.kind) else { return };
+        if *shape != ShapeKind::PolyLine {
+            self.finish_shape_draft();
+            return;
+        }
+
+        let (first, last) = (points[0], points[points.len() - 1]);
+        if points.len() >= 4 && (last.0 - first.0).hypot(last.1 - first.1) <= close_distance {
+            points.pop();
+            *shape = ShapeKind::Polygon;
+            self.finish_shape_draft();
+        } else {
+            points.push(last);
+        }
+    }
+
+    // 作成中の図形を確定してアノテーションに加える (小さすぎるものは捨てる)
+    pub fn finish_shape_draft(&mut self) {
+        let Some(mut draft) = self.shape_draft.take() else { return };
+        let AnnotationKind::Shape { shape, points, width, line_endings, .. } = &mut draft.kind else { return };
+
+        // 多角形のダブルクリックなどで重なった頂点を除く
+        points.dedup_by(|a, b| (a.0 - b.0).hypot(a.1 - b.1) < 1.0);
+        if let ShapeKind::Square | ShapeKind::Circle = shape {
+            // 対角の2点を左上・右下にそろえる
+            let (a, b) = (points[0], points[points.len() - 1]);
+            *points = vec![(a.0.min(b.0), a.1.min(b.1)), (a.0.max(b.0), a.1.max(b.1))];
+            if points[1].0 - points[0].0 < 2.0 || points[1].1 - points[0].1 < 2.0 {
+                return;
+            }
+        }
+        if points.len() < 2 {
+            return;
+        }
+
+        let Some((x, y, _, _)) = annotations::shape_bounds(points, *width, *line_endings) else { return };
+        draft.x = x;
+        draft.y = y;
+        self.active_annotation_id = Some(draft.id.clone());
+        self.annotations.push(draft);
+        self.record_last_added();
+        self.set_dirty(true);
+    }
+
+    // 消しゴム: (x, y) から radius 以内を通る手書きの線を消す。線が無くなったアノテーションは削除
+    pub fn erase_ink_at(&mut self, x: f64, y: f64, radius: f64) -> bool {
+        let page = (self.current_page + 1) as u32;
+        let mut edits = Vec::new();
+
+        for (index, ann) in self.annotations.iter_mut().enumerate().filter(|(_, a)| a.page == page) {
+            let AnnotationKind::Ink { strokes, width } = &ann.kind else { continue };
+            let reach = radius + annotations::ink_line_width(*width, 1.0) / 2.0;
+            let touches = |stroke: &Vec<(f64, f64, f64)>| stroke_within(stroke, x, y, reach);
+            if !strokes.iter().any(touches) {
+                continue;
+            }
+
+            let before = ann.clone();
+            let AnnotationKind::Ink { strokes, width } = &mut ann.kind else { continue };
+            strokes.retain(|stroke| !touches(stroke));
+            if strokes.is_empty() {
+                edits.push(EditCommand::Remove { index, ann: before });
+            } else {
+                ann.bounds.set(None);
+                if let Some((bx, by, _, _)) = annotations::ink_bounds(strokes, *width) {
+                    ann.x = bx;
+                    ann.y = by;
+                }
+                edits.push(EditCommand::Modify { before, after: ann.clone() });
+            }
+        }
+
+        if edits.is_empty() {
+            return false;
+        }
+        self.annotations.retain(|a| !matches!(&a.kind, AnnotationKind::Ink { strokes, .. } if strokes.is_empty()));
+        if let Some(id) = &self.active_annotation_id {
+            if !self.annotations.iter().any(|a| &a.id == id) {
+                self.active_annotation_id = None;
+            }
+        }
+        // 後ろの注釈から消したことにすれば、元に戻すときに前から順に元の位置へ挿し戻せる
+        for edit in edits.into_iter().rev() {
+            self.record_edit(edit);
+        }
+        self.set_dirty(true);
+        true
+    }
+
+    pub fn clear_text_selection(&mut self) {
+        self.text_selection.clear();
+        self.selected_text.clear();
+    }
+
+    pub fn has_text_selection(&self) -> bool {
+        !self.text_selection.is_empty()
+    }
+
+    
+
+    // 検索結果を丸ごと受け取るメソッド
+    // (描画のときにページごとに引くので、表示中のページに結果があれば即反映される)
+    pub fn set_all_search_results(&mut self, results: HashMap<i32, Vec<Rectangle>>) {
+        self.search_results_cache = results;
+    }
+
+    // 検索クリア
+    pub fn clear_search_results(&mut self) {
+        self.search_results_cache.clear();
+    }
+
+    // --- ページの配置 ---
+
+    // 表示するページの配置 (area_width は中央寄せに使う)
+    pub fn page_slots(&self, area_width: f64, scale: f64) -> Vec<PageSlot> {
+        let page_size = |page: i32| {
+            let &(pdf_w, pdf_h) = self.page_sizes.get(page as usize)?;
+            Some((pdf_w * scale, pdf_h * scale))
+        };
+        // 画面幅の方が広い場合のみ中央に寄せる。画面の方が狭いなら左端(0)から。
+        let centered = |width: f64| if area_width > width { (area_width - width) / 2.0 } else { 0.0 };
+
+        let mut slots = Vec::new();
+        match self.view_mode {
+            // 縦に並べる
+            ViewMode::SinglePage | ViewMode::Continuous => {
+                let pages = if self.view_mode == ViewMode::Continuous {
+                    0..self.total_pages
+                } else {
+                    self.current_page..self.current_page + 1
+                };
+                let mut y = PAGE_MARGIN;
+                for page in pages {
+                    let Some((width, height)) = page_size(page) else { continue };
+                    slots.push(PageSlot { page, x: centered(width), y, width, height });
+                    y += height + PAGE_GAP;
+                }
+            }
+            // 見開きは横に並べて、上端を揃える
+            ViewMode::TwoPage | ViewMode::Book => {
+                let pages: Vec<(i32, (f64, f64))> = self.spread(self.current_page)
+                    .filter_map(|page| page_size(page).map(|size| (page, size)))
+                    .collect();
+                let total_width = pages.iter().map(|(_, (width, _))| width).sum::<f64>()
+                    + SPREAD_GAP * pages.len().saturating_sub(1) as f64;
+                let mut x = centered(total_width);
+                for (page, (width, height)) in pages {
+                    slots.push(PageSlot { page, x, y: PAGE_MARGIN, width, height });
+                    x += width + SPREAD_GAP;
+                }
+            }
+        }
+        slots
+    }
+
+    // 並べたページ全体の大きさ (上下の余白を含む。DrawingArea の大きさに使う)
+    pub fn layout_size(&self, scale: f64) -> Option<(f64, f64)> {
+        let slots = self.page_slots(0.0, scale);
+        if slots.is_empty() {
+            return None;
+        }
+        let width = slots.iter().map(|slot| slot.x + slot.width).fold(0.0, f64::max);
+        let height = slots.iter().map(|slot| slot.y + slot.height).fold(0.0, f64::max);
+        Some((width, height + PAGE_MARGIN))
+    }
+
+    // 画面上の点があるページ (ページの間なら近い方) と、そのページでのPDF座標
+    pub fn page_at(&self, x: f64, y: f64, area_width: f64, scale: f64) -> Option<(i32, f64, f64)> {
+        let slots = self.page_slots(area_width, scale);
+        // まず縦の位置で行を選び (見開きの2ページは同じ行)、その中で横の位置でページを選ぶ
+        let row_y = slots.iter().find(|slot| y < slot.y + slot.height + PAGE_GAP / 2.0).or(slots.last())?.y;
+        let row: Vec<&PageSlot> = slots.iter().filter(|slot| slot.y == row_y).collect();
+        let slot = row.iter().find(|slot| x < slot.x + slot.width + SPREAD_GAP / 2.0).or(row.last())?;
+        let (pdf_x, pdf_y) = slot.to_pdf(x, y, scale);
+        Some((slot.page, pdf_x, pdf_y))
+    }
+
+    // ページの上端の位置 (画面上のピクセル)
+    pub fn page_offset(&self, page_index: i32, scale: f64) -> Option<f64> {
+        self.page_slots(0.0, scale).iter().find(|slot| slot.page == page_index).map(|slot| slot.y)
+    }
+
+    // 画面の中央に見えているページ (scroll は縦スクロール量、view_height は見えている高さ)
+    pub fn page_in_view(&self, scroll: f64, view_height: f64, scale: f64) -> Option<i32> {
+        self.page_at(0.0, scroll + view_height / 2.0, 0.0, scale).map(|(page, _, _)| page)
+    }
+
+    // 縦スクロール量 offset が指している位置を、倍率を変えたあとも同じ場所を指すように直す
+    // (ページの間隔は倍率によらないので、ページ内での位置で合わせる)
+    pub fn rescale_offset(&self, offset: f64, old_scale: f64, new_scale: f64) -> f64 {
+        let Some((page, _, pdf_y)) = self.page_at(0.0, offset, 0.0, old_scale) else { return offset };
+        match self.page_offset(page, new_scale) {
+            Some(top) => (top + pdf_y * new_scale).max(0.0),
+            None => offset,
+        }
+    }
+
+    // PDF描画処理
+    // visible は画面に見えている縦の範囲 (連続表示では、その範囲のページだけを描く)
+    pub fn draw(&self, context: &Context, area_width: f64, area_height: f64, scale: f64, visible: (f64, f64)) {
+        // 1. 背景をダークグレーで塗りつぶす
+        context.set_source_rgb(0.2, 0.2, 0.2);
+        context.paint().expect("Painting failed");
+
+        if let Some(doc) = &self.doc {
+            let (visible_top, visible_bottom) = visible;
+            for slot in self.page_slots(area_width, scale) {
+                if slot.y + slot.height < visible_top || slot.y > visible_bottom {
+                    continue;
+                }
+                if let Some(page) = doc.page(slot.page) {
+                    self.draw_page(context, &page, &slot, scale);
                 }
-                
-                context.restore().unwrap(); // 状態復帰
             }
         } else {
             // PDFがない時のメッセージ（中央寄せ）
@@ -302,147 +1760,927 @@ impl PdfEngine {
 
     }
 
-    fn draw_custom_annotations(&self, context: &Context, scale: f64) {
-        let current_page_u32 = (self.current_page + 1) as u32;
+    // 読み込んだ注釈を Poppler 側の描画から外す (保存済みの /AP が page.render() でも描かれ、二重に表示されるのを防ぐ)
+    // メモリ上の Document から消すだけで、ファイルには影響しない。ページごとに、初めて描くときに行う
+    fn hide_source_annots(&self, page: &poppler::Page) {
+        let index = page.index();
+        if !self.hidden_pages.borrow_mut().insert(index) {
+            return;
+        }
+        let sources: Vec<&SourceAnnot> = self.source_annots.iter()
+            .filter(|source| source.page == (index + 1) as u32)
+            .collect();
+        if sources.is_empty() {
+            return;
+        }
+        for mapping in page.annot_mapping() {
+            let raw: *const poppler::ffi::PopplerAnnotMapping = mapping.to_glib_none().0;
+            // SAFETY: raw は mapping が持っている有効な PopplerAnnotMapping を指し、mapping はこの間生きている
+            // annot はその mapping が参照を持っている PopplerAnnot で、from_glib_none で参照を増やしてから受け取る
+            let annot: poppler::Annot = unsafe { from_glib_none((*raw).annot) };
+            if sources.iter().any(|source| is_source_annot(source, &annot)) {
+                page.remove_annot(&annot);
+            }
+        }
+    }
+
+    // 1ページ分の描画 (選択範囲や書いている途中の線は、現在のページにだけ描く)
+    fn draw_page(&self, context: &Context, page: &poppler::Page, slot: &PageSlot, scale: f64) {
+        let (draw_w, draw_h) = (slot.width, slot.height);
+
+        // 座標系を変換 (移動 -> 拡大)
+        context.save().unwrap(); // 状態保存
         
-        for ann in self.annotations.iter().filter(|a| a.page == current_page_u32) {
-            context.save().unwrap();
-            context.translate(ann.x, ann.y);
+        context.translate(slot.x, slot.y);
 
-            let font_size = ann.font_size.unwrap_or(14.0) as f64;
+        // PDFの影を描画 (オプション: ちょっと立体的に見える)
+        context.set_source_rgba(0.0, 0.0, 0.0, 0.5);
+        context.rectangle(5.0, 5.0, draw_w, draw_h);
+        context.fill().unwrap();
 
-            // ★変更: 複数行対応
-            // 改行で分割し、LaTeXの \begin{array}{l} (左揃え) に展開する
-            let lines: Vec<&str> = ann.content.split('\n').collect();
-            let mut latex = String::from("\\begin{array}{l}\n");
-            
-            for (i, line) in lines.iter().enumerate() {
-                // 空行対策（空行だと高さが潰れるためダミー文字を見えなくして置く）
-                if line.trim().is_empty() {
-                    latex.push_str("\\text{\\phantom{A}}"); 
-                } else {
-                    latex.push_str(&format!("\\text{{{}}}", line));
-                }
-                
-                // 最後の行以外は改行記号 \\ をつける
-                if i < lines.len() - 1 {
-                    latex.push_str(" \\\\\n");
-                } else {
-                    latex.push_str("\n");
+        // 用紙の白背景を描画
+        context.set_source_rgb(1.0, 1.0, 1.0);
+        context.rectangle(0.0, 0.0, draw_w, draw_h);
+        context.fill().unwrap();
+
+        // 拡大適用
+        context.scale(scale, scale);
+        
+        // PDFの中身を描画
+        self.hide_source_annots(page);
+        page.render(context);
+
+        // アノテーションを描画
+        self.draw_custom_annotations(context, slot.page, scale);
+
+        if slot.page == self.current_page {
+            // テキスト選択範囲を描画
+            if !self.text_selection.is_empty() {
+                context.save().unwrap();
+                context.set_source_rgba(0.2, 0.5, 1.0, 0.3);
+                for &(x, y, w, h) in &self.text_selection {
+                    context.rectangle(x, y, w, h);
                 }
+                context.fill().unwrap();
+                context.restore().unwrap();
             }
-            latex.push_str("\\end{array}");
-
-            // 1. SVG変換を試みる
-            let mut final_w = 100.0;
-            let mut final_h = 20.0;
 
-            if let Ok(svg_string) = convert_to_svg(&latex) {
-                // ... (ここからSVG保存〜描画のロジックは既存のまま) ...
-                let temp_path = env::temp_dir().join(format!("math_{}.svg", ann.id));
-                if let Ok(mut file) = File::create(&temp_path) {
-                    let _ = file.write_all(svg_string.as_bytes());
-                }
+            // 書いている途中の手書きの線・図形を描画
+            if let Some(stroke) = &self.ink_stroke {
+                context.save().unwrap();
+                paint_ink(context, std::slice::from_ref(&stroke.points), stroke.width, stroke.color);
+                context.restore().unwrap();
+            }
+            if let Some(draft) = &self.shape_draft {
+                context.save().unwrap();
+                paint_shape(context, &draft.kind, draft.color.unwrap_or((0.0, 0.0, 0.0)));
+                context.restore().unwrap();
+            }
+        }
 
-                let loader = Loader::new();
-                if let Ok(handle) = loader.read_path(&temp_path) {
-                    let renderer = CairoRenderer::new(&handle);
-                    let rect = renderer.intrinsic_dimensions();
-                    let w = rect.width.length;
-                    let h = rect.height.length;
-
-                    let target_h = (font_size * 1.5) * lines.len() as f64; 
-                    let s = if h > 0.0 { target_h / h } else { 1.0 };
-                    let draw_w = w * s;
-                    let draw_h = h * s;
-
-                    final_w = draw_w + 10.0;
-                    final_h = draw_h;
-
-                    context.set_source_rgba(1.0, 1.0, 0.8, 0.8);
-                    context.rectangle(-5.0, 0.0, final_w, final_h);
-                    context.fill().unwrap();
-
-                    let offset_x = 0.0;
-                    let offset_y = (draw_h - (h * s)) / 2.0;
-
-                    context.save().unwrap();
-                    context.translate(offset_x, offset_y);
-                    context.scale(s, s);
-                    let _ = renderer.render_document(context, &cairo::Rectangle::new(0.0, 0.0, w, h));
-                    context.restore().unwrap();
-                }
-                let _ = std::fs::remove_file(&temp_path);
-            } else {
-                // ★変更: 変換失敗時のフォールバックも複数行描画に対応
-                let line_h = font_size * 1.5;
-                final_w = 150.0; 
-                final_h = line_h * lines.len() as f64;
+        // 検索ハイライトを描画
+        if let Some(rects) = self.search_results_cache.get(&slot.page) {
+            context.save().unwrap();
+            context.set_source_rgba(1.0, 0.0, 0.0, 0.5); 
+            
+            // ページの本来の高さを取得（これで反転計算する）
+            let (_, page_h) = page.size();
+
+            for rect in rects {
+                // 1. PDF座標系での「上端」と「下端」を整理
+                // (Popplerの矩形は y1 < y2 とは限らないため念のため min/max を使う)
+                let pdf_y_bottom = rect.y1().min(rect.y2());
+                let pdf_y_top = rect.y1().max(rect.y2());
+                
+                // 2. Cairo座標系へ変換
+                // Cairoでの描画開始位置(Y) = ページ高さ - PDFでの上端
+                let cairo_y = page_h - pdf_y_top;
+                
+                // 高さはそのまま差分
+                let height = pdf_y_top - pdf_y_bottom;
+                let width = (rect.x2() - rect.x1()).abs();
 
-                context.set_source_rgba(1.0, 0.8, 0.8, 0.8); 
-                context.rectangle(-5.0, 0.0, final_w, final_h);
+                // 3. 描画
+                context.rectangle(rect.x1(), cairo_y, width, height);
                 context.fill().unwrap();
+            }
+            context.restore().unwrap();
+        }
+        
+        context.restore().unwrap(); // 状態復帰
+    }
 
-                context.set_source_rgb(0.0, 0.0, 0.0);
-                context.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
-                context.set_font_size(font_size);
-                
-                for (i, line) in lines.iter().enumerate() {
-                    context.move_to(0.0, font_size + (i as f64 * line_h));
-                    context.show_text(line).unwrap();
+    fn draw_custom_annotations(&self, context: &Context, page_index: i32, scale: f64) {
+        let page_u32 = (page_index + 1) as u32;
+        
+        for ann in self.annotations.iter().filter(|a| a.page == page_u32) {
+            context.save().unwrap();
+            match &ann.kind {
+                AnnotationKind::Shape { .. } => {
+                    paint_shape(context, &ann.kind, ann.color.unwrap_or((0.0, 0.0, 0.0)));
+                }
+                AnnotationKind::Ink { strokes, width } => {
+                    paint_ink(context, strokes, *width, ann.color.unwrap_or((0.0, 0.0, 0.0)));
+                }
+                AnnotationKind::Markup { style, rects } => {
+                    paint_markup(context, *style, rects, ann.color.unwrap_or(style.default_color()));
+                }
+                AnnotationKind::FreeText => {
+                    context.translate(ann.x, ann.y);
+                    let layout = self.layout_annotation(ann);
+                    self.paint_annotation(context, ann, &layout);
+                    // 実際に描いた大きさを記録する (当たり判定・選択枠・保存時の /Rect に使う)
+                    ann.bounds.set(Some((ann.x, ann.y, layout.width, layout.height)));
+                }
+                AnnotationKind::Note { .. } => {
+                    paint_note_icon(context, ann.x, ann.y, ann.color.unwrap_or(NOTE_COLOR));
                 }
             }
+            context.restore().unwrap();
 
-            // 選択枠（青い破線）の描画
             if Some(&ann.id) == self.active_annotation_id.as_ref() {
-                context.set_source_rgb(0.0, 0.5, 1.0);
-                context.set_line_width(2.0);
-                context.set_dash(&[4.0, 4.0], 0.0);
-                context.rectangle(-7.0, -2.0, final_w + 4.0, final_h + 4.0);
-                context.stroke().unwrap();
-                context.set_dash(&[], 0.0);
+                if let Some((x, y, w, h)) = self.annotation_bounds(ann) {
+                    draw_selection_frame(context, x, y, w, h);
+                    if ann.kind == AnnotationKind::FreeText {
+                        draw_resize_handles(context, x, y, w, h);
+                    }
+                }
             }
+        }
 
-            context.restore().unwrap();
+        // 開いている付箋のポップアップは、他の注釈より手前に描く
+        for ann in self.annotations.iter().filter(|a| a.page == page_u32) {
+            if let AnnotationKind::Note { open: true, popup, .. } = ann.kind {
+                context.save().unwrap();
+                paint_note_popup(context, ann, popup, ann.color.unwrap_or(NOTE_COLOR));
+                context.restore().unwrap();
+            }
+        }
+    }
+
+    // アノテーションの実際の描画範囲 (UI座標の x, y, 幅, 高さ)
+    // 記録済みならそれを使い、無ければ描画と同じ計算をして記録する
+    fn annotation_bounds(&self, ann: &AnnotationData) -> Option<(f64, f64, f64, f64)> {
+        if let Some(bounds) = ann.bounds.get() {
+            return Some(bounds);
+        }
+        let bounds = match &ann.kind {
+            AnnotationKind::FreeText => {
+                let layout = self.layout_annotation(ann);
+                Some((ann.x, ann.y, layout.width, layout.height))
+            }
+            AnnotationKind::Markup { rects, .. } => annotations::bounding_rect(rects),
+            AnnotationKind::Ink { strokes, width } => annotations::ink_bounds(strokes, *width),
+            AnnotationKind::Shape { points, width, line_endings, .. } => {
+                annotations::shape_bounds(points, *width, *line_endings)
+            }
+            AnnotationKind::Note { .. } => Some((ann.x, ann.y, NOTE_ICON_SIZE, NOTE_ICON_SIZE)),
+        };
+        ann.bounds.set(bounds);
+        bounds
+    }
+
+    // 枠の幅が決まっている注釈は、その幅に収まるように改行を入れたテキストを描く
+    fn annotation_text(&self, ann: &AnnotationData) -> String {
+        let font_size = ann.font_size.unwrap_or(14.0) as f64;
+        match (ann.width, &self.measure_context) {
+            (Some(width), Some(context)) => {
+                let max_width = (width - ANNOTATION_PADDING * 2.0).max(font_size);
+                wrap_text(context, &ann.content, ann.text_style.font_family, font_size, max_width)
+            }
+            _ => ann.content.clone(),
+        }
+    }
+
+    fn svg_cache_key(&self, ann: &AnnotationData) -> SvgCacheKey {
+        let (r, g, b) = ann.text_style.text_color;
+        SvgCacheKey {
+            text: self.annotation_text(ann),
+            font_size: ann.font_size.unwrap_or(14.0).to_bits(),
+            font_family: ann.text_style.font_family,
+            text_color: [r.to_bits(), g.to_bits(), b.to_bits()],
+        }
+    }
+
+    // アノテーションの描画サイズを計算する (MathJaxでSVGに変換して計測)
+    // 幅が決まっていれば折り返し、高さが決まっていればその大きさの枠にする
+    fn layout_annotation(&self, ann: &AnnotationData) -> AnnotationLayout {
+        let font_size = ann.font_size.unwrap_or(14.0) as f64;
+
+        // 1. SVG変換を試みる (結果はキャッシュし、失敗も覚えておく)
+        let key = self.svg_cache_key(ann);
+        let svg = self.svg_cache
+            .borrow_mut()
+            .entry(key)
+            .or_insert_with_key(|key| render_math_svg(&key.text, &ann.text_style).map(Rc::new))
+            .clone();
+
+        if let Some(svg) = svg {
+            let target_h = (font_size * 1.5) * svg.lines as f64; 
+            let mut s = if svg.height > 0.0 { target_h / svg.height } else { 1.0 };
+            // 折り返せない長い語や数式は、枠からはみ出さないように縮める
+            if let Some(width) = ann.width {
+                let inner_w = (width - ANNOTATION_PADDING * 2.0).max(1.0);
+                if svg.width * s > inner_w {
+                    s = inner_w / svg.width;
+                }
+            }
+
+            return AnnotationLayout {
+                width: ann.width.unwrap_or(svg.width * s + ANNOTATION_PADDING * 2.0),
+                height: ann.height.unwrap_or(svg.height * s),
+                svg: Some(svg),
+                scale: s,
+            };
+        }
+
+        // 変換失敗時は通常のテキストとして描画する
+        let line_count = self.annotation_text(ann).split('\n').count();
+        AnnotationLayout {
+            svg: None,
+            scale: 1.0,
+            width: ann.width.unwrap_or(150.0),
+            height: ann.height.unwrap_or(font_size * 1.5 * line_count as f64),
+        }
+    }
+
+    // アノテーション本体を原点(左上)から描画する
+    // 画面描画と、保存時の外観ストリーム(/AP)生成の両方で使う
+    fn paint_annotation(&self, context: &Context, ann: &AnnotationData, layout: &AnnotationLayout) {
+        let style = &ann.text_style;
+        let (text_r, text_g, text_b) = style.text_color;
+        // 背景・文字・枠線をまとめて描いてから、不透明度をかける
+        // 不透明度を指定していなければ、背景だけを少し透かす (文字は透かさない)
+        context.push_group();
+
+        if let Some((r, g, b)) = style.background {
+            let alpha = if style.opacity.is_some() { 1.0 } else { annotations::DEFAULT_BACKGROUND_ALPHA };
+            context.set_source_rgba(r, g, b, alpha);
+            context.rectangle(0.0, 0.0, layout.width, layout.height);
+            context.fill().unwrap();
+        }
+
+        context.save().unwrap();
+        // 高さを固定した枠からはみ出す分は描かない
+        context.rectangle(0.0, 0.0, layout.width, layout.height);
+        context.clip();
+        if let Some(svg) = &layout.svg {
+            let renderer = CairoRenderer::new(&svg.handle);
+            context.translate(ANNOTATION_PADDING, 0.0);
+            context.scale(layout.scale, layout.scale);
+            let _ = renderer.render_document(context, &cairo::Rectangle::new(0.0, 0.0, svg.width, svg.height));
+        } else {
+            // ★変更: 変換失敗時のフォールバックも複数行描画に対応
+            let font_size = ann.font_size.unwrap_or(14.0) as f64;
+            let line_h = font_size * 1.5;
+
+            context.set_source_rgb(text_r, text_g, text_b);
+            context.select_font_face(cairo_font_family(style.font_family), cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+            context.set_font_size(font_size);
+            
+            for (i, line) in self.annotation_text(ann).split('\n').enumerate() {
+                context.move_to(ANNOTATION_PADDING, font_size + (i as f64 * line_h));
+                context.show_text(line).unwrap();
+            }
+        }
+        context.restore().unwrap();
+
+        if style.border_width > 0.0 {
+            let w = style.border_width;
+            context.set_source_rgb(text_r, text_g, text_b);
+            context.set_line_width(w);
+            context.rectangle(w / 2.0, w / 2.0, layout.width - w, layout.height - w);
+            context.stroke().unwrap();
         }
+
+        context.pop_group_to_source().unwrap();
+        context.paint_with_alpha(style.opacity.unwrap_or(1.0)).unwrap();
     }
 
+    // 保存用の外観ストリームを作る
+    // 画面と同じ描画処理を cairo の PDF サーフェスに流し、1ページのPDFとして受け取る
+    fn render_appearance(&self, ann: &AnnotationData) -> Option<AppearanceStream> {
+        let (x, y, width, height) = self.annotation_bounds(ann)?;
+
+        let surface = cairo::PdfSurface::for_stream(width, height, Vec::<u8>::new()).ok()?;
+        {
+            let context = Context::new(&surface).ok()?;
+            match &ann.kind {
+                AnnotationKind::FreeText => {
+                    let layout = self.layout_annotation(ann);
+                    self.paint_annotation(&context, ann, &layout);
+                }
+                AnnotationKind::Markup { style, rects } => {
+                    // ページ上の座標で描くので、描画範囲の左上を原点に合わせる
+                    context.translate(-x, -y);
+                    paint_markup(&context, *style, rects, ann.color.unwrap_or(style.default_color()));
+                }
+                AnnotationKind::Ink { strokes, width } => {
+                    context.translate(-x, -y);
+                    paint_ink(&context, strokes, *width, ann.color.unwrap_or((0.0, 0.0, 0.0)));
+                }
+                AnnotationKind::Shape { .. } => {
+                    context.translate(-x, -y);
+                    paint_shape(&context, &ann.kind, ann.color.unwrap_or((0.0, 0.0, 0.0)));
+                }
+                AnnotationKind::Note { .. } => {
+                    context.translate(-x, -y);
+                    paint_note_icon(&context, ann.x, ann.y, ann.color.unwrap_or(NOTE_COLOR));
+                }
+            }
+        }
+        let stream = surface.finish_output_stream().ok()?;
+        let pdf = *stream.downcast::<Vec<u8>>().ok()?;
+
+        Some(AppearanceStream {
+            x,
+            y,
+            width,
+            height,
+            pdf,
+        })
+    }
 
     pub fn hit_test_annotation(&self, pdf_x: f64, pdf_y: f64) -> Option<String> {
         let current_page_u32 = (self.current_page + 1) as u32;
         
+        // 開いている付箋のポップアップは一番手前に描いているので、先に判定する
+        let popup_hit = self.annotations.iter().rev().filter(|a| a.page == current_page_u32).find(|a| {
+            matches!(a.kind, AnnotationKind::Note { open: true, popup: (x, y, w, h), .. }
+                if pdf_x >= x && pdf_x <= x + w && pdf_y >= y && pdf_y <= y + h)
+        });
+        if let Some(ann) = popup_hit {
+            return Some(ann.id.clone());
+        }
+
         // 前面（配列の後ろ）から判定する
         for ann in self.annotations.iter().rev().filter(|a| a.page == current_page_u32) {
-            let font_size = ann.font_size.unwrap_or(14.0) as f64;
-            // SVGの正確な幅はキャッシュしていないため、文字数から大まかな当たり判定ボックスを作成
-            let estimated_width = (ann.content.len() as f64 * font_size * 0.8).max(40.0);
-            let estimated_height = font_size * 2.0;
+            if let AnnotationKind::Markup { rects, .. } = &ann.kind {
+                let hit = rects.iter().any(|&(x, y, w, h)| {
+                    pdf_x >= x && pdf_x <= x + w && pdf_y >= y && pdf_y <= y + h
+                });
+                if hit {
+                    return Some(ann.id.clone());
+                }
+                continue;
+            }
+            if let AnnotationKind::Shape { .. } = &ann.kind {
+                if shape_hit(&ann.kind, pdf_x, pdf_y, HIT_MARGIN) {
+                    return Some(ann.id.clone());
+                }
+                continue;
+            }
+            if let AnnotationKind::Ink { strokes, width } = &ann.kind {
+                let reach = annotations::ink_line_width(*width, 1.0) / 2.0 + HIT_MARGIN;
+                if strokes.iter().any(|stroke| stroke_within(stroke, pdf_x, pdf_y, reach)) {
+                    return Some(ann.id.clone());
+                }
+                continue;
+            }
 
-            // マージンを含めた矩形判定
-            if pdf_x >= ann.x - 10.0 && pdf_x <= ann.x + estimated_width + 10.0 &&
-               pdf_y >= ann.y - 10.0 && pdf_y <= ann.y + estimated_height + 10.0 {
-                return Some(ann.id.clone());
+            // 実際に描画された範囲で判定する (小さい注釈でも掴めるよう少しだけ余白を持たせる)
+            if let Some((x, y, w, h)) = self.annotation_bounds(ann) {
+                if pdf_x >= x - HIT_MARGIN && pdf_x <= x + w + HIT_MARGIN &&
+                   pdf_y >= y - HIT_MARGIN && pdf_y <= y + h + HIT_MARGIN {
+                    return Some(ann.id.clone());
+                }
             }
         }
         None
     }
 
+    // ページ page_index の (x, y) にあるリンクの行き先
+    pub fn link_at(&self, page_index: i32, x: f64, y: f64) -> Option<LinkTarget> {
+        let doc = self.doc.as_ref()?;
+        let mut cache = self.link_cache.borrow_mut();
+        if cache.as_ref().is_none_or(|(index, _)| *index != page_index) {
+            *cache = Some((page_index, page_links(doc, page_index)?));
+        }
+        let (_, links) = cache.as_ref()?;
+        links.iter()
+            .find(|&&((left, top, right, bottom), _)| x >= left && x <= right && y >= top && y <= bottom)
+            .and_then(|(_, target)| target.clone())
+    }
+
+    // 選択中の FreeText のつまみが (x, y) の近くにあれば、そのつまみと今の枠の大きさを返す
+    pub fn resize_handle_at(&self, x: f64, y: f64, tolerance: f64) -> Option<(ResizeHandle, f64, f64)> {
+        let id = self.active_annotation_id.as_ref()?;
+        let ann = self.annotations.iter().find(|a| &a.id == id && a.kind == AnnotationKind::FreeText)?;
+        if ann.page != (self.current_page + 1) as u32 {
+            return None;
+        }
+        let (bx, by, bw, bh) = self.annotation_bounds(ann)?;
+        let reach = RESIZE_HANDLE_SIZE / 2.0 + tolerance;
+        resize_handle_points(bx, by, bw, bh).into_iter()
+            .find(|&(_, (hx, hy))| (hx - x).abs() <= reach && (hy - y).abs() <= reach)
+            .map(|(handle, _)| (handle, bw, bh))
+    }
+
+    // FreeText の枠の大きさを変える (None の辺はそのまま)
+    pub fn resize_annotation(&mut self, id: &str, width: Option<f64>, height: Option<f64>) {
+        let Some(ann) = self.annotations.iter_mut().find(|a| a.id == id) else { return };
+        let font_size = ann.font_size.unwrap_or(14.0) as f64;
+        // 1文字と1行は入る大きさより小さくはしない
+        let width = width.map(|w| w.max(font_size * 2.0 + ANNOTATION_PADDING * 2.0)).or(ann.width);
+        let height = height.map(|h| h.max(font_size * 1.5)).or(ann.height);
+        if ann.width != width || ann.height != height {
+            let before = ann.clone();
+            ann.width = width;
+            ann.height = height;
+            ann.bounds.set(None);
+            let after = ann.clone();
+            self.record_edit(EditCommand::Modify { before, after });
+            self.set_dirty(true);
+        }
+    }
+
+    // FreeText の文字サイズと見た目を変える
+    pub fn set_annotation_style(&mut self, id: &str, font_size: f32, style: TextStyle) {
+        let Some(ann) = self.annotations.iter_mut().find(|a| a.id == id) else { return };
+        if ann.font_size == Some(font_size) && ann.text_style == style {
+            return;
+        }
+        let before = ann.clone();
+        ann.font_size = Some(font_size);
+        ann.text_style = style;
+        ann.bounds.set(None);
+        let after = ann.clone();
+        self.record_edit(EditCommand::Modify { before, after });
+        self.set_dirty(true);
+    }
+
     pub fn update_active_annotation_content(&mut self, content: &str) {
-        if let Some(id) = &self.active_annotation_id {
-            if let Some(ann) = self.annotations.iter_mut().find(|a| &a.id == id) {
-                ann.content = content.to_string();
+        if let Some(id) = self.active_annotation_id.clone() {
+            if let Some(ann) = self.annotations.iter_mut().find(|a| a.id == id) {
+                if ann.content != content {
+                    let before = ann.clone();
+                    ann.content = content.to_string();
+                    ann.bounds.set(None);
+                    let after = ann.clone();
+                    self.record_edit(EditCommand::Modify { before, after });
+                    self.set_dirty(true);
+                }
             }
         }
     }
 
     pub fn move_annotation(&mut self, id: &str, new_x: f64, new_y: f64) {
         if let Some(ann) = self.annotations.iter_mut().find(|a| a.id == id) {
-            ann.x = new_x;
-            ann.y = new_y;
+            // マークアップは本文の文字に付いているので動かさない
+            if matches!(ann.kind, AnnotationKind::Markup { .. }) {
+                return;
+            }
+            if ann.x != new_x || ann.y != new_y {
+                let before = ann.clone();
+                translate_annotation(ann, new_x - ann.x, new_y - ann.y);
+                let after = ann.clone();
+                self.record_edit(EditCommand::Modify { before, after });
+                self.set_dirty(true);
+            }
         }
     }
 
-    
+    // 注釈に返信する。state を指定すると、レビューの状態も変える
+    pub fn add_reply(&mut self, id: &str, content: &str, state: Option<ReviewState>) {
+        if content.is_empty() && state.is_none() {
+            return;
+        }
+        let author = self.author_name();
+        let Some(ann) = self.annotations.iter_mut().find(|a| a.id == id) else { return };
+        let before = ann.clone();
+        let now = annotations::now();
+        ann.replies.push(Reply {
+            id: uuid::Uuid::new_v4().to_string(),
+            object_id: None,
+            author,
+            content: content.to_string(),
+            created: Some(now),
+            modified: Some(now),
+            state,
+            parent: None,
+        });
+        let after = ann.clone();
+        self.record_edit(EditCommand::Modify { before, after });
+        self.set_dirty(true);
+    }
+
+    // 注釈を削除する
+    pub fn delete_annotation(&mut self, id: &str) -> bool {
+        let Some(index) = self.annotations.iter().position(|a| a.id == id) else { return false };
+        let ann = self.annotations.remove(index);
+        if self.active_annotation_id.as_deref() == Some(id) {
+            self.active_annotation_id = None;
+        }
+        self.record_edit(EditCommand::Remove { index, ann });
+        self.set_dirty(true);
+        true
+    }
+
+    // 注釈を少しずらして複製し、複製した方を選択する
+    pub fn duplicate_annotation(&mut self, id: &str) -> Option<String> {
+        let ann = self.annotations.iter().find(|a| a.id == id)?.clone();
+        let page = ann.page;
+        self.insert_copies(vec![ann], page, None)
+    }
+
+    // クリップボードに載せるデータ (注釈だけを載せたPDF と、他のアプリ向けのテキスト)
+    pub fn copy_annotation(&self, id: &str) -> Option<(Vec<u8>, String)> {
+        let ann = self.annotations.iter().find(|a| a.id == id)?;
+        let page_size = self.doc.as_ref()
+            .and_then(|d| d.page(ann.page as i32 - 1))
+            .map(|p| p.size())
+            .unwrap_or((595.0, 842.0));
+        match annotations::annotations_to_clipboard_pdf(std::slice::from_ref(ann), page_size) {
+            Ok(pdf) => Some((pdf, ann.content.clone())),
+            Err(e) => {
+                eprintln!("Copy Error: {}", e);
+                None
+            }
+        }
+    }
+
+    // クリップボードから読み戻した注釈を現在のページに貼り付ける
+    // at を指定すると、(最初の注釈の) 左上がその位置 (UI座標) に来るように置く
+    pub fn paste_annotations(&mut self, pasted: Vec<AnnotationData>, at: Option<(f64, f64)>) -> Option<String> {
+        let page = (self.current_page + 1) as u32;
+        self.insert_copies(pasted, page, at)
+    }
+
+    fn insert_copies(&mut self, copies: Vec<AnnotationData>, page: u32, at: Option<(f64, f64)>) -> Option<String> {
+        let (first_x, first_y) = copies.first().map(|a| (a.x, a.y))?;
+        let (mut dx, mut dy) = match at {
+            Some((x, y)) => (x - first_x, y - first_y),
+            None => (0.0, 0.0),
+        };
+        // 同じ位置に重なるときは、複製だと分かるように少しずらす (何度も貼ればその分ずれていく)
+        while at.is_none() && self.annotations.iter().any(|a| {
+            a.page == page && (a.x - (first_x + dx)).abs() < 0.5 && (a.y - (first_y + dy)).abs() < 0.5
+        }) {
+            dx += PASTE_OFFSET;
+            dy += PASTE_OFFSET;
+        }
+
+        self.begin_edit_group();
+        let mut last_id = None;
+        for mut ann in copies {
+            ann.id = uuid::Uuid::new_v4().to_string();
+            ann.page = page;
+            ann.object_id = None;
+            // 複製は新しい注釈として始める (返信のやり取りは元の注釈に残す)
+            ann.replies.clear();
+            // マークアップは本文の文字に付くので、ずらさずに同じ位置へ置く
+            if !matches!(ann.kind, AnnotationKind::Markup { .. }) {
+                translate_annotation(&mut ann, dx, dy);
+            }
+            last_id = Some(ann.id.clone());
+            self.annotations.push(ann);
+            self.record_last_added();
+        }
+        self.end_edit_group();
+
+        if page as i32 - 1 != self.current_page {
+            self.jump_to_page(page as i32 - 1);
+        }
+        self.active_annotation_id = last_id.clone();
+        self.set_dirty(true);
+        last_id
+    }
+
+    // 最後に追加した注釈に作成者と作成日時を付けて、元に戻せるように記録する
+    // 複製・貼り付けも、新しく作った注釈として扱う
+    fn record_last_added(&mut self) {
+        let author = self.author_name();
+        if let Some(ann) = self.annotations.last_mut() {
+            let now = annotations::now();
+            ann.author = author;
+            ann.created = Some(now);
+            ann.modified = Some(now);
+        }
+        if let Some(ann) = self.annotations.last().cloned() {
+            let index = self.annotations.len() - 1;
+            self.record_edit(EditCommand::Add { index, ann });
+        }
+    }
+
+    // 注釈に記録する作成者 (空なら記録しない)
+    fn author_name(&self) -> Option<String> {
+        let author = self.author.trim();
+        (!author.is_empty()).then(|| author.to_string())
+    }
+
+    fn record_edit(&mut self, mut command: EditCommand) {
+        self.redo_stack.clear();
+        // 保存した時点が取り消した側にあったら、もうそこへは戻れない
+        if self.saved_undo_len.is_some_and(|len| len > self.undo_stack.len()) {
+            self.saved_undo_len = None;
+        }
+        self.mark_edited(&command);
+        self.prune_svg_cache();
+        // 変更した注釈の更新日時を進める (返信が付いただけなら本体は変わっていない)
+        let touched = match &mut command {
+            EditCommand::Modify { before, after } if before.replies == after.replies => Some(after),
+            _ => None,
+        };
+        if let Some(after) = touched {
+            let now = annotations::now();
+            after.modified = Some(now);
+            if let Some(ann) = self.annotations.iter_mut().find(|a| a.id == after.id) {
+                ann.modified = Some(now);
+            }
+        }
+        let Some(pending) = &mut self.pending_edit else {
+            self.push_undo(command);
+            return;
+        };
+        // まとめている途中なら、同じ注釈への続けての変更は最初と最後の状態だけ残す
+        if let EditCommand::Modify { after, .. } = &command {
+            for earlier in pending.iter_mut().rev() {
+                match earlier {
+                    EditCommand::Modify { after: latest, .. } | EditCommand::Add { ann: latest, .. } if latest.id == after.id => {
+                        *latest = after.clone();
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        pending.push(command);
+    }
+
+    // どの注釈にも使われなくなった SVG (内容や見た目を変える前のもの、削除した注釈のもの) をキャッシュから捨てる
+    // 元に戻したときは、もう一度変換し直す
+    fn prune_svg_cache(&self) {
+        let used: HashSet<SvgCacheKey> = self.annotations.iter()
+            .filter(|ann| matches!(ann.kind, AnnotationKind::FreeText))
+            .map(|ann| self.svg_cache_key(ann))
+            .collect();
+        self.svg_cache.borrow_mut().retain(|key, _| used.contains(key));
+    }
+
+    // 変更した注釈を、次の保存で外観を作り直すものとして記録する
+    fn mark_edited(&mut self, command: &EditCommand) {
+        match command {
+            EditCommand::Add { ann, .. } | EditCommand::Remove { ann, .. } | EditCommand::Modify { after: ann, .. } => {
+                self.edited_ids.insert(ann.id.clone());
+            }
+            EditCommand::Group(commands) => {
+                for command in commands {
+                    self.mark_edited(command);
+                }
+            }
+        }
+    }
+
+    fn push_undo(&mut self, command: EditCommand) {
+        self.undo_stack.push_back(command);
+        if self.undo_stack.len() > UNDO_LIMIT {
+            self.undo_stack.pop_front();
+            // 保存した時点も1つ前にずれる (押し出されたら、もう戻れない)
+            self.saved_undo_len = self.saved_undo_len.and_then(|len| len.checked_sub(1));
+        }
+    }
+
+    // ここから end_edit_group までの変更を、1回の「元に戻す」で戻るようにまとめる (ドラッグ操作など)
+    pub fn begin_edit_group(&mut self) {
+        if self.pending_edit.is_none() {
+            self.pending_edit = Some(Vec::new());
+        }
+    }
+
+    pub fn end_edit_group(&mut self) {
+        let Some(mut commands) = self.pending_edit.take() else { return };
+        let command = match commands.len() {
+            0 => return,
+            1 => commands.remove(0),
+            _ => EditCommand::Group(commands),
+        };
+        self.push_undo(command);
+    }
+
+    fn clear_edit_history(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.pending_edit = None;
+        self.saved_undo_len = Some(0);
+    }
+
+    // 直前の変更を取り消す。変更した注釈のページが表示中でなければ、そのページへ移動する
+    pub fn undo(&mut self) -> bool {
+        // 作成途中の多角形は、まずそれを取り消す
+        if self.shape_draft.take().is_some() {
+            return true;
+        }
+        self.end_edit_group();
+        let Some(command) = self.undo_stack.pop_back() else { return false };
+        let page = self.apply_edit(&command, true);
+        self.mark_edited(&command);
+        self.redo_stack.push(command);
+        self.after_history_change(page);
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.end_edit_group();
+        let Some(command) = self.redo_stack.pop() else { return false };
+        let page = self.apply_edit(&command, false);
+        self.mark_edited(&command);
+        self.push_undo(command);
+        self.after_history_change(page);
+        true
+    }
+
+    fn after_history_change(&mut self, page: Option<u32>) {
+        self.last_ink_stroke = None;
+        self.prune_svg_cache();
+        if let Some(id) = &self.active_annotation_id {
+            if !self.annotations.iter().any(|a| &a.id == id) {
+                self.active_annotation_id = None;
+            }
+        }
+        if let Some(page) = page {
+            if page as i32 - 1 != self.current_page {
+                self.jump_to_page(page as i32 - 1);
+            }
+        }
+        // 保存した時点まで戻したら未保存ではなくなる (目次の編集は履歴に無いので、残っていれば未保存のまま)
+        let at_saved = self.saved_undo_len == Some(self.undo_stack.len());
+        self.set_dirty(!at_saved || self.outline_edited);
+    }
+
+    // 変更を取り消す (undo) か、もう一度行う。変更した注釈のページ (1-based) を返す
+    fn apply_edit(&mut self, command: &EditCommand, undo: bool) -> Option<u32> {
+        match command {
+            EditCommand::Add { index, ann } | EditCommand::Remove { index, ann } => {
+                let insert = matches!(command, EditCommand::Add { .. }) != undo;
+                if insert {
+                    let restored = ann.clone();
+                    restored.bounds.set(None);
+                    self.annotations.insert((*index).min(self.annotations.len()), restored);
+                } else {
+                    self.annotations.retain(|a| a.id != ann.id);
+                }
+                Some(ann.page)
+            }
+            EditCommand::Modify { before, after } => {
+                let target = if undo { before } else { after };
+                let ann = self.annotations.iter_mut().find(|a| a.id == target.id)?;
+                // 保存で割り当てられたオブジェクト番号は、戻した後も使い続ける
+                let object_id = ann.object_id;
+                *ann = target.clone();
+                ann.object_id = object_id;
+                ann.bounds.set(None);
+                Some(target.page)
+            }
+            EditCommand::Group(commands) => {
+                let mut page = None;
+                if undo {
+                    for command in commands.iter().rev() {
+                        page = self.apply_edit(command, undo).or(page);
+                    }
+                } else {
+                    for command in commands {
+                        page = self.apply_edit(command, undo).or(page);
+                    }
+                }
+                page
+            }
+        }
+    }
+
+    pub fn outline(&self) -> &[OutlineItem] {
+        &self.outline
+    }
+
+    // ファイルから読んだ目次 (編集したことにはしない)
+    // 読み込みが終わる前に目次を編集していたら、編集した方を残す (反映したかどうかを返す)
+    pub fn set_outline(&mut self, items: Vec<OutlineItem>) -> bool {
+        if self.outline_edited {
+            return false;
+        }
+        self.outline = items;
+        self.outline_suggested = false;
+        true
+    }
+
+    // 目次のないファイルに、推測した目次を表示する (その間に目次ができたり、編集していたりすれば何もしない)
+    pub fn set_suggested_outline(&mut self, items: Vec<OutlineItem>) -> bool {
+        if !self.outline.is_empty() || self.outline_edited || items.is_empty() {
+            return false;
+        }
+        self.outline = items;
+        self.outline_suggested = true;
+        true
+    }
+
+    pub fn is_outline_suggested(&self) -> bool {
+        self.outline_suggested
+    }
+
+    // 推測した目次をそのまま受け入れる (保存時に書き込む)
+    pub fn accept_suggested_outline(&mut self) {
+        if self.outline_suggested {
+            self.outline_changed();
+        }
+    }
+
+    pub fn dismiss_suggested_outline(&mut self) {
+        if self.outline_suggested {
+            self.outline.clear();
+            self.outline_suggested = false;
+        }
+    }
+
+    // 子項目の開閉 (表示だけの変更なので未保存扱いにしない。保存するときは /Count に反映される)
+    pub fn set_outline_expanded(&mut self, index: usize, expanded: bool) {
+        if let Some(item) = self.outline.get_mut(index) {
+            item.expanded = expanded;
+        }
+    }
+
+    // 現在のページのしおりを追加する (追加した項目の位置を返す)
+    pub fn add_page_bookmark(&mut self) -> Option<usize> {
+        self.doc.as_ref()?;
+        let title = match self.get_page_label(self.current_page) {
+            Some(label) => format!("Page {}", label),
+            None => format!("Page {}", self.current_page + 1),
+        };
+        Some(self.insert_bookmark(title, None))
+    }
+
+    // 選択中のテキストを見出しにして、その位置のしおりを追加する
+    pub fn add_selection_bookmark(&mut self) -> Option<usize> {
+        // 見出しは1行にまとめる
+        let title = self.selected_text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if title.is_empty() {
+            return None;
+        }
+        let (_, page_height) = self.get_page_size()?;
+        let top = self.text_selection.iter().map(|&(_, y, _, _)| y).fold(f64::INFINITY, f64::min);
+        let top = top.is_finite().then_some(page_height - top);
+        Some(self.insert_bookmark(title, top))
+    }
+
+    // ページ順になるよう、現在のページより後ろを指す最初の項目の前に入れる (階層は直前の項目と同じ)
+    fn insert_bookmark(&mut self, title: String, top: Option<f64>) -> usize {
+        let page = self.current_page;
+        let index = self.outline.iter()
+            .position(|item| item.page_index.is_some_and(|p| p > page))
+            .unwrap_or(self.outline.len());
+        let level = index.checked_sub(1).map_or(0, |prev| self.outline[prev].level);
+        self.outline.insert(index, OutlineItem {
+            title,
+            page_index: Some(page),
+            level,
+            expanded: true,
+            top,
+            original: lopdf::Dictionary::new(),
+        });
+        self.outline_changed();
+        index
+    }
+
+    pub fn rename_outline_item(&mut self, index: usize, title: &str) {
+        let Some(item) = self.outline.get_mut(index) else { return };
+        if item.title == title {
+            return;
+        }
+        item.title = title.to_string();
+        self.outline_changed();
+    }
+
+    // 項目 from を子項目ごと、項目 target を落とした位置へ動かす (動かした先の位置を返す)
+    pub fn move_outline_item(&mut self, from: usize, target: usize) -> Option<usize> {
+        let moved = move_outline_subtree(&mut self.outline, from, target)?;
+        self.outline_changed();
+        Some(moved)
+    }
+
+    // 項目を子項目ごと1段深く (delta = 1) / 浅く (delta = -1) する
+    pub fn indent_outline_item(&mut self, index: usize, delta: i32) -> bool {
+        if !indent_outline_subtree(&mut self.outline, index, delta) {
+            return false;
+        }
+        self.outline_changed();
+        true
+    }
+
+    // 項目を削除する (子項目は1段浅くして残す)
+    pub fn remove_outline_item(&mut self, index: usize) -> bool {
+        if !remove_outline_entry(&mut self.outline, index) {
+            return false;
+        }
+        self.outline_changed();
+        true
+    }
+
+    // 編集のあとで階層を詰める (推測した目次を編集したら、受け入れたものとして扱う)
+    fn outline_changed(&mut self) {
+        clamp_outline_levels(&mut self.outline);
+        self.outline_edited = true;
+        self.outline_suggested = false;
+        self.set_dirty(true);
+    }
+
     pub fn get_page_thumbnail(&self, page_num: i32, target_width: f64) -> Option<gdk::Texture> {
         let doc = self.doc.as_ref()?;
         let page = doc.page(page_num)?;
@@ -496,5 +2734,110 @@ impl PdfEngine {
         self.filepath.clone()
     }
 
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // 作成者を設定ファイルに保存する (1文字ごとには書かず、入力を確定したときに呼ぶ)
+    pub fn save_author_setting(&self) {
+        if let Err(e) = save_author(&self.author) {
+            eprintln!("Failed to save settings: {}", e);
+        }
+    }
+
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (タイトル, 階層) の並びで目次を作る
+    fn outline(items: &[(&str, i32)]) -> Vec<OutlineItem> {
+        items.iter()
+            .map(|&(title, level)| OutlineItem {
+                title: title.to_string(),
+                page_index: Some(0),
+                level,
+                expanded: true,
+                top: None,
+                original: lopdf::Dictionary::new(),
+            })
+            .collect()
+    }
+
+    fn summary(items: &[OutlineItem]) -> Vec<(&str, i32)> {
+        items.iter().map(|item| (item.title.as_str(), item.level)).collect()
+    }
+
+    #[test]
+    fn moving_down_keeps_the_target_children() {
+        let mut items = outline(&[("Item 0", 0), ("Item 1", 0), ("Chapter 2", 0), ("Child", 1)]);
+        assert_eq!(move_outline_subtree(&mut items, 0, 2), Some(3));
+        assert_eq!(summary(&items), [("Item 1", 0), ("Chapter 2", 0), ("Child", 1), ("Item 0", 0)]);
+    }
+
+    #[test]
+    fn moving_up_takes_the_children_along() {
+        let mut items = outline(&[("Item 0", 0), ("Chapter 1", 0), ("Child", 1), ("Item 3", 0)]);
+        assert_eq!(move_outline_subtree(&mut items, 1, 0), Some(0));
+        assert_eq!(summary(&items), [("Chapter 1", 0), ("Child", 1), ("Item 0", 0), ("Item 3", 0)]);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn cannot_move_onto_itself_or_its_descendants() {
+        let mut items = outline(&[("Chapter", 0), ("Child", 1), ("Grandchild", 2), ("Next", 0)]);
+        assert_eq!(move_outline_subtree(&mut items, 0, 0), None);
+        assert_eq!(move_outline_subtree(&mut items, 0, 2), None);
+        assert_eq!(move_outline_subtree(&mut items, 0, 4), None);
+        assert_eq!(summary(&items), [("Chapter", 0), ("Child", 1), ("Grandchild", 2), ("Next", 0)]);
+    }
+
+    #[test]
+    fn moved_child_is_clamped_to_a_valid_level() {
+        let mut items = outline(&[("Chapter", 0), ("Child", 1), ("Next", 0)]);
+        assert_eq!(move_outline_subtree(&mut items, 1, 0), Some(0));
+        clamp_outline_levels(&mut items);
+        // 先頭には親がないので最上位になる
+        assert_eq!(summary(&items), [("Child", 0), ("Chapter", 0), ("Next", 0)]);
+    }
+
+    #[test]
+    fn indent_moves_the_subtree() {
+        let mut items = outline(&[("A", 0), ("B", 0), ("B1", 1), ("C", 0)]);
+        assert!(indent_outline_subtree(&mut items, 1, 1));
+        assert_eq!(summary(&items), [("A", 0), ("B", 1), ("B1", 2), ("C", 0)]);
+        assert!(indent_outline_subtree(&mut items, 1, -1));
+        assert_eq!(summary(&items), [("A", 0), ("B", 0), ("B1", 1), ("C", 0)]);
+    }
+
+    #[test]
+    fn indent_is_limited_by_the_previous_item() {
+        let mut items = outline(&[("A", 0), ("B", 0), ("B1", 1)]);
+        // 先頭は深くできず、最上位は浅くできない
+        assert!(!indent_outline_subtree(&mut items, 0, 1));
+        assert!(!indent_outline_subtree(&mut items, 1, -1));
+        // 直前の項目より2段深くはできない
+        assert!(!indent_outline_subtree(&mut items, 2, 1));
+        assert!(!indent_outline_subtree(&mut items, 3, 1));
+        assert_eq!(summary(&items), [("A", 0), ("B", 0), ("B1", 1)]);
+    }
+
+    #[test]
+    fn stroke_is_hit_between_sparse_points() {
+        let stroke = [(0.0, 0.0, 1.0), (100.0, 0.0, 1.0)];
+        assert!(stroke_within(&stroke, 50.0, 2.0, 3.0));
+        assert!(!stroke_within(&stroke, 50.0, 5.0, 3.0));
+        assert!(!stroke_within(&stroke, 105.0, 0.0, 3.0));
+        // 1点だけの線 (点を打っただけ)
+        assert!(stroke_within(&[(10.0, 10.0, 1.0)], 11.0, 11.0, 3.0));
+        assert!(!stroke_within(&[], 0.0, 0.0, 3.0));
+    }
+
+    #[test]
+    fn remove_lifts_the_children() {
+        let mut items = outline(&[("A", 0), ("B", 0), ("B1", 1), ("B2", 1), ("B2a", 2), ("C", 0)]);
+        assert!(remove_outline_entry(&mut items, 1));
+        assert_eq!(summary(&items), [("A", 0), ("B1", 0), ("B2", 0), ("B2a", 1), ("C", 0)]);
+        assert!(!remove_outline_entry(&mut items, 5));
+    }
+}
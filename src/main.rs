@@ -5,6 +5,7 @@ use gtk4::Application;
 mod engine;
 mod ui;
 mod annotations;
+mod outline;
 
 fn main() {
     let app = Application::builder()
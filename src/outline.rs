@@ -0,0 +1,629 @@
+// PDF の目次 (/Outlines) の読み書き
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{HashMap, HashSet};
+use crate::annotations::{decode_text_string, encode_text_string};
+
+// 目次の1項目 (木構造を文書順に並べ、階層は level で表す)
+#[derive(Clone, Debug)]
+pub struct OutlineItem {
+    pub title: String,
+    pub page_index: Option<i32>, // ページ番号 (ない場合もある)
+    pub level: i32,              // 階層レベル (0, 1, 2...)
+    pub expanded: bool,          // 子項目を開いて表示するか (/Count が正なら開いている)
+    // Margium で追加した項目の行き先の上端 (PDF座標、下が 0。None ならページ全体)
+    pub top: Option<f64>,
+    // ファイルから読んだ項目の、書き直しても残すキー (行き先 /Dest・/A と、色 /C・文字のスタイル /F)
+    pub original: Dictionary,
+}
+
+// 目次を書き直すときに、元の項目から引き継ぐキー
+const KEPT_KEYS: [&[u8]; 4] = [b"Dest", b"A", b"C", b"F"];
+
+// 目次の階層の上限 (/First が循環しているファイル対策)
+const MAX_OUTLINE_DEPTH: i32 = 64;
+// 名前付きの行き先をたどる深さの上限 (名前ツリーや行き先の参照が循環しているファイル対策)
+const MAX_DEST_DEPTH: usize = 32;
+
+// 文書の目次を、行き先をページ番号に解決して読み込む
+pub fn read_outline(doc: &Document) -> Vec<OutlineItem> {
+    let mut items = Vec::new();
+    let Some(root) = doc.catalog().ok()
+        .and_then(|catalog| catalog.get(b"Outlines").ok())
+        .and_then(|o| resolve_dict(doc, o))
+    else {
+        return items;
+    };
+
+    // ページのオブジェクトID -> ページ番号 (0始まり)
+    let pages: HashMap<ObjectId, i32> = doc.get_pages()
+        .into_iter()
+        .map(|(number, id)| (id, number as i32 - 1))
+        .collect();
+    let mut visited = HashSet::new();
+    read_children(doc, root, 0, &pages, &mut visited, &mut items);
+    items
+}
+
+fn resolve_dict<'a>(doc: &'a Document, obj: &'a Object) -> Option<&'a Dictionary> {
+    match obj {
+        Object::Reference(id) => doc.get_dictionary(*id).ok(),
+        other => other.as_dict().ok(),
+    }
+}
+
+// parent の子項目を /First から /Next でたどり、孫以下も続けて並べる
+fn read_children(
+    doc: &Document,
+    parent: &Dictionary,
+    level: i32,
+    pages: &HashMap<ObjectId, i32>,
+    visited: &mut HashSet<ObjectId>,
+    items: &mut Vec<OutlineItem>,
+) {
+    if level >= MAX_OUTLINE_DEPTH {
+        return;
+    }
+    let mut next = parent.get(b"First").and_then(|o| o.as_reference()).ok();
+    while let Some(id) = next {
+        // 壊れたファイルで /Next が循環していても止まるようにする
+        if !visited.insert(id) {
+            break;
+        }
+        let Ok(dict) = doc.get_dictionary(id) else { break };
+
+        let title = dict.get(b"Title").ok()
+            .and_then(|o| doc.dereference(o).ok())
+            .and_then(|(_, o)| o.as_str().ok())
+            .map(decode_text_string)
+            .unwrap_or_default();
+        let count = dict.get(b"Count").and_then(|o| o.as_i64()).unwrap_or(0);
+        let mut original = Dictionary::new();
+        for key in KEPT_KEYS {
+            if let Ok(value) = dict.get(key) {
+                original.set(key, value.clone());
+            }
+        }
+        items.push(OutlineItem {
+            title,
+            page_index: item_page(doc, dict, pages),
+            level,
+            expanded: count > 0,
+            top: None,
+            original,
+        });
+
+        read_children(doc, dict, level + 1, pages, visited, items);
+        next = dict.get(b"Next").and_then(|o| o.as_reference()).ok();
+    }
+}
+
+// 項目の行き先 (/Dest か、/A の GoTo アクション) のページ番号
+fn item_page(doc: &Document, dict: &Dictionary, pages: &HashMap<ObjectId, i32>) -> Option<i32> {
+    if let Ok(dest) = dict.get(b"Dest") {
+        return dest_page(doc, dest, pages, 0);
+    }
+    let action = dict.get(b"A").ok().and_then(|o| resolve_dict(doc, o))?;
+    let is_goto = action.get(b"S").and_then(|o| o.as_name()).is_ok_and(|s| s == b"GoTo");
+    if !is_goto {
+        return None;
+    }
+    dest_page(doc, action.get(b"D").ok()?, pages, 0)
+}
+
+// 行き先 (ページを指す配列、または名前付きの行き先) をページ番号にする
+fn dest_page(doc: &Document, dest: &Object, pages: &HashMap<ObjectId, i32>, depth: usize) -> Option<i32> {
+    if depth > MAX_DEST_DEPTH {
+        return None;
+    }
+    let (_, dest) = doc.dereference(dest).ok()?;
+    match dest {
+        Object::Array(arr) => match arr.first()? {
+            Object::Reference(id) => pages.get(id).copied(),
+            // ページ番号 (0始まり) で書かれていることもある
+            Object::Integer(n) => Some(*n as i32),
+            _ => None,
+        },
+        // 名前付きの行き先の値は、/D に配列を持つ辞書のこともある
+        Object::Dictionary(d) => dest_page(doc, d.get(b"D").ok()?, pages, depth + 1),
+        Object::Name(name) | Object::String(name, _) => {
+            dest_page(doc, named_dest(doc, name, depth)?, pages, depth + 1)
+        }
+        _ => None,
+    }
+}
+
+// 名前付きの行き先を探す (PDF 1.1 の /Dests 辞書と、/Names の /Dests 名前ツリー)
+fn named_dest<'a>(doc: &'a Document, name: &[u8], depth: usize) -> Option<&'a Object> {
+    let catalog = doc.catalog().ok()?;
+    let old_style = catalog.get(b"Dests").ok()
+        .and_then(|o| resolve_dict(doc, o))
+        .and_then(|dests| dests.get(name).ok());
+    if old_style.is_some() {
+        return old_style;
+    }
+    let tree = catalog.get(b"Names").ok()
+        .and_then(|o| resolve_dict(doc, o))
+        .and_then(|names| names.get(b"Dests").ok())
+        .and_then(|o| resolve_dict(doc, o))?;
+    find_in_name_tree(doc, tree, name, depth)
+}
+
+fn find_in_name_tree<'a>(doc: &'a Document, node: &'a Dictionary, name: &[u8], depth: usize) -> Option<&'a Object> {
+    if depth > MAX_DEST_DEPTH {
+        return None;
+    }
+    let array_of = |key: &[u8]| {
+        node.get(key).ok()
+            .and_then(|o| doc.dereference(o).ok())
+            .and_then(|(_, o)| o.as_array().ok())
+    };
+    // 葉: [名前1 値1 名前2 値2 ...]
+    if let Some(names) = array_of(b"Names") {
+        let found = names.chunks_exact(2)
+            .find(|pair| pair[0].as_str().is_ok_and(|key| key == name))
+            .map(|pair| &pair[1]);
+        if found.is_some() {
+            return found;
+        }
+    }
+    array_of(b"Kids")?
+        .iter()
+        .filter_map(|kid| resolve_dict(doc, kid))
+        .find_map(|kid| find_in_name_tree(doc, kid, name, depth + 1))
+}
+
+// 目次全体を書き直して update に入れる (空なら /Outlines を外す)
+// 項目は文書順に並んでいて、直前の項目より深い項目はその子になる
+pub fn write_outline(doc: &Document, update: &mut Document, items: &[OutlineItem]) -> Result<(), String> {
+    let catalog_id = doc.trailer.get(b"Root").and_then(|o| o.as_reference()).map_err(|e| e.to_string())?;
+    let mut catalog = doc.get_dictionary(catalog_id).map_err(|e| e.to_string())?.clone();
+
+    if items.is_empty() {
+        catalog.remove(b"Outlines");
+        update.objects.insert(catalog_id, Object::Dictionary(catalog));
+        return Ok(());
+    }
+
+    // 親子関係: 自分より浅い直前の項目が親 (None は最上位)
+    let mut parents: Vec<Option<usize>> = Vec::with_capacity(items.len());
+    let mut stack: Vec<usize> = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        while stack.last().is_some_and(|&last| items[last].level >= item.level) {
+            stack.pop();
+        }
+        parents.push(stack.last().copied());
+        stack.push(index);
+    }
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); items.len()];
+    let mut top_level = Vec::new();
+    for (index, parent) in parents.iter().enumerate() {
+        match parent {
+            Some(parent) => children[*parent].push(index),
+            None => top_level.push(index),
+        }
+    }
+
+    // 開いたときに見える子孫の数 (/Count。子は後ろにあるので後ろから数える)
+    let mut visible = vec![0i64; items.len()];
+    for index in (0..items.len()).rev() {
+        visible[index] = children[index]
+            .iter()
+            .map(|&child| 1 + if items[child].expanded { visible[child] } else { 0 })
+            .sum();
+    }
+
+    // 元の /Outlines は使い回し、項目は新しいオブジェクトとして書く
+    // (全体を書き直す保存では、参照されなくなった古い項目は捨てられる)
+    let root_id = match catalog.get(b"Outlines").and_then(|o| o.as_reference()) {
+        Ok(id) => id,
+        Err(_) => update.new_object_id(),
+    };
+    let ids: Vec<ObjectId> = items.iter().map(|_| update.new_object_id()).collect();
+    let pages = doc.get_pages();
+
+    let link_siblings = |dict: &mut Dictionary, siblings: &[usize], index: usize| {
+        let position = siblings.iter().position(|&i| i == index).unwrap_or(0);
+        if position > 0 {
+            dict.set("Prev", Object::Reference(ids[siblings[position - 1]]));
+        }
+        if let Some(&next) = siblings.get(position + 1) {
+            dict.set("Next", Object::Reference(ids[next]));
+        }
+    };
+    let link_children = |dict: &mut Dictionary, kids: &[usize]| {
+        if let (Some(&first), Some(&last)) = (kids.first(), kids.last()) {
+            dict.set("First", Object::Reference(ids[first]));
+            dict.set("Last", Object::Reference(ids[last]));
+        }
+    };
+
+    for (index, item) in items.iter().enumerate() {
+        let mut dict = item.original.clone();
+        dict.set("Title", encode_text_string(&item.title));
+        let (parent_id, siblings) = match parents[index] {
+            Some(parent) => (ids[parent], &children[parent]),
+            None => (root_id, &top_level),
+        };
+        dict.set("Parent", Object::Reference(parent_id));
+        link_siblings(&mut dict, siblings, index);
+        link_children(&mut dict, &children[index]);
+        if !children[index].is_empty() {
+            // 閉じている項目は負の数にする
+            let count = if item.expanded { visible[index] } else { -visible[index] };
+            dict.set("Count", Object::Integer(count));
+        }
+
+        // 行き先のない項目 (Margium で追加したもの) は、ページを指す /Dest を作る
+        if !dict.has(b"Dest") && !dict.has(b"A") {
+            let page_id = item.page_index.and_then(|page| pages.get(&(page as u32 + 1)));
+            if let Some(&page_id) = page_id {
+                let dest = match item.top {
+                    Some(top) => vec![
+                        Object::Reference(page_id),
+                        Object::Name(b"XYZ".to_vec()),
+                        Object::Null,
+                        Object::Real(top as f32),
+                        Object::Null,
+                    ],
+                    None => vec![Object::Reference(page_id), Object::Name(b"Fit".to_vec())],
+                };
+                dict.set("Dest", Object::Array(dest));
+            }
+        }
+        update.objects.insert(ids[index], Object::Dictionary(dict));
+    }
+
+    let mut root = Dictionary::new();
+    root.set("Type", Object::Name(b"Outlines".to_vec()));
+    link_children(&mut root, &top_level);
+    let root_count: i64 = top_level.iter().map(|&i| 1 + if items[i].expanded { visible[i] } else { 0 }).sum();
+    root.set("Count", Object::Integer(root_count));
+    update.objects.insert(root_id, Object::Dictionary(root));
+
+    if catalog.get(b"Outlines").and_then(|o| o.as_reference()).ok() != Some(root_id) {
+        catalog.set("Outlines", Object::Reference(root_id));
+        update.objects.insert(catalog_id, Object::Dictionary(catalog));
+    }
+    Ok(())
+}
+
+// --- 目次の推測 (目次のないファイル向け) ---
+// 文字の大きさと太さから見出しらしい行を探し、大きい書式から順に階層を割り当てる
+
+// 本文より何倍以上大きい文字を見出しとみなすか
+const HEADING_SIZE_RATIO: f64 = 1.15;
+// 見出しとみなす行の長さ (文字数)
+const MAX_HEADING_CHARS: usize = 100;
+// 推測する階層の数
+const MAX_SUGGESTED_LEVELS: usize = 3;
+// 1ページあたりこれより多く出てくる書式は、見出しではなく本文の一種とみなす
+const MAX_HEADINGS_PER_PAGE: usize = 3;
+
+// 1行分の文字と、その行でいちばん多く使われている書式
+struct TextLine {
+    text: String,
+    page: i32,
+    top: f64,    // PDF座標 (上が 0)
+    bottom: f64,
+    style: TextStyleKey,
+}
+
+// 文字の書式 (大きさは 0.5pt 単位に丸める)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TextStyleKey {
+    half_points: i64,
+    bold: bool,
+}
+
+impl TextStyleKey {
+    fn size(&self) -> f64 {
+        self.half_points as f64 / 2.0
+    }
+}
+
+fn is_bold_font(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["bold", "black", "heavy", "semibold", "demi"].iter().any(|w| name.contains(w))
+}
+
+// ページの文字ごとの矩形 (poppler-rs は poppler_page_get_text_layout を公開していないので直接呼ぶ)
+fn text_layout(page: &poppler::Page) -> Vec<(f64, f64, f64, f64)> {
+    use glib::translate::ToGlibPtr;
+    let mut rects: *mut poppler::ffi::PopplerRectangle = std::ptr::null_mut();
+    let mut count: std::os::raw::c_uint = 0;
+    let page_ptr: *mut poppler::ffi::PopplerPage = page.to_glib_none().0;
+    // SAFETY: page_ptr は page が持っている有効な PopplerPage を指し、page はこの関数の間生きている
+    // rects と count は、呼び出しが書き込む先としてローカル変数を渡している
+    if unsafe { poppler::ffi::poppler_page_get_text_layout(page_ptr, &mut rects, &mut count) } == 0 || rects.is_null() {
+        return Vec::new();
+    }
+    // SAFETY: 成功したときの rects は、count 個の PopplerRectangle を並べた、呼び出し側が持つ配列
+    // ここで値を写してから g_free で1回だけ解放し、その後は rects を使わない
+    unsafe {
+        let layout = std::slice::from_raw_parts(rects, count as usize)
+            .iter()
+            .map(|r| (r.x1, r.y1, r.x2, r.y2))
+            .collect();
+        glib::ffi::g_free(rects as *mut _);
+        layout
+    }
+}
+
+// ページの文字を行に分け、行ごとの位置と書式を求める
+fn page_lines(page: &poppler::Page, page_index: i32) -> Vec<TextLine> {
+    use glib::translate::ToGlibPtr;
+    let Some(text) = page.text() else { return Vec::new() };
+    let chars: Vec<char> = text.chars().collect();
+    let rects = text_layout(page);
+    let len = chars.len().min(rects.len());
+
+    // 文字ごとの書式 (start_index..=end_index は文字の位置)
+    let mut styles: Vec<Option<TextStyleKey>> = vec![None; len];
+    for attrs in page.text_attributes() {
+        let raw: *const poppler::ffi::PopplerTextAttributes = attrs.to_glib_none().0;
+        // SAFETY: raw は attrs が持っている有効な PopplerTextAttributes を指し、attrs はこの繰り返しの間生きている
+        let (size, name, start, end) = unsafe { ((*raw).font_size, (*raw).font_name, (*raw).start_index, (*raw).end_index) };
+        // SAFETY: font_name は attrs が持っている NUL 終端の文字列で、attrs より長く使わないよう、この場で文字列に写す
+        let bold = !name.is_null() && is_bold_font(&unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy());
+        let style = TextStyleKey { half_points: (size * 2.0).round() as i64, bold };
+        let start = start.max(0) as usize;
+        let end = (end.max(0) as usize + 1).min(len);
+        for slot in styles.iter_mut().take(end).skip(start) {
+            *slot = Some(style);
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while start < len {
+        let end = chars[start..len].iter().position(|&c| c == '\n').map_or(len, |offset| start + offset);
+        let indices: Vec<usize> = (start..end).filter(|&i| !chars[i].is_whitespace()).collect();
+        start = end + 1;
+
+        // 行でいちばん多く使われている書式
+        let mut counts: HashMap<TextStyleKey, usize> = HashMap::new();
+        for &i in &indices {
+            if let Some(style) = styles[i] {
+                *counts.entry(style).or_default() += 1;
+            }
+        }
+        let Some((style, _)) = counts.into_iter().max_by_key(|&(style, count)| (count, style.half_points)) else { continue };
+
+        let text: String = chars[indices[0]..=indices[indices.len() - 1]].iter().collect();
+        let top = indices.iter().map(|&i| rects[i].1).fold(f64::INFINITY, f64::min);
+        let bottom = indices.iter().map(|&i| rects[i].3).fold(f64::NEG_INFINITY, f64::max);
+        lines.push(TextLine { text, page: page_index, top, bottom, style });
+    }
+    lines
+}
+
+fn is_heading_candidate(line: &TextLine, body: TextStyleKey) -> bool {
+    let length = line.text.chars().count();
+    if !(2..=MAX_HEADING_CHARS).contains(&length) || !line.text.chars().any(char::is_alphabetic) {
+        return false;
+    }
+    let larger = line.style.size() >= body.size() * HEADING_SIZE_RATIO;
+    // 本文と同じ大きさでも、本文が太字でなければ太字の行は小見出しとみなす
+    let bolder = line.style.bold && !body.bold && line.style.half_points >= body.half_points;
+    larger || bolder
+}
+
+// 全ページの文字の書式から目次を推測する (重いのでワーカースレッドで呼ぶ)
+pub fn suggest_outline(doc: &poppler::Document) -> Vec<OutlineItem> {
+    let total_pages = doc.n_pages();
+    let mut lines = Vec::new();
+    let mut page_heights = HashMap::new();
+    for i in 0..total_pages {
+        let Some(page) = doc.page(i) else { continue };
+        page_heights.insert(i, page.size().1);
+        lines.extend(page_lines(&page, i));
+    }
+
+    // 本文の書式: 使われている文字数がいちばん多いもの
+    let mut char_counts: HashMap<TextStyleKey, usize> = HashMap::new();
+    for line in &lines {
+        *char_counts.entry(line.style).or_default() += line.text.chars().count();
+    }
+    let Some((body, _)) = char_counts.into_iter().max_by_key(|&(_, count)| count) else { return Vec::new() };
+
+    // 見出しの候補 (lines の位置) と、書式ごとの出現回数
+    let candidates: Vec<usize> = (0..lines.len()).filter(|&i| is_heading_candidate(&lines[i], body)).collect();
+    let mut style_counts: HashMap<TextStyleKey, usize> = HashMap::new();
+    for &i in &candidates {
+        *style_counts.entry(lines[i].style).or_default() += 1;
+    }
+
+    // 大きい書式 (同じ大きさなら太字) から順に階層を割り当てる
+    let max_count = (total_pages.max(1) as usize) * MAX_HEADINGS_PER_PAGE;
+    let mut heading_styles: Vec<TextStyleKey> = style_counts
+        .into_iter()
+        .filter(|&(_, count)| count <= max_count)
+        .map(|(style, _)| style)
+        .collect();
+    heading_styles.sort_by_key(|style| std::cmp::Reverse((style.half_points, style.bold)));
+    heading_styles.truncate(MAX_SUGGESTED_LEVELS);
+
+    let mut items: Vec<OutlineItem> = Vec::new();
+    let mut previous: Option<usize> = None;
+    for i in candidates {
+        let line = &lines[i];
+        let Some(level) = heading_styles.iter().position(|&style| style == line.style) else { continue };
+
+        // 直前の行と同じ書式で続いている見出しは、折り返した1つの見出しとしてまとめる
+        let continued = previous.is_some_and(|prev| {
+            let prev_line = &lines[prev];
+            prev + 1 == i
+                && prev_line.page == line.page
+                && prev_line.style == line.style
+                && line.top - prev_line.bottom < line.style.size()
+        });
+        previous = Some(i);
+        if continued {
+            if let Some(item) = items.last_mut() {
+                item.title.push(' ');
+                item.title.push_str(&line.text);
+            }
+            continue;
+        }
+
+        let page_height = page_heights.get(&line.page).copied().unwrap_or(0.0);
+        items.push(OutlineItem {
+            title: line.text.clone(),
+            page_index: Some(line.page),
+            level: level as i32,
+            expanded: true,
+            top: Some(page_height - line.top),
+            original: Dictionary::new(),
+        });
+    }
+
+    // 最上位の見出しがない部分でも、階層が飛ばないように詰める
+    let mut max_level = 0;
+    for item in &mut items {
+        item.level = item.level.min(max_level);
+        max_level = item.level + 1;
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    fn document_with_pages(count: i64) -> Document {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let kids: Vec<Object> = (0..count)
+            .map(|_| {
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+                }).into()
+            })
+            .collect();
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => count,
+        }));
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc
+    }
+
+    fn item(title: &str, level: i32, page: i32, expanded: bool) -> OutlineItem {
+        OutlineItem {
+            title: title.to_string(),
+            page_index: Some(page),
+            level,
+            expanded,
+            top: None,
+            original: Dictionary::new(),
+        }
+    }
+
+    fn reference(dict: &Dictionary, key: &[u8]) -> Option<ObjectId> {
+        dict.get(key).and_then(|o| o.as_reference()).ok()
+    }
+
+    #[test]
+    fn written_outline_reads_back() {
+        let mut doc = document_with_pages(3);
+        let items = vec![
+            item("Chapter 1", 0, 0, false),
+            item("Section 1.1", 1, 0, true),
+            item("Section 1.2", 1, 1, true),
+            item("Chapter 2", 0, 1, true),
+            item("Section 2.1", 1, 2, false),
+            item("Section 2.1.1", 2, 2, true),
+        ];
+        let mut update = Document::new_from_prev(&doc);
+        write_outline(&doc, &mut update, &items).unwrap();
+        doc.max_id = doc.max_id.max(update.max_id);
+        doc.objects.extend(update.objects);
+
+        let read = read_outline(&doc);
+        let summary: Vec<(&str, i32, Option<i32>)> = read.iter()
+            .map(|item| (item.title.as_str(), item.level, item.page_index))
+            .collect();
+        assert_eq!(summary, [
+            ("Chapter 1", 0, Some(0)),
+            ("Section 1.1", 1, Some(0)),
+            ("Section 1.2", 1, Some(1)),
+            ("Chapter 2", 0, Some(1)),
+            ("Section 2.1", 1, Some(2)),
+            ("Section 2.1.1", 2, Some(2)),
+        ]);
+        // 子項目を持つ項目の開閉は /Count の符号で残る
+        assert!(!read[0].expanded);
+        assert!(read[3].expanded);
+        assert!(!read[4].expanded);
+    }
+
+    #[test]
+    fn written_outline_links_are_consistent() {
+        let mut doc = document_with_pages(2);
+        let items = vec![
+            item("Chapter 1", 0, 0, false),
+            item("Section 1.1", 1, 0, true),
+            item("Section 1.2", 1, 1, true),
+            item("Chapter 2", 0, 1, true),
+            item("Section 2.1", 1, 1, true),
+        ];
+        let mut update = Document::new_from_prev(&doc);
+        write_outline(&doc, &mut update, &items).unwrap();
+        doc.objects.extend(update.objects);
+
+        let root_id = reference(doc.catalog().unwrap(), b"Outlines").unwrap();
+        let root = doc.get_dictionary(root_id).unwrap();
+        // 閉じている Chapter 1 の子は数えない
+        assert_eq!(root.get(b"Count").unwrap().as_i64().unwrap(), 3);
+
+        let chapter1_id = reference(root, b"First").unwrap();
+        let chapter2_id = reference(root, b"Last").unwrap();
+        let chapter1 = doc.get_dictionary(chapter1_id).unwrap();
+        let chapter2 = doc.get_dictionary(chapter2_id).unwrap();
+        assert_eq!(reference(chapter1, b"Parent"), Some(root_id));
+        assert_eq!(reference(chapter1, b"Next"), Some(chapter2_id));
+        assert_eq!(reference(chapter1, b"Prev"), None);
+        assert_eq!(reference(chapter2, b"Prev"), Some(chapter1_id));
+        assert_eq!(reference(chapter2, b"Next"), None);
+        assert_eq!(chapter1.get(b"Count").unwrap().as_i64().unwrap(), -2);
+        assert_eq!(chapter2.get(b"Count").unwrap().as_i64().unwrap(), 1);
+
+        let section11_id = reference(chapter1, b"First").unwrap();
+        let section12_id = reference(chapter1, b"Last").unwrap();
+        let section11 = doc.get_dictionary(section11_id).unwrap();
+        let section12 = doc.get_dictionary(section12_id).unwrap();
+        assert_eq!(reference(section11, b"Parent"), Some(chapter1_id));
+        assert_eq!(reference(section12, b"Parent"), Some(chapter1_id));
+        assert_eq!(reference(section11, b"Next"), Some(section12_id));
+        assert_eq!(reference(section12, b"Prev"), Some(section11_id));
+        // 子のない項目は /First・/Last・/Count を持たない
+        assert!(!section11.has(b"First") && !section11.has(b"Last") && !section11.has(b"Count"));
+
+        let section21_id = reference(chapter2, b"First").unwrap();
+        assert_eq!(reference(chapter2, b"Last"), Some(section21_id));
+        assert_eq!(reference(doc.get_dictionary(section21_id).unwrap(), b"Parent"), Some(chapter2_id));
+    }
+
+    #[test]
+    fn empty_outline_removes_outlines() {
+        let mut doc = document_with_pages(1);
+        let mut update = Document::new_from_prev(&doc);
+        write_outline(&doc, &mut update, &[item("Chapter", 0, 0, true)]).unwrap();
+        doc.objects.extend(update.objects);
+        assert!(doc.catalog().unwrap().has(b"Outlines"));
+
+        let mut update = Document::new_from_prev(&doc);
+        write_outline(&doc, &mut update, &[]).unwrap();
+        doc.objects.extend(update.objects);
+        assert!(!doc.catalog().unwrap().has(b"Outlines"));
+        assert!(read_outline(&doc).is_empty());
+    }
+}
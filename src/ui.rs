@@ -5,6 +5,7 @@ use gtk4::{Application, ApplicationWindow, Label, Orientation, Separator};
 use std::cell::RefCell;
 use std::rc::Rc;
 use crate::engine::PdfEngine;
+use crate::annotations::{LineEnding, ShapeKind, TextStyle};
 
 // モジュール宣言
 // uiフォルダ内に各ファイルを配置している前提です
@@ -12,11 +13,54 @@ pub mod toolbar;
 pub mod main_content;
 pub mod popover_menu;
 pub mod button_event;
+pub mod clipboard;
 pub mod sidebar; // sidebarフォルダ内の mod.rs を参照します
 
 pub struct UiState {
     pub scale: f64,
     pub last_click_pos: Option<(f64, f64)>,
+    pub tool: Tool,
+    // 手書き・図形ツールの線の色と太さ、図形の塗り (None なら塗らない) と不透明度
+    pub stroke_color: (f64, f64, f64),
+    pub stroke_width: f64,
+    pub fill_color: Option<(f64, f64, f64)>,
+    pub opacity: f64,
+    // 新しく作るテキスト注釈の文字サイズと見た目 (ダイアログで最後に選んだもの)
+    pub note_font_size: f32,
+    pub note_style: TextStyle,
+    // PDFエリアをスクロールさせた先 (大きさが変わったあとも、そこまで届くよう当て直す)
+    pub scroll_target: Option<f64>,
+    // 連続表示で、スクロールやクリックで現在のページにしたページ
+    // (見えている間は、そのページの先頭までスクロールし直さない)
+    pub followed_page: Option<i32>,
+}
+
+// ページ上でのドラッグ操作の種類 (ツールバーで切り替える)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Select, // アノテーションの移動・テキスト選択
+    Ink,    // 手書き
+    Eraser, // 手書きの線を消す
+    Rectangle,
+    Ellipse,
+    Line,
+    Arrow,
+    Polygon, // クリックで頂点を置く。最初の頂点で閉じる / ダブルクリックで折れ線として確定
+}
+
+impl Tool {
+    // 図形ツールなら、作る図形と線の端の形
+    pub fn shape(&self) -> Option<(ShapeKind, (LineEnding, LineEnding))> {
+        let no_ending = (LineEnding::None, LineEnding::None);
+        match self {
+            Tool::Rectangle => Some((ShapeKind::Square, no_ending)),
+            Tool::Ellipse => Some((ShapeKind::Circle, no_ending)),
+            Tool::Line => Some((ShapeKind::Line, no_ending)),
+            Tool::Arrow => Some((ShapeKind::Line, (LineEnding::None, LineEnding::OpenArrow))),
+            Tool::Polygon => Some((ShapeKind::PolyLine, no_ending)),
+            Tool::Select | Tool::Ink | Tool::Eraser => None,
+        }
+    }
 }
 
 pub fn build(app: &Application) {
@@ -25,6 +69,15 @@ pub fn build(app: &Application) {
     let ui_state = Rc::new(RefCell::new(UiState {
         scale: 1.0,
         last_click_pos: None,
+        tool: Tool::Select,
+        stroke_color: (0.85, 0.1, 0.1),
+        stroke_width: 2.0,
+        fill_color: None,
+        opacity: 1.0,
+        note_font_size: 14.0,
+        note_style: TextStyle::default(),
+        scroll_target: None,
+        followed_page: None,
     }));
 
     // 2. ウィンドウ構築
@@ -50,6 +103,7 @@ pub fn build(app: &Application) {
     // sidebar::build は内部で各サブウィジェット(ThumbnailWidget等)を生成して返します
     let sidebar = Rc::new(sidebar::build(
         engine.clone(), 
+        ui_state.clone(),
         &drawing_area
     ));
 
@@ -1,17 +1,21 @@
 use poppler::{Document};
 use gtk4::prelude::*;
 use gtk4::{
-    ApplicationWindow, DrawingArea, TextBuffer, Label, 
-    FileChooserDialog, FileChooserAction, ResponseType,
+    AlertDialog, ApplicationWindow, DrawingArea, TextBuffer, Label, FileDialog,
+    FileChooserAction, ResponseType,
     EventControllerKey, gdk,
 };
+// ファイルを開くダイアログは GTK 4.10 で非推奨になったが、まだ置き換えていない
+#[allow(deprecated)]
+use gtk4::FileChooserDialog;
 use std::rc::Rc;
 use std::cell::RefCell;
-use crate::engine::PdfEngine;
-use crate::ui::{UiState};
+use crate::engine::{PdfEngine, ViewMode, PAGE_MARGIN};
+use crate::ui::{clipboard, main_content, Tool, UiState};
 use crate::ui::toolbar::ToolbarWidgets;
 use crate::ui::sidebar::{SidebarWidgets, ThumbnailResult, search::SearchResult};
 use crate::annotations;
+use crate::outline::{self, OutlineItem};
 use std::sync::{Arc};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use poppler::FindFlags;
@@ -37,6 +41,7 @@ pub fn setup(
         let lbl_page = widgets.label_page.clone();
         let lbl_file = filename_label.clone();
         let sb_view = sidebar.clone();
+        let ui_view = ui_state.clone();
 
         move || {
             let eng = engine.borrow();
@@ -45,7 +50,8 @@ pub fn setup(
             lbl_file.set_text(&eng.status_text());
             lbl_page.set_text(&eng.page_info()); // "1 / 10"
 
-            // 2. 再描画
+            // 2. 再描画 (連続表示では、現在のページが見えるようにスクロールする)
+            main_content::reveal_current_page(&area, &ui_view, &eng);
             area.queue_draw();
 
             // 3. テキスト更新
@@ -55,16 +61,48 @@ pub fn setup(
                 buf.set_text("");
             }
 
-            // 4. サムネイル更新
+            // 4. アノテーション一覧を更新
+            sb_view.annotations.update_annotations(&eng);
+
+            // 5. サムネイル更新
             let current_page = engine.borrow().get_current_page_number();
             sb_view.thumbnails.scroll_to_thumbnail(current_page);
+
+            // 6. 目次で現在の章を強調
+            sb_view.outline.highlight_page(&eng);
         }
     };
 
+    // ページ内のリンクをたどったら、ページを移ったときと同じく画面を更新する
+    main_content::connect_links(drawing_area, engine.clone(), ui_state.clone(), update_view.clone());
+    // 連続表示でスクロールして現在のページが変わったときも同じ
+    main_content::connect_page_follow(drawing_area, engine.clone(), ui_state.clone(), update_view.clone());
+
     // ---------------------------------------------------------
     // ボタンイベント
     // ---------------------------------------------------------
 
+    // --- Back / Forward Buttons ---
+    let eng_back = engine.clone();
+    let ui_back = ui_state.clone();
+    let area_back = drawing_area.clone();
+    let up_back = update_view.clone();
+    widgets.btn_back.connect_clicked(move |_| {
+        if step_history(&eng_back, &ui_back, &area_back, false) {
+            up_back();
+        }
+    });
+
+    let eng_forward = engine.clone();
+    let ui_forward = ui_state.clone();
+    let area_forward = drawing_area.clone();
+    let up_forward = update_view.clone();
+    widgets.btn_forward.connect_clicked(move |_| {
+        if step_history(&eng_forward, &ui_forward, &area_forward, true) {
+            up_forward();
+        }
+    });
+
     // --- Prev Button ---
     let eng_prev = engine.clone();
     let up_prev = update_view.clone();
@@ -84,20 +122,164 @@ pub fn setup(
     });
 
     // --- Zoom In ---
+    let eng_in = engine.clone();
     let ui_in = ui_state.clone();
     let area_in = drawing_area.clone();
     widgets.btn_zoom_in.connect_clicked(move |_| {
-        ui_in.borrow_mut().scale += 0.2;
-        area_in.queue_draw();
+        main_content::zoom_by(&area_in, &ui_in, &eng_in.borrow(), 0.2);
     });
 
     // --- Zoom Out ---
+    let eng_out = engine.clone();
     let ui_out = ui_state.clone();
     let area_out = drawing_area.clone();
     widgets.btn_zoom_out.connect_clicked(move |_| {
-        let mut ui = ui_out.borrow_mut();
-        ui.scale = (ui.scale - 0.2).max(0.4);
-        area_out.queue_draw();
+        main_content::zoom_by(&area_out, &ui_out, &eng_out.borrow(), -0.2);
+    });
+
+    // --- ページの並べ方 ---
+    let eng_mode = engine.clone();
+    let ui_mode = ui_state.clone();
+    let area_mode = drawing_area.clone();
+    let up_mode = update_view.clone();
+    widgets.view_mode.connect_selected_notify(move |dd| {
+        let Some(&mode) = ViewMode::ALL.get(dd.selected() as usize) else { return };
+        // 切り替えたら、現在のページの先頭から表示する
+        let page_top = {
+            let mut eng = eng_mode.borrow_mut();
+            eng.view_mode = mode;
+            let page = eng.get_current_page_number();
+            ui_mode.borrow_mut().followed_page = Some(page);
+            eng.page_offset(page, ui_mode.borrow().scale)
+        };
+        if let Some(page_top) = page_top {
+            main_content::scroll_to(&area_mode, &ui_mode, page_top - PAGE_MARGIN);
+        }
+        up_mode();
+    });
+
+    // --- ツール切り替え ---
+    for (btn, tool) in [
+        (&widgets.btn_tool_select, Tool::Select),
+        (&widgets.btn_tool_ink, Tool::Ink),
+        (&widgets.btn_tool_eraser, Tool::Eraser),
+        (&widgets.btn_tool_rectangle, Tool::Rectangle),
+        (&widgets.btn_tool_ellipse, Tool::Ellipse),
+        (&widgets.btn_tool_line, Tool::Line),
+        (&widgets.btn_tool_arrow, Tool::Arrow),
+        (&widgets.btn_tool_polygon, Tool::Polygon),
+    ] {
+        let ui_tool = ui_state.clone();
+        let eng_tool = engine.clone();
+        let area_tool = drawing_area.clone();
+        btn.connect_toggled(move |b| {
+            if b.is_active() {
+                ui_tool.borrow_mut().tool = tool;
+                // 作成途中の多角形があれば、ツールを変える前に確定する
+                eng_tool.borrow_mut().finish_shape_draft();
+                area_tool.queue_draw();
+            }
+        });
+    }
+
+    let ui_color = ui_state.clone();
+    #[allow(deprecated)]
+    widgets.stroke_color.connect_color_set(move |b| {
+        let rgba = b.rgba();
+        ui_color.borrow_mut().stroke_color = (rgba.red() as f64, rgba.green() as f64, rgba.blue() as f64);
+    });
+
+    let ui_width = ui_state.clone();
+    widgets.stroke_width.connect_value_changed(move |b| {
+        ui_width.borrow_mut().stroke_width = b.value();
+    });
+
+    // 塗りは "Fill" にチェックがあるときだけ使う
+    #[allow(deprecated)]
+    let update_fill = {
+        let ui_fill = ui_state.clone();
+        let check = widgets.fill_check.clone();
+        let color = widgets.fill_color.clone();
+        move || {
+            let rgba = color.rgba();
+            ui_fill.borrow_mut().fill_color = check.is_active()
+                .then(|| (rgba.red() as f64, rgba.green() as f64, rgba.blue() as f64));
+        }
+    };
+    let update_fill_check = update_fill.clone();
+    widgets.fill_check.connect_toggled(move |_| update_fill_check());
+    #[allow(deprecated)]
+    widgets.fill_color.connect_color_set(move |_| update_fill());
+
+    let ui_opacity = ui_state.clone();
+    widgets.opacity.connect_value_changed(move |b| {
+        ui_opacity.borrow_mut().opacity = b.value();
+    });
+
+    // 作成者
+    widgets.author.set_text(&engine.borrow().author);
+    let eng_author = engine.clone();
+    widgets.author.connect_changed(move |e| {
+        eng_author.borrow_mut().author = e.text().to_string();
+    });
+    // 設定ファイルには、Enter を押したときと入力欄を離れたときだけ保存する
+    let eng_author_activate = engine.clone();
+    widgets.author.connect_activate(move |_| eng_author_activate.borrow().save_author_setting());
+    let author_focus = gtk4::EventControllerFocus::new();
+    let eng_author_leave = engine.clone();
+    author_focus.connect_leave(move |_| eng_author_leave.borrow().save_author_setting());
+    widgets.author.add_controller(author_focus);
+
+    // 未保存マーク ("*") とファイル名をウィンドウタイトルに反映
+    let window_title = window.downgrade();
+    engine.borrow_mut().connect_title_changed(move |title| {
+        if let Some(window) = window_title.upgrade() {
+            window.set_title(Some(title));
+        }
+    });
+
+    // --- Save ---
+    let eng_save = engine.clone();
+    let up_save = update_view.clone();
+    let window_save = window.downgrade();
+    widgets.btn_save.connect_clicked(move |_| {
+        let window = match window_save.upgrade() { Some(w) => w, None => return };
+        if eng_save.borrow().get_filepath().is_none() {
+            return;
+        }
+        if save_in_place(&window, &eng_save) {
+            up_save();
+        }
+    });
+
+    // 追記保存 (増分更新) にするかどうか
+    widgets.incremental_save.set_active(engine.borrow().incremental_save);
+    let eng_incremental = engine.clone();
+    widgets.incremental_save.connect_toggled(move |check| {
+        eng_incremental.borrow_mut().incremental_save = check.is_active();
+    });
+
+    // --- Save As ---
+    let eng_save_as = engine.clone();
+    let up_save_as = update_view.clone();
+    let window_save_as = window.downgrade();
+    widgets.btn_save_as.connect_clicked(move |_| {
+        let window = match window_save_as.upgrade() { Some(w) => w, None => return };
+        if eng_save_as.borrow().get_filepath().is_none() {
+            return;
+        }
+        show_save_as_dialog(&window, eng_save_as.clone(), up_save_as.clone());
+    });
+
+    // --- 未保存のまま閉じようとした場合 ---
+    let eng_close = engine.clone();
+    window.connect_close_request(move |w| {
+        if !eng_close.borrow().is_dirty() {
+            return gtk4::glib::Propagation::Proceed;
+        }
+        let win = w.clone();
+        confirm_unsaved_changes(w, eng_close.clone(), move || win.destroy());
+        gtk4::glib::Propagation::Stop
     });
 
     // --- Open File ---
@@ -108,8 +290,10 @@ pub fn setup(
     // ファイル選択ダイアログの処理を関数化（ショートカットからも呼べるように）
     let sidebar_for_open = sidebar.clone();
     let drawing_area_open = drawing_area.clone();
+    let ui_open = ui_state.clone();
 
-    let open_action = move || {
+    #[allow(deprecated)]
+    let choose_file = move || {
         let window = match window_weak.upgrade() { Some(w) => w, None => return };
         let dialog = FileChooserDialog::new(
             Some("Select PDF"), Some(&window), FileChooserAction::Open,
@@ -123,6 +307,7 @@ pub fn setup(
         let up = up_open.clone();
         let sb = sidebar_for_open.clone();
         let area = drawing_area_open.clone();
+        let ui = ui_open.clone();
 
         dialog.connect_response(move |d, response| {
             if response == ResponseType::Accept {
@@ -135,21 +320,39 @@ pub fn setup(
                                 let eng_ref = eng.borrow();
                                 sb.annotations.update_annotations(&eng_ref);
                                 sb.thumbnails.prepare_empty_thumbnails(&eng_ref);
+                                // 目次は読み込みが終わるまで空にしておく (前のファイルのものを残さない)
+                                sb.outline.update_outline(&eng_ref);
                             } 
 
+                            // 新しいファイルは先頭から表示する
+                            ui.borrow_mut().followed_page = None;
+                            main_content::scroll_to(&area, &ui, 0.0);
+
                             // 画面更新
                             up(); 
 
                             let path_for_thread = path.to_str().unwrap().to_string();
+                            // 読み込み結果が届く前に別のファイルを開いたら、届いたものは捨てる
+                            let generation = eng.borrow().generation();
 
                             // A. アノテーション用
-                            let (annot_sender, annot_receiver) = async_channel::unbounded::<Result<Vec<annotations::AnnotationData>, String>>();
+                            let (annot_sender, annot_receiver) = async_channel::unbounded::<Result<annotations::LoadedAnnotations, String>>();
                             // B. サムネイル用
                             let (thumb_sender, thumb_receiver) = async_channel::unbounded::<ThumbnailResult>();
+                            // C. 目次用
+                            let (outline_sender, outline_receiver) = async_channel::unbounded::<Vec<OutlineItem>>();
+                            // D. 推測した目次用 (目次のないファイルだけ)
+                            let (suggest_sender, suggest_receiver) = async_channel::unbounded::<Vec<OutlineItem>>();
 
                             let eng_async = eng.clone();
                             let area_async = area.clone();
                             let sidebar_async = sb.clone(); // サムネイル更新用
+                            let sidebar_for_annot = sb.clone(); // アノテーション一覧用
+                            let sidebar_for_outline = sb.clone(); // 目次用
+                            let eng_outline = eng.clone();
+                            let sidebar_for_suggest = sb.clone();
+                            let eng_suggest = eng.clone();
+                            let eng_thumb = eng.clone();
 
                             // -------------------------------------------------------------------------
                             // 2. メインスレッド側 (受信): 2つのレシーバーを待ち受ける
@@ -158,23 +361,54 @@ pub fn setup(
                             // 受信処理 A: アノテーション
                             gtk4::glib::MainContext::default().spawn_local(async move {
                                 while let Ok(result) = annot_receiver.recv().await {
+                                    if eng_async.borrow().generation() != generation {
+                                        break;
+                                    }
                                     match result {
-                                        Ok(annots) => {
-                                            println!("Loaded {} annotations.", annots.len());
-                                            eng_async.borrow_mut().set_annotations(annots);
+                                        Ok(loaded) => {
+                                            println!("Loaded {} annotations.", loaded.annotations.len());
+                                            eng_async.borrow_mut().set_annotations(loaded);
                                             area_async.queue_draw();
-                                            // 必要ならサイドバーのアノテーションリストも更新
-                                            // sidebar_for_annot.update_annotations(...)
+                                            sidebar_for_annot.annotations.update_annotations(&eng_async.borrow());
                                         }
                                         Err(e) => eprintln!("Annot Error: {}", e),
                                     }
                                 }
                             });
 
+                            // 受信処理 C: 目次
+                            gtk4::glib::MainContext::default().spawn_local(async move {
+                                while let Ok(items) = outline_receiver.recv().await {
+                                    if eng_outline.borrow().generation() != generation {
+                                        break;
+                                    }
+                                    if eng_outline.borrow_mut().set_outline(items) {
+                                        sidebar_for_outline.outline.update_outline(&eng_outline.borrow());
+                                    }
+                                }
+                            });
+
+                            // 受信処理 D: 推測した目次
+                            gtk4::glib::MainContext::default().spawn_local(async move {
+                                while let Ok(items) = suggest_receiver.recv().await {
+                                    let mut eng = eng_suggest.borrow_mut();
+                                    if eng.generation() != generation {
+                                        break;
+                                    }
+                                    if eng.set_suggested_outline(items) {
+                                        sidebar_for_suggest.outline.update_outline(&eng);
+                                    }
+                                }
+                            });
+
                             // 受信処理 B: サムネイル
                             // ※ spawn_localはいくつでも作れます。これらは並行して動きます。
                             gtk4::glib::MainContext::default().spawn_local(async move {
                                 while let Ok(res) = thumb_receiver.recv().await {
+                                    // 受信をやめると、ワーカーは次の送信に失敗して止まる
+                                    if eng_thumb.borrow().generation() != generation {
+                                        break;
+                                    }
                                     // 生データからTexture復元
                                     let bytes = gtk4::glib::Bytes::from(&res.pixels);
                                     let texture = gtk4::gdk::MemoryTexture::new(
@@ -196,14 +430,30 @@ pub fn setup(
 
                             std::thread::spawn(move || {
                                 println!("Loading Annotations");
+                                // アノテーションと目次は、1回だけ解析した lopdf の Document から読む
+                                let lo_doc = annotations::load_document(&pdf_path);
+
                                 // === JOB 1: アノテーション読み込み ===
                                 // これは一瞬で終わるので最初にやる
-                                let annot_result = annotations::load_annotations(pdf_path.clone());
+                                let annot_result = lo_doc.as_ref()
+                                    .map_err(|e| e.clone())
+                                    .and_then(annotations::read_annotations);
                                 // 送信 (失敗したら受信側がいないので終了)
                                 if annot_sender.send_blocking(annot_result).is_err() {
                                     return; 
                                 }
 
+                                // === JOB 1.5: 目次読み込み ===
+                                // 読み込めなかったときは、アノテーションの方でエラーを出している
+                                let mut has_outline = false;
+                                if let Ok(lo_doc) = lo_doc {
+                                    let items = outline::read_outline(&lo_doc);
+                                    has_outline = !items.is_empty();
+                                    if outline_sender.send_blocking(items).is_err() {
+                                        return;
+                                    }
+                                }
+
                                 println!("Generating Thumbnails");
                                 // === JOB 2: サムネイル生成 ===
                                 // 続けて重い処理を開始
@@ -255,7 +505,7 @@ pub fn setup(
                                                     
                                                     // 1枚ごとに送信
                                                     if thumb_sender.send_blocking(res).is_err() {
-                                                        break; // アプリが終了していたらループを抜ける
+                                                        return; // 別のファイルを開いたかアプリが終了したので、目次の推測もしない
                                                     }
                                                 }
                                             }
@@ -263,6 +513,13 @@ pub fn setup(
                                         // UIスレッドを少し休ませる（カクつき防止）
                                         std::thread::sleep(std::time::Duration::from_millis(10)); 
                                     }
+
+                                    // === JOB 3: 目次の推測 ===
+                                    // 目次のないファイルだけ、全ページの文字の大きさから見出しを探す
+                                    // 受け取る側がもういなければ (別のファイルを開いた)、全ページを調べない
+                                    if !has_outline && !suggest_sender.is_closed() {
+                                        let _ = suggest_sender.send_blocking(outline::suggest_outline(&doc));
+                                    }
                                 }
                                 println!("Thumbnail generation thread done.");
                             });
@@ -275,6 +532,14 @@ pub fn setup(
         dialog.show();
     };
 
+    // 未保存の変更があれば、別のファイルを開く前に確認する
+    let eng_open_check = engine.clone();
+    let window_open_check = window.downgrade();
+    let open_action = move || {
+        let window = match window_open_check.upgrade() { Some(w) => w, None => return };
+        confirm_unsaved_changes(&window, eng_open_check.clone(), choose_file.clone());
+    };
+
     // ボタンに接続
     let open_action_clone = open_action.clone(); // クローンしてボタン用に使う
     widgets.btn_open.connect_clicked(move |_| {
@@ -289,6 +554,7 @@ pub fn setup(
 
     // Thumbnails Click
     let eng_thumb = engine.clone();
+    let ui_thumb = ui_state.clone();
     let area_thumb = drawing_area.clone();
     let up_key = update_view.clone();
     
@@ -296,7 +562,8 @@ pub fn setup(
         if let Some(r) = row {
             let idx = r.index(); // 0-based
             if let Ok(mut eng) = eng_thumb.try_borrow_mut() {
-                if eng.jump_to_page(idx) {
+                let scroll = main_content::scroll_offset(&area_thumb);
+                if eng.navigate_to(idx, scroll, ui_thumb.borrow().scale) {
                     area_thumb.queue_draw();
                 }
             }
@@ -304,6 +571,13 @@ pub fn setup(
         }
     });
 
+    // Outline Click
+    // (ページ移動は OutlineWidget 側で済んでいるので、ここではラベルや本文を追従させる)
+    let up_outline = update_view.clone();
+    sidebar.outline.list.connect_row_activated(move |_, _| {
+        up_outline();
+    });
+
     // ---------------------------------------------------------
     // 検索機能 (非同期 & ハイライト)
     // ---------------------------------------------------------
@@ -345,6 +619,7 @@ pub fn setup(
 
     // ★追加: 検索結果リストのクリックイベント処理
     let eng_search_click = engine.clone();
+    let ui_search_click = ui_state.clone();
     let sidebar_search_click = sidebar.clone();
     let area_search_click = drawing_area.clone();
     let up_key = update_view.clone();
@@ -358,7 +633,8 @@ pub fn setup(
                 // Engineを安全に借用
                 if let Ok(mut eng) = eng_search_click.try_borrow_mut() {
                     // 1. 該当ページへジャンプ（これで古いハイライトは消える）
-                    eng.jump_to_page(res.page);
+                    let scroll = main_content::scroll_offset(&area_search_click);
+                    eng.navigate_to(res.page, scroll, ui_search_click.borrow().scale);
                     
                     // 3. 再描画
                     area_search_click.queue_draw();
@@ -486,10 +762,20 @@ pub fn setup(
     // open_action は Clone ではないので、再度定義するか、Rcで包むなどの工夫が必要ですが、
     // ここではシンプルにもう一度 Dialog ロジックを書くか、Openボタンのクリックを発火させます。
     let btn_open_ref = widgets.btn_open.clone();
+    let btn_save_ref = widgets.btn_save.clone();
+    let btn_save_as_ref = widgets.btn_save_as.clone();
 
     key_controller.connect_key_pressed(move |_, keyval, _keycode, state| {
         let mut eng = eng_key.borrow_mut();
         let handled = match keyval {
+            // 移動前の位置に戻る (Alt + Left) / 進む (Alt + Right)
+            gdk::Key::Left | gdk::Key::Right if state.contains(gdk::ModifierType::ALT_MASK) => {
+                drop(eng);
+                if step_history(&eng_key, &ui_key, &area_key, keyval == gdk::Key::Right) {
+                    up_key();
+                }
+                true
+            }
             // ページ戻る (Left, K, Up)
             gdk::Key::Left | gdk::Key::k | gdk::Key::Up => {
                 if eng.prev_page() {
@@ -508,23 +794,76 @@ pub fn setup(
             }
             // ズームイン (+, =)
             gdk::Key::plus | gdk::Key::equal => {
-                ui_key.borrow_mut().scale += 0.2;
-                area_key.queue_draw();
+                main_content::zoom_by(&area_key, &ui_key, &eng, 0.2);
                 true
             }
             // ズームアウト (-)
             gdk::Key::minus => {
-                let mut ui = ui_key.borrow_mut();
-                ui.scale = (ui.scale - 0.2).max(0.4);
-                area_key.queue_draw();
+                main_content::zoom_by(&area_key, &ui_key, &eng, -0.2);
                 true
             }
             // ファイルを開く (Ctrl + O)
             gdk::Key::o if state.contains(gdk::ModifierType::CONTROL_MASK) => {
                 // ボタンのクリックイベントを発火させる（ロジックを再利用）
+                // (ハンドラ側でEngineを借用するので先に解放)
+                drop(eng);
                 btn_open_ref.emit_clicked();
                 true
             }
+            // 保存 (Ctrl + S) / 別名で保存 (Ctrl + Shift + S)
+            gdk::Key::s | gdk::Key::S if state.contains(gdk::ModifierType::CONTROL_MASK) => {
+                drop(eng);
+                if state.contains(gdk::ModifierType::SHIFT_MASK) {
+                    btn_save_as_ref.emit_clicked();
+                } else {
+                    btn_save_ref.emit_clicked();
+                }
+                true
+            }
+            // 選択中のアノテーションを削除 (Delete)
+            gdk::Key::Delete | gdk::Key::KP_Delete => {
+                let Some(id) = eng.active_annotation_id.clone() else { return gtk4::glib::Propagation::Proceed };
+                eng.delete_annotation(&id);
+                drop(eng);
+                up_key();
+                true
+            }
+            // 複製 (Ctrl + D)
+            gdk::Key::d if state.contains(gdk::ModifierType::CONTROL_MASK) => {
+                let Some(id) = eng.active_annotation_id.clone() else { return gtk4::glib::Propagation::Proceed };
+                eng.duplicate_annotation(&id);
+                drop(eng);
+                up_key();
+                true
+            }
+            // コピー (Ctrl + C) / 切り取り (Ctrl + X)
+            gdk::Key::c | gdk::Key::x if state.contains(gdk::ModifierType::CONTROL_MASK) => {
+                drop(eng);
+                let copied = clipboard::copy_active_annotation(&eng_key, &area_key.clipboard(), keyval == gdk::Key::x);
+                if copied && keyval == gdk::Key::x {
+                    up_key();
+                }
+                copied
+            }
+            // 貼り付け (Ctrl + V)
+            gdk::Key::v if state.contains(gdk::ModifierType::CONTROL_MASK) => {
+                drop(eng);
+                clipboard::paste(eng_key.clone(), &area_key.clipboard(), None, up_key.clone());
+                true
+            }
+            // 元に戻す (Ctrl + Z) / やり直し (Ctrl + Shift + Z)
+            gdk::Key::z | gdk::Key::Z if state.contains(gdk::ModifierType::CONTROL_MASK) => {
+                let changed = if state.contains(gdk::ModifierType::SHIFT_MASK) {
+                    eng.redo()
+                } else {
+                    eng.undo()
+                };
+                if changed {
+                    drop(eng);
+                    up_key();
+                }
+                true
+            }
             gdk::Key::f if state.contains(gdk::ModifierType::CONTROL_MASK) => {
                 // フォーカスを検索エントリに移す
                 sb_key.stack.set_visible_child_name("search");
@@ -542,4 +881,142 @@ pub fn setup(
     });
 
     window.add_controller(key_controller);
-}
\ No newline at end of file
+}
+
+// 履歴をたどって戻る・進む (移動したら true)
+fn step_history(
+    engine: &Rc<RefCell<PdfEngine>>,
+    ui_state: &Rc<RefCell<UiState>>,
+    drawing_area: &DrawingArea,
+    forward: bool,
+) -> bool {
+    let scroll = main_content::scroll_offset(drawing_area);
+    let scale = ui_state.borrow().scale;
+    let position = if forward {
+        engine.borrow_mut().go_forward(scroll, scale)
+    } else {
+        engine.borrow_mut().go_back(scroll, scale)
+    };
+    match position {
+        Some(position) => {
+            main_content::restore_view(drawing_area, ui_state, position);
+            true
+        }
+        None => false,
+    }
+}
+
+// 上書き保存 (成功したら true)
+fn save_in_place(window: &ApplicationWindow, engine: &Rc<RefCell<PdfEngine>>) -> bool {
+    let result = engine.borrow_mut().save();
+    match result {
+        Ok(signed) => {
+            if signed {
+                show_signed_save_notice(window);
+            }
+            true
+        }
+        Err(e) => {
+            show_save_error(window, &e);
+            false
+        }
+    }
+}
+
+// 保存に失敗したことを知らせる (変更は未保存のまま残る)
+fn show_save_error(window: &ApplicationWindow, error: &str) {
+    eprintln!("Save Error: {}", error);
+    let dialog = AlertDialog::builder()
+        .modal(true)
+        .message("Could not save the file")
+        .detail(error)
+        .build();
+    dialog.show(Some(window));
+}
+
+// 署名済みのため、変更を追記して保存したことを知らせる (ファイルごとに最初の1回だけ)
+fn show_signed_save_notice(window: &ApplicationWindow) {
+    let dialog = AlertDialog::builder()
+        .modal(true)
+        .message("Changes were appended to the file")
+        .detail("This PDF is digitally signed, so it was saved incrementally to keep the signature valid.")
+        .build();
+    dialog.show(Some(window));
+}
+
+// 保存先を選んで別名保存
+fn show_save_as_dialog(
+    window: &ApplicationWindow,
+    engine: Rc<RefCell<PdfEngine>>,
+    on_saved: impl Fn() + 'static,
+) {
+    let filter = gtk4::FileFilter::new();
+    filter.set_name(Some("PDF"));
+    filter.add_mime_type("application/pdf");
+    let filters = gtk4::gio::ListStore::new::<gtk4::FileFilter>();
+    filters.append(&filter);
+    let dialog = FileDialog::builder()
+        .title("Save PDF As")
+        .modal(true)
+        .filters(&filters)
+        .default_filter(&filter)
+        .build();
+
+    // 現在のファイルと同じ場所・名前を初期値にする
+    if let Some(path) = engine.borrow().get_filepath() {
+        dialog.set_initial_file(Some(&gtk4::gio::File::for_path(path)));
+    }
+
+    let parent = window.clone();
+    dialog.save(Some(window), None::<&gtk4::gio::Cancellable>, move |file| {
+        let Some(path) = file.ok().and_then(|f| f.path()) else { return };
+        let result = engine.borrow_mut().save_as(path);
+        match result {
+            Ok(signed) => {
+                if signed {
+                    show_signed_save_notice(&parent);
+                }
+                on_saved();
+            }
+            Err(e) => show_save_error(&parent, &e),
+        }
+    });
+}
+
+// 未保存の変更があれば保存するか確認し、続行してよければ on_continue を呼ぶ
+fn confirm_unsaved_changes(
+    window: &ApplicationWindow,
+    engine: Rc<RefCell<PdfEngine>>,
+    on_continue: impl Fn() + 'static,
+) {
+    if !engine.borrow().is_dirty() {
+        on_continue();
+        return;
+    }
+
+    // ボタンの並び (choose の結果はこの位置)
+    const CANCEL: i32 = 0;
+    const DONT_SAVE: i32 = 1;
+    const SAVE: i32 = 2;
+    let dialog = AlertDialog::builder()
+        .modal(true)
+        .message("Save changes to the annotations?")
+        .detail("Unsaved annotations will be lost.")
+        .buttons(["Cancel", "Don't Save", "Save"])
+        .cancel_button(CANCEL)
+        .default_button(SAVE)
+        .build();
+
+    let parent = window.clone();
+    dialog.choose(Some(window), None::<&gtk4::gio::Cancellable>, move |response| {
+        match response {
+            Ok(SAVE) => {
+                if save_in_place(&parent, &engine) {
+                    on_continue();
+                }
+            }
+            Ok(DONT_SAVE) => on_continue(),
+            _ => {}
+        }
+    });
+}
@@ -0,0 +1,94 @@
+// src/ui/clipboard.rs
+// アノテーションのコピー・切り取り・貼り付け (GDK のクリップボード経由)
+// 注釈は専用の MIME タイプで「注釈だけを載せたPDF」として載せるので、別の Margium ウィンドウにも貼り付けられる
+// 他のアプリ向けには注釈の本文をテキストとしても載せておく
+
+use gtk4::prelude::*;
+use gtk4::{gdk, gio, glib};
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::annotations;
+use crate::engine::PdfEngine;
+
+const ANNOTATION_MIME_TYPE: &str = "application/x-margium-annotation";
+// 位置の指定が無いときにテキストを貼り付ける場所 (ページ左上寄り、PDF座標)
+const PLAIN_TEXT_POSITION: (f64, f64) = (40.0, 40.0);
+
+// 選択中の注釈をクリップボードに載せる (cut なら削除も行う)。載せたら true
+pub fn copy_active_annotation(engine: &Rc<RefCell<PdfEngine>>, clipboard: &gdk::Clipboard, cut: bool) -> bool {
+    let mut eng = engine.borrow_mut();
+    let Some(id) = eng.active_annotation_id.clone() else { return false };
+    let Some((pdf, text)) = eng.copy_annotation(&id) else { return false };
+
+    let provider = gdk::ContentProvider::new_union(&[
+        gdk::ContentProvider::for_bytes(ANNOTATION_MIME_TYPE, &glib::Bytes::from_owned(pdf)),
+        gdk::ContentProvider::for_value(&text.to_value()),
+    ]);
+    if let Err(e) = clipboard.set_content(Some(&provider)) {
+        eprintln!("Clipboard Error: {}", e);
+        return false;
+    }
+
+    if cut {
+        eng.delete_annotation(&id);
+    }
+    true
+}
+
+// クリップボードの内容を現在のページに貼り付ける
+// 注釈があればそれを、無ければテキストを新しい注釈として貼る。at は貼り付け位置 (UI座標)
+// 読み込みは非同期なので、貼り付け終わったら on_pasted を呼ぶ
+pub fn paste(
+    engine: Rc<RefCell<PdfEngine>>,
+    clipboard: &gdk::Clipboard,
+    at: Option<(f64, f64)>,
+    on_pasted: impl Fn() + 'static,
+) {
+    let clipboard = clipboard.clone();
+    glib::MainContext::default().spawn_local(async move {
+        let pasted = if clipboard.formats().contain_mime_type(ANNOTATION_MIME_TYPE) {
+            match read_annotations(&clipboard).await {
+                Ok(pasted) => engine.borrow_mut().paste_annotations(pasted, at).is_some(),
+                Err(e) => {
+                    eprintln!("Paste Error: {}", e);
+                    false
+                }
+            }
+        } else {
+            match clipboard.read_text_future().await {
+                Ok(Some(text)) if !text.trim().is_empty() => {
+                    let (x, y) = at.unwrap_or(PLAIN_TEXT_POSITION);
+                    engine.borrow_mut().add_annotation(text.trim(), x, y).is_ok()
+                }
+                _ => false,
+            }
+        };
+        if pasted {
+            on_pasted();
+        }
+    });
+}
+
+async fn read_annotations(clipboard: &gdk::Clipboard) -> Result<Vec<annotations::AnnotationData>, String> {
+    let (stream, _) = clipboard
+        .read_future(&[ANNOTATION_MIME_TYPE], glib::Priority::DEFAULT)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let output = gio::MemoryOutputStream::new_resizable();
+    output
+        .splice_future(
+            &stream,
+            gio::OutputStreamSpliceFlags::CLOSE_SOURCE | gio::OutputStreamSpliceFlags::CLOSE_TARGET,
+            glib::Priority::DEFAULT,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let bytes = output.steal_as_bytes();
+    let pasted = annotations::annotations_from_clipboard_pdf(&bytes)?;
+    if pasted.is_empty() {
+        return Err("No annotation in clipboard".to_string());
+    }
+    Ok(pasted)
+}
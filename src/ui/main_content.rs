@@ -2,12 +2,39 @@ use gtk4::prelude::*;
 use gtk4::{
     Box as GtkBox, DrawingArea, Orientation, Paned, ScrolledWindow, 
     TextView, TextBuffer, Separator, 
-    GestureClick, EventControllerScroll, EventControllerScrollFlags, GestureDrag
+    GestureClick, GestureDrag, GestureStylus, gdk,
+    EventControllerMotion,
 };
 use std::rc::Rc;
-use std::cell::RefCell;
-use crate::engine::PdfEngine;
-use crate::ui::UiState;
+use std::cell::{Cell, RefCell};
+use crate::engine::{LinkTarget, PdfEngine, ResizeHandle, ViewMode, ViewPosition, PAGE_MARGIN};
+use crate::annotations::{AnnotationKind, DEFAULT_PRESSURE};
+use crate::ui::{Tool, UiState};
+
+// 消しゴムの半径 (画面上のピクセル)
+const ERASER_RADIUS: f64 = 8.0;
+// 多角形の最初の頂点にこの距離まで近づけて離すと閉じる (画面上のピクセル)
+const POLYGON_CLOSE_DISTANCE: f64 = 8.0;
+// 枠の大きさを変えるつまみを掴める距離 (画面上のピクセル)
+const RESIZE_HANDLE_DISTANCE: f64 = 4.0;
+// ズームアウトの下限
+const MIN_SCALE: f64 = 0.4;
+
+// UI -> PDF座標変換のヘルパー関数 (点があるページと、そのページでのPDF座標)
+pub fn convert_to_pdf_coords(ui_x: f64, ui_y: f64, eng: &PdfEngine, ui_scale: f64, area_w: f64) -> (i32, f64, f64) {
+    eng.page_at(ui_x, ui_y, area_w, ui_scale)
+        .unwrap_or((eng.get_current_page_number(), 0.0, 0.0))
+}
+
+// 連続表示・見開きで別のページを触ったら、そのページを現在のページにする
+// (注釈の操作やテキスト選択は現在のページに対して行う)
+pub fn focus_page_at(ui_x: f64, ui_y: f64, eng: &mut PdfEngine, ui_scale: f64, area_w: f64) -> (f64, f64) {
+    let (page, pdf_x, pdf_y) = convert_to_pdf_coords(ui_x, ui_y, eng, ui_scale, area_w);
+    if page != eng.get_current_page_number() {
+        eng.jump_to_page(page);
+    }
+    (pdf_x, pdf_y)
+}
 
 // 戻り値:
 // 1. GtkBox: レイアウト全体の親コンテナ
@@ -67,7 +94,7 @@ pub fn build(
     // ロジック設定
     // ============================================================
 
-    // 1. 描画ロジック (単一ページ用)
+    // 1. 描画ロジック
     let eng_draw = engine.clone();
     let ui_draw = ui_state.clone();
     
@@ -75,15 +102,19 @@ pub fn build(
         let eng = eng_draw.borrow();
         let ui = ui_draw.borrow();
         
-        // エンジンに描画させる
-        eng.draw(ctx, w as f64, h as f64, ui.scale);
+        // エンジンに描画させる (連続表示では、画面に見えているページだけを描く)
+        let visible = scrolled_window(area)
+            .map(|scroll| scroll.vadjustment())
+            .filter(|adj| adj.page_size() > 0.0)
+            .map_or((0.0, h as f64), |adj| (adj.value(), adj.value() + adj.page_size()));
+        eng.draw(ctx, w as f64, h as f64, ui.scale, visible);
 
-        // ★重要: 単一ページモードにおけるサイズ調整
+        // ★重要: 表示するページ全体に合わせたサイズ調整
         // ズーム倍率に合わせて DrawingArea のサイズ（content_size）を更新する。
         // これにより、拡大時に自動的にスクロールバーが表示されるようになる。
-        if let Some((pdf_w, pdf_h)) = eng.get_page_size() {
-            let req_w = (pdf_w * ui.scale) as i32;
-            let req_h = (pdf_h * ui.scale) as i32 + 40; // 上下余白分
+        if let Some((layout_w, layout_h)) = eng.layout_size(ui.scale) {
+            let req_w = layout_w as i32;
+            let req_h = layout_h as i32; // 上下余白込み
             
             // 無限ループを防ぐため、サイズが異なるときだけセット
             if area.content_width() != req_w || area.content_height() != req_h {
@@ -93,46 +124,72 @@ pub fn build(
         }
     });
 
-    // UI -> PDF座標変換のヘルパー関数
-    let convert_to_pdf_coords = |ui_x: f64, ui_y: f64, eng: &PdfEngine, ui_scale: f64, area_w: f64| -> (f64, f64) {
-        if let Some((pdf_w, _)) = eng.get_page_size() {
-            let draw_w = pdf_w * ui_scale;
-            let offset_x = if area_w > draw_w { (area_w - draw_w) / 2.0 } else { 0.0 };
-            let offset_y = 20.0;
-            
-            let pdf_x = (ui_x - offset_x) / ui_scale;
-            let pdf_y = (ui_y - offset_y) / ui_scale;
-            (pdf_x, pdf_y)
-        } else {
-            (0.0, 0.0)
-        }
-    };
-
     // 2. クリック
     let click_ctrl = GestureClick::new();
     let eng_click = engine.clone();
     let ui_click = ui_state.clone();
     let area_click = drawing_area.clone();
+    // 直前の押下が何回目のクリックか (ダブルクリックの2回目でドラッグを始めないために、ドラッグ側で見る)
+    let press_count = Rc::new(Cell::new(0));
+    let press_count_click = press_count.clone();
 
-    click_ctrl.connect_pressed(move |_, _, x, y| {
+    click_ctrl.connect_pressed(move |_, n_press, x, y| {
+        press_count_click.set(n_press);
         let mut eng = eng_click.borrow_mut();
-        let scale = ui_click.borrow().scale;
+        let ui = ui_click.borrow();
+        let scale = ui.scale;
         let area_w = area_click.width() as f64;
+
+        // 選択ツール以外では、クリックでアノテーションを選ばない (描画はドラッグ側で扱う)
+        if ui.tool != Tool::Select {
+            // 多角形ツールのダブルクリックで、閉じずに折れ線として確定
+            if ui.tool == Tool::Polygon && n_press == 2 {
+                eng.finish_shape_draft();
+                area_click.queue_draw();
+            }
+            area_click.grab_focus();
+            return;
+        }
         
-        let (pdf_x, pdf_y) = convert_to_pdf_coords(x, y, &eng, scale, area_w);
+        let (pdf_x, pdf_y) = focus_page_at(x, y, &mut eng, scale, area_w);
+
+        // クリックでテキスト選択は解除する (ドラッグなら drag_update で選び直される)
+        eng.clear_text_selection();
 
         // 1. まずクリックした位置にアノテーションがあるか判定
-        if let Some(hit_id) = eng.hit_test_annotation(pdf_x, pdf_y) {
-            eng.active_annotation_id = Some(hit_id); // 選択
-        } else {
-            eng.active_annotation_id = None; // 選択解除
+        // (選択中の注釈のつまみは枠の少し外側にあるので、そこを押しても選択は外さない)
+        if eng.resize_handle_at(pdf_x, pdf_y, RESIZE_HANDLE_DISTANCE / scale).is_none() {
+            if let Some(hit_id) = eng.hit_test_annotation(pdf_x, pdf_y) {
+                eng.active_annotation_id = Some(hit_id); // 選択
+            } else {
+                eng.active_annotation_id = None; // 選択解除
+            }
         }
         area_click.queue_draw();
         area_click.grab_focus();
     });
+
+    // 付箋のアイコンをクリックしたら、ポップアップを開閉する
+    // (ドラッグで動かしたときは released が来ないので開閉しない)
+    let eng_release = engine.clone();
+    let ui_release = ui_state.clone();
+    let area_release = drawing_area.clone();
+    click_ctrl.connect_released(move |_, n_press, x, y| {
+        let ui = ui_release.borrow();
+        if ui.tool != Tool::Select || n_press != 1 {
+            return;
+        }
+        let mut eng = eng_release.borrow_mut();
+        let (_, pdf_x, pdf_y) = convert_to_pdf_coords(x, y, &eng, ui.scale, area_release.width() as f64);
+        if eng.toggle_note_at(pdf_x, pdf_y) {
+            area_release.queue_draw();
+        }
+    });
     drawing_area.add_controller(click_ctrl);
 
-    // 3. ドラッグ＆ドロップ (移動)
+    // 3. ドラッグ＆ドロップ
+    // 選択ツール: アノテーション上なら移動、それ以外はテキスト選択
+    // 手書きツール: 線を書く / 消しゴム: なぞった線を消す
     let drag_ctrl = GestureDrag::new();
     let eng_drag = engine.clone();
     let ui_drag = ui_state.clone();
@@ -141,48 +198,387 @@ pub fn build(
     // ドラッグ開始時の元の座標を記憶する用
     let start_pos = Rc::new(RefCell::new((0.0, 0.0)));
     let start_pos_clone = start_pos.clone();
+    // 移動以外の操作 (テキスト選択・手書き・消しゴム) ならドラッグ開始位置 (PDF座標)
+    let drag_origin: Rc<RefCell<Option<(f64, f64)>>> = Rc::new(RefCell::new(None));
+    let drag_origin_clone = drag_origin.clone();
+    // 枠のつまみを掴んでいるなら、そのつまみとドラッグ開始時の枠の大きさ
+    let resizing: Rc<RefCell<Option<(ResizeHandle, f64, f64)>>> = Rc::new(RefCell::new(None));
+    let resizing_clone = resizing.clone();
+
+    // 筆圧はペンの入力を GestureStylus で受け取って覚えておき、ドラッグ側で使う
+    // (ペンを離しているときやマウスでは取れないので None にして既定値を使う)
+    let stylus_pressure: Rc<Cell<Option<f64>>> = Rc::new(Cell::new(None));
+    let stylus = GestureStylus::new();
+    let pressure_down = stylus_pressure.clone();
+    stylus.connect_down(move |gesture, _, _| pressure_down.set(gesture.axis(gdk::AxisUse::Pressure)));
+    let pressure_motion = stylus_pressure.clone();
+    stylus.connect_motion(move |gesture, _, _| pressure_motion.set(gesture.axis(gdk::AxisUse::Pressure)));
+    let pressure_up = stylus_pressure.clone();
+    stylus.connect_up(move |_, _, _| pressure_up.set(None));
+    // ドラッグより先に筆圧を受け取れるよう、先に追加する
+    drawing_area.add_controller(stylus);
+    let pressure_of = move || stylus_pressure.get().unwrap_or(DEFAULT_PRESSURE);
+    let pressure_begin = pressure_of.clone();
 
-    drag_ctrl.connect_drag_begin(move |_, x, y| {
+    drag_ctrl.connect_drag_begin(move |gesture, x, y| {
         let mut eng = eng_drag.borrow_mut();
-        let scale = ui_drag.borrow().scale;
+        let ui = ui_drag.borrow();
+        // 多角形を確定するダブルクリックの2回目の押下では、新しい図形を始めない
+        if ui.tool == Tool::Polygon && press_count.get() >= 2 {
+            gesture.set_state(gtk4::EventSequenceState::Denied);
+            return;
+        }
         let area_w = area_drag.width() as f64;
         
-        let (pdf_x, pdf_y) = convert_to_pdf_coords(x, y, &eng, scale, area_w);
+        let (pdf_x, pdf_y) = focus_page_at(x, y, &mut eng, ui.scale, area_w);
+        // ドラッグ1回分の変更 (移動・大きさ変更・手書き・消しゴム) は、まとめて1回で元に戻す
+        eng.begin_edit_group();
 
-        // ドラッグ開始位置にアノテーションがあれば選択し、その初期座標を記憶
-        if let Some(hit_id) = eng.hit_test_annotation(pdf_x, pdf_y) {
-            eng.active_annotation_id = Some(hit_id.clone());
-            if let Some(ann) = eng.annotations.iter().find(|a| a.id == hit_id) {
-                *start_pos_clone.borrow_mut() = (ann.x, ann.y);
+        match ui.tool {
+            Tool::Ink => {
+                eng.begin_ink_stroke(pdf_x, pdf_y, pressure_begin(), ui.stroke_color, ui.stroke_width);
+                *drag_origin_clone.borrow_mut() = Some((pdf_x, pdf_y));
+            }
+            Tool::Rectangle | Tool::Ellipse | Tool::Line | Tool::Arrow | Tool::Polygon => {
+                // 多角形の続きの頂点なら、作成中の図形の最後の点をここに置く
+                if eng.has_shape_draft() {
+                    eng.update_shape_draft(pdf_x, pdf_y);
+                } else if let Some((shape, line_endings)) = ui.tool.shape() {
+                    let kind = AnnotationKind::Shape {
+                        shape,
+                        points: vec![(pdf_x, pdf_y), (pdf_x, pdf_y)],
+                        width: ui.stroke_width,
+                        fill: ui.fill_color,
+                        opacity: ui.opacity,
+                        line_endings,
+                    };
+                    eng.begin_shape(kind, ui.stroke_color);
+                }
+                *drag_origin_clone.borrow_mut() = Some((pdf_x, pdf_y));
+            }
+            Tool::Eraser => {
+                eng.erase_ink_at(pdf_x, pdf_y, ERASER_RADIUS / ui.scale);
+                *drag_origin_clone.borrow_mut() = Some((pdf_x, pdf_y));
+            }
+            Tool::Select => {
+                // 選択中の注釈のつまみを掴んだら、枠の大きさを変える
+                *resizing_clone.borrow_mut() = eng.resize_handle_at(pdf_x, pdf_y, RESIZE_HANDLE_DISTANCE / ui.scale);
+                if resizing_clone.borrow().is_some() {
+                    *drag_origin_clone.borrow_mut() = None;
+                } else if let Some(hit_id) = eng.hit_test_annotation(pdf_x, pdf_y) {
+                    // ドラッグ開始位置にアノテーションがあれば選択し、その初期座標を記憶
+                    eng.active_annotation_id = Some(hit_id.clone());
+                    if let Some(ann) = eng.annotations.iter().find(|a| a.id == hit_id) {
+                        *start_pos_clone.borrow_mut() = (ann.x, ann.y);
+                    }
+                    *drag_origin_clone.borrow_mut() = None;
+                } else {
+                    eng.active_annotation_id = None;
+                    *drag_origin_clone.borrow_mut() = Some((pdf_x, pdf_y));
+                }
             }
         }
+        area_drag.queue_draw();
     });
 
     let eng_drag_update = engine.clone();
     let ui_drag_update = ui_state.clone();
     let area_drag_update = drawing_area.clone();
     let start_pos_update = start_pos.clone();
+    let drag_origin_update = drag_origin.clone();
+    let resizing_update = resizing.clone();
 
     drag_ctrl.connect_drag_update(move |_, offset_x, offset_y| {
         let mut eng = eng_drag_update.borrow_mut();
-        let scale = ui_drag_update.borrow().scale;
+        let ui = ui_drag_update.borrow();
+        let scale = ui.scale;
 
         // UI上の移動量をPDF上の移動量にスケール変換
         let pdf_dx = offset_x / scale;
         let pdf_dy = offset_y / scale;
 
-        if let Some(id) = eng.active_annotation_id.clone() {
+        if let Some((origin_x, origin_y)) = *drag_origin_update.borrow() {
+            let (pdf_x, pdf_y) = (origin_x + pdf_dx, origin_y + pdf_dy);
+            match ui.tool {
+                Tool::Ink => eng.extend_ink_stroke(pdf_x, pdf_y, pressure_of()),
+                Tool::Eraser => {
+                    eng.erase_ink_at(pdf_x, pdf_y, ERASER_RADIUS / scale);
+                }
+                Tool::Select => eng.select_text(origin_x, origin_y, pdf_x, pdf_y),
+                Tool::Rectangle | Tool::Ellipse | Tool::Line | Tool::Arrow | Tool::Polygon => {
+                    eng.update_shape_draft(pdf_x, pdf_y);
+                }
+            }
+            area_drag_update.queue_draw();
+        } else if let Some(id) = eng.active_annotation_id.clone() {
+            if let Some((handle, start_w, start_h)) = *resizing_update.borrow() {
+                let (width, height) = match handle {
+                    ResizeHandle::Right => (Some(start_w + pdf_dx), None),
+                    ResizeHandle::Bottom => (None, Some(start_h + pdf_dy)),
+                    ResizeHandle::BottomRight => (Some(start_w + pdf_dx), Some(start_h + pdf_dy)),
+                };
+                eng.resize_annotation(&id, width, height);
+                area_drag_update.queue_draw();
+                return;
+            }
             let (start_x, start_y) = *start_pos_update.borrow();
             eng.move_annotation(&id, start_x + pdf_dx, start_y + pdf_dy);
             area_drag_update.queue_draw();
         }
     });
-    drawing_area.add_controller(drag_ctrl);
 
-    // 4. スクロールコントローラーの作成
-    // ロジック（ページ送り）はここには書かず、コントローラーだけ作って親(ui.rs)に渡す
-    let scroll_ctrl = EventControllerScroll::new(EventControllerScrollFlags::VERTICAL);
-    pdf_scroll_window.add_controller(scroll_ctrl.clone());
+    let eng_drag_end = engine.clone();
+    let ui_drag_end = ui_state.clone();
+    let area_drag_end = drawing_area.clone();
+    let resizing_end = resizing.clone();
+    drag_ctrl.connect_drag_end(move |_, _, _| {
+        let mut eng = eng_drag_end.borrow_mut();
+        *resizing_end.borrow_mut() = None;
+        // 書いている途中の線・図形があれば確定する
+        eng.finish_ink_stroke();
+        eng.place_shape_point(POLYGON_CLOSE_DISTANCE / ui_drag_end.borrow().scale);
+        eng.end_edit_group();
+        area_drag_end.queue_draw();
+    });
+    drawing_area.add_controller(drag_ctrl);
 
     (container, drawing_area, text_buffer)
 }
+
+
+// ページ内のリンク (目次・引用など) をたどる
+// 選択ツールのときだけ、リンクの上でカーソルを変え、クリックで移動する
+// ページを移ったあとの画面更新 (ラベル・本文・サイドバー) は on_jump に任せる
+pub fn connect_links(
+    drawing_area: &DrawingArea,
+    engine: Rc<RefCell<PdfEngine>>,
+    ui_state: Rc<RefCell<UiState>>,
+    on_jump: impl Fn() + 'static,
+) {
+    // 1. リンクの上ではカーソルを指の形にする
+    let motion_ctrl = EventControllerMotion::new();
+    let eng_motion = engine.clone();
+    let ui_motion = ui_state.clone();
+    let area_motion = drawing_area.clone();
+    motion_ctrl.connect_motion(move |_, x, y| {
+        let (Ok(eng), Ok(ui)) = (eng_motion.try_borrow(), ui_motion.try_borrow()) else { return };
+        let (page, pdf_x, pdf_y) = convert_to_pdf_coords(x, y, &eng, ui.scale, area_motion.width() as f64);
+        // 注釈は現在のページのものだけを調べる (別のページは触ったときに現在のページになる)
+        let on_annotation = page == eng.get_current_page_number() && eng.hit_test_annotation(pdf_x, pdf_y).is_some();
+        let on_link = ui.tool == Tool::Select
+            && !on_annotation
+            && eng.link_at(page, pdf_x, pdf_y).is_some();
+        area_motion.set_cursor_from_name(if on_link { Some("pointer") } else { None });
+    });
+    drawing_area.add_controller(motion_ctrl);
+
+    // 2. クリックでリンク先へ (注釈の上なら注釈の操作を優先する)
+    let click_ctrl = GestureClick::new();
+    let area_click = drawing_area.clone();
+    click_ctrl.connect_released(move |_, n_press, x, y| {
+        let (target, scale) = {
+            let eng = engine.borrow();
+            let ui = ui_state.borrow();
+            if ui.tool != Tool::Select || n_press != 1 {
+                return;
+            }
+            let (page, pdf_x, pdf_y) = convert_to_pdf_coords(x, y, &eng, ui.scale, area_click.width() as f64);
+            if page == eng.get_current_page_number() && eng.hit_test_annotation(pdf_x, pdf_y).is_some() {
+                return;
+            }
+            (eng.link_at(page, pdf_x, pdf_y), ui.scale)
+        };
+
+        match target {
+            Some(LinkTarget::Page(page, top)) => {
+                // 同じページ内のリンクはスクロールだけ (履歴にも残らない)
+                if engine.borrow_mut().navigate_to(page, scroll_offset(&area_click), scale) {
+                    on_jump();
+                }
+                // 行き先の位置が画面の上端に来るようにスクロールする (指定がなければページの先頭)
+                let Some(page_top) = engine.borrow().page_offset(page, scale) else { return };
+                let offset = top.map(|t| page_top + t * scale).unwrap_or(page_top - PAGE_MARGIN);
+                scroll_to(&area_click, &ui_state, offset);
+            }
+            Some(LinkTarget::Uri(uri)) => confirm_open_uri(&area_click, uri),
+            None => {}
+        }
+    });
+    drawing_area.add_controller(click_ctrl);
+}
+
+// 連続表示で、スクロール位置から現在のページを決める
+// スクロールしたら見えるようになったページを描き、画面の中央のページを現在のページにして on_page_changed を呼ぶ
+// (連続表示・見開きで、ページをクリックして現在のページが変わったときも同じく呼ぶ)
+pub fn connect_page_follow(
+    drawing_area: &DrawingArea,
+    engine: Rc<RefCell<PdfEngine>>,
+    ui_state: Rc<RefCell<UiState>>,
+    on_page_changed: impl Fn() + 'static,
+) {
+    let Some(scroll) = scrolled_window(drawing_area) else { return };
+    let adjustment = scroll.vadjustment();
+    let on_page_changed = Rc::new(on_page_changed);
+
+    // 1. DrawingArea の大きさが変わったら、届かなかったスクロール先へ当て直す
+    let ui_changed = ui_state.clone();
+    adjustment.connect_changed(move |adj| {
+        let target = ui_changed.try_borrow().ok().and_then(|ui| ui.scroll_target);
+        if let Some(target) = target.filter(|target| (adj.value() - target).abs() >= 0.5) {
+            adj.set_value(target);
+        }
+    });
+
+    // 2. スクロールに合わせて描き直し、現在のページを追従させる
+    let eng_scroll = engine.clone();
+    let ui_scroll = ui_state.clone();
+    let area_scroll = drawing_area.clone();
+    let on_scroll = on_page_changed.clone();
+    adjustment.connect_value_changed(move |adj| {
+        let page = {
+            let (Ok(eng), Ok(mut ui)) = (eng_scroll.try_borrow(), ui_scroll.try_borrow_mut()) else { return };
+            let continuous = eng.view_mode == ViewMode::Continuous;
+            if continuous {
+                area_scroll.queue_draw();
+            }
+
+            // 自分でスクロールさせた分 (行き先か、届く限り近く) では現在のページを変えない
+            // ユーザーがスクロールしたら、行き先は忘れる
+            let max = (adj.upper() - adj.page_size()).max(0.0);
+            if ui.scroll_target.is_some_and(|target| (adj.value() - target.clamp(0.0, max)).abs() < 0.5) {
+                return;
+            }
+            ui.scroll_target = None;
+            if !continuous {
+                return;
+            }
+
+            let Some(page) = eng.page_in_view(adj.value(), adj.page_size(), ui.scale) else { return };
+            if page == eng.get_current_page_number() {
+                return;
+            }
+            ui.followed_page = Some(page);
+            page
+        };
+        let Ok(mut eng) = eng_scroll.try_borrow_mut() else { return };
+        eng.jump_to_page(page);
+        drop(eng);
+        on_scroll();
+    });
+
+    // 3. 別のページ (見開きのもう片方など) をクリックして現在のページが変わったら、ラベルなどを追従させる
+    // (現在のページを変えるのは build のクリック・ドラッグのハンドラなので、その後で調べる)
+    let click_ctrl = GestureClick::new();
+    click_ctrl.set_button(0);
+    click_ctrl.connect_pressed(move |_, _, _, _| {
+        let engine = engine.clone();
+        let ui_state = ui_state.clone();
+        let on_click = on_page_changed.clone();
+        gtk4::glib::idle_add_local_once(move || {
+            let page = engine.borrow().get_current_page_number();
+            let mut ui = ui_state.borrow_mut();
+            if engine.borrow().view_mode == ViewMode::SinglePage || ui.followed_page == Some(page) {
+                return;
+            }
+            ui.followed_page = Some(page);
+            drop(ui);
+            on_click();
+        });
+    });
+    drawing_area.add_controller(click_ctrl);
+}
+
+// 連続表示で、ページ送りや目次などで移った現在のページが画面になければ、その先頭までスクロールする
+// (スクロールやクリックで選んだページは、見えている間は動かさない)
+pub fn reveal_current_page(drawing_area: &DrawingArea, ui_state: &Rc<RefCell<UiState>>, engine: &PdfEngine) {
+    if engine.view_mode != ViewMode::Continuous {
+        return;
+    }
+    let Some(scroll) = scrolled_window(drawing_area) else { return };
+    let adjustment = scroll.vadjustment();
+    let page = engine.get_current_page_number();
+    let (scale, followed, target) = {
+        let ui = ui_state.borrow();
+        (ui.scale, ui.followed_page, ui.scroll_target)
+    };
+    let Some(page_top) = engine.page_offset(page, scale) else { return };
+
+    // スクロール中の行き先があれば、そこを見ているものとして扱う
+    let view_top = target.unwrap_or(adjustment.value());
+    let view_bottom = view_top + adjustment.page_size();
+    let page_bottom = page_top + engine.get_page_size().map_or(0.0, |(_, pdf_h)| pdf_h * scale);
+    if followed == Some(page) && page_top < view_bottom && page_bottom > view_top {
+        return;
+    }
+    ui_state.borrow_mut().followed_page = Some(page);
+    if engine.page_in_view(view_top, adjustment.page_size(), scale) != Some(page) {
+        scroll_to(drawing_area, ui_state, page_top - PAGE_MARGIN);
+    }
+}
+
+// ズーム (連続表示では、画面の上端に見えていた位置がずれないようにスクロールも合わせる)
+pub fn zoom_by(drawing_area: &DrawingArea, ui_state: &Rc<RefCell<UiState>>, engine: &PdfEngine, delta: f64) {
+    let old_scale = ui_state.borrow().scale;
+    let new_scale = (old_scale + delta).max(MIN_SCALE);
+    ui_state.borrow_mut().scale = new_scale;
+    if engine.view_mode == ViewMode::Continuous {
+        let offset = engine.rescale_offset(scroll_offset(drawing_area), old_scale, new_scale);
+        scroll_to(drawing_area, ui_state, offset);
+    }
+    drawing_area.queue_draw();
+}
+
+fn scrolled_window(drawing_area: &DrawingArea) -> Option<ScrolledWindow> {
+    drawing_area.ancestor(ScrolledWindow::static_type()).and_downcast::<ScrolledWindow>()
+}
+
+// PDFエリアの今の縦スクロール量 (戻る・進むの履歴に残す)
+pub fn scroll_offset(drawing_area: &DrawingArea) -> f64 {
+    scrolled_window(drawing_area)
+        .map(|scroll| scroll.vadjustment().value())
+        .unwrap_or(0.0)
+}
+
+// 戻る・進むで、履歴に残したズームとスクロールに戻す
+pub fn restore_view(drawing_area: &DrawingArea, ui_state: &Rc<RefCell<UiState>>, position: ViewPosition) {
+    {
+        let mut ui = ui_state.borrow_mut();
+        ui.scale = position.scale;
+        ui.followed_page = Some(position.page);
+    }
+    scroll_to(drawing_area, ui_state, position.scroll);
+}
+
+// PDFエリアを縦にスクロールする
+// ページや倍率が変わると描画時に DrawingArea の大きさが変わって届かないことがあるので、
+// 行き先を覚えておき、大きさが変わったら当て直す (connect_page_follow)
+pub fn scroll_to(drawing_area: &DrawingArea, ui_state: &Rc<RefCell<UiState>>, offset: f64) {
+    let Some(scroll) = scrolled_window(drawing_area) else { return };
+    ui_state.borrow_mut().scroll_target = Some(offset);
+    scroll.vadjustment().set_value(offset);
+}
+
+// 外部リンクは、確認してからブラウザなどで開く
+fn confirm_open_uri(drawing_area: &DrawingArea, uri: String) {
+    let Some(window) = drawing_area.root().and_downcast::<gtk4::Window>() else { return };
+
+    let dialog = gtk4::AlertDialog::builder()
+        .modal(true)
+        .message("Open this link?")
+        .detail(uri.as_str())
+        .buttons(["Cancel", "Open"])
+        .cancel_button(0)
+        .default_button(1)
+        .build();
+
+    let parent = window.clone();
+    dialog.choose(Some(&parent), None::<&gtk4::gio::Cancellable>, move |response| {
+        if response.is_ok_and(|button| button == 1) {
+            gtk4::UriLauncher::new(&uri).launch(Some(&window), None::<&gtk4::gio::Cancellable>, |result| {
+                if let Err(e) = result {
+                    eprintln!("Link Error: {}", e);
+                }
+            });
+        }
+    });
+}
@@ -1,13 +1,17 @@
 use gtk4::prelude::*;
 use gtk4::{
-    gdk, Box as GtkBox, Button, DrawingArea, Entry, GestureClick, Label, 
-    Orientation, Popover, TextView, TextBuffer, ScrolledWindow, EventControllerKey
+    gdk, Box as GtkBox, Button, CheckButton, DrawingArea, DropDown, Entry, GestureClick, Grid, Label, 
+    Orientation, Popover, SpinButton, TextView, TextBuffer, ScrolledWindow, EventControllerKey
 };
 use gtk4::ApplicationWindow;
+// ColorButton は GTK 4.10 で非推奨になったが、まだ ColorDialogButton に置き換えていない
+#[allow(deprecated)]
+use gtk4::ColorButton;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use crate::engine::PdfEngine;
-use crate::ui::UiState;
+use crate::annotations::{AnnotationKind, FontFamily, MarkupStyle, TextStyle, DEFAULT_BACKGROUND};
+use crate::ui::{clipboard, main_content, UiState};
 
 pub fn setup(
     window: &ApplicationWindow,
@@ -23,12 +27,104 @@ pub fn setup(
     let action_btn = Button::with_label(" ➕ Add Annotation ");
     action_btn.set_has_frame(false);
     menu_box.append(&action_btn);
-    
-    popover.set_child(Some(&menu_box));
-    popover.set_parent(drawing_area);
+
+    // 何もない場所で右クリックしたときだけ表示する、付箋の作成ボタン
+    let note_btn = Button::with_label(" 📌 Add Sticky Note ");
+    note_btn.set_has_frame(false);
+    menu_box.append(&note_btn);
+
+    // テキストを選択しているときだけ表示する、マークアップ作成ボタン
+    let markup_box = GtkBox::new(Orientation::Vertical, 0);
+    for style in MarkupStyle::ALL {
+        let btn = Button::with_label(&format!(" 🖍 {} ", style.label()));
+        btn.set_has_frame(false);
+
+        let eng_markup = engine.clone();
+        let area_markup = drawing_area.clone();
+        let popover_markup = popover.clone();
+        btn.connect_clicked(move |_| {
+            popover_markup.popdown();
+            if let Err(e) = eng_markup.borrow_mut().add_markup_annotation(style) {
+                eprintln!("Error: {}", e);
+            }
+            area_markup.queue_draw();
+        });
+        markup_box.append(&btn);
+    }
+    menu_box.append(&markup_box);
 
     // ★追加: どの状態（新規追加か、既存の編集か）を保持する変数
     let target_annot_id: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    // 右クリックした位置 (PDF座標)。貼り付け位置に使う
+    let click_pdf_pos: Rc<RefCell<(f64, f64)>> = Rc::new(RefCell::new((0.0, 0.0)));
+
+    // アノテーションの上で右クリックしたときだけ表示する、削除・複製・コピー
+    let annot_box = GtkBox::new(Orientation::Vertical, 0);
+    let btn_delete = Button::with_label(" 🗑 Delete ");
+    let btn_duplicate = Button::with_label(" ⧉ Duplicate ");
+    let btn_cut = Button::with_label(" ✂ Cut ");
+    let btn_copy = Button::with_label(" 📄 Copy ");
+    for btn in [&btn_delete, &btn_duplicate, &btn_cut, &btn_copy] {
+        btn.set_has_frame(false);
+        annot_box.append(btn);
+    }
+    menu_box.append(&annot_box);
+
+    let btn_paste = Button::with_label(" 📋 Paste ");
+    btn_paste.set_has_frame(false);
+    menu_box.append(&btn_paste);
+
+    let eng_delete = engine.clone();
+    let area_delete = drawing_area.clone();
+    let popover_delete = popover.clone();
+    let target_id_delete = target_annot_id.clone();
+    btn_delete.connect_clicked(move |_| {
+        popover_delete.popdown();
+        if let Some(id) = target_id_delete.borrow().as_ref() {
+            eng_delete.borrow_mut().delete_annotation(id);
+        }
+        area_delete.queue_draw();
+    });
+
+    let eng_duplicate = engine.clone();
+    let area_duplicate = drawing_area.clone();
+    let popover_duplicate = popover.clone();
+    let target_id_duplicate = target_annot_id.clone();
+    btn_duplicate.connect_clicked(move |_| {
+        popover_duplicate.popdown();
+        if let Some(id) = target_id_duplicate.borrow().as_ref() {
+            eng_duplicate.borrow_mut().duplicate_annotation(id);
+        }
+        area_duplicate.queue_draw();
+    });
+
+    for (btn, cut) in [(&btn_cut, true), (&btn_copy, false)] {
+        let eng_copy = engine.clone();
+        let area_copy = drawing_area.clone();
+        let popover_copy = popover.clone();
+        let target_id_copy = target_annot_id.clone();
+        btn.connect_clicked(move |_| {
+            popover_copy.popdown();
+            let Some(id) = target_id_copy.borrow().clone() else { return };
+            eng_copy.borrow_mut().active_annotation_id = Some(id);
+            clipboard::copy_active_annotation(&eng_copy, &area_copy.clipboard(), cut);
+            area_copy.queue_draw();
+        });
+    }
+
+    let eng_paste = engine.clone();
+    let area_paste = drawing_area.clone();
+    let popover_paste = popover.clone();
+    let click_pos_paste = click_pdf_pos.clone();
+    btn_paste.connect_clicked(move |_| {
+        popover_paste.popdown();
+        let area_done = area_paste.clone();
+        let at = *click_pos_paste.borrow();
+        clipboard::paste(eng_paste.clone(), &area_paste.clipboard(), Some(at), move || area_done.queue_draw());
+    });
+    
+    popover.set_child(Some(&menu_box));
+    popover.set_parent(drawing_area);
 
     // 2. 右クリックのハンドラー
     let right_click = GestureClick::new();
@@ -39,26 +135,23 @@ pub fn setup(
     let eng_click = engine.clone();
     let target_id_click = target_annot_id.clone();
     let btn_click = action_btn.clone();
+    let markup_box_click = markup_box.clone();
+    let annot_box_click = annot_box.clone();
+    let note_btn_click = note_btn.clone();
+    let click_pos_click = click_pdf_pos.clone();
     let area_click = drawing_area.clone();
 
     right_click.connect_pressed(move |_, _, x, y| {
         // クリック位置を保存
         ui_click.borrow_mut().last_click_pos = Some((x, y));
 
-        let eng = eng_click.borrow();
+        let mut eng = eng_click.borrow_mut();
         let ui = ui_click.borrow();
         
         // --- 座標変換 (UI座標 → PDF座標) ---
-        let scale = ui.scale;
-        let area_w = area_click.width() as f64;
-        let (pdf_w, _) = eng.get_page_size().unwrap_or((0.0, 0.0));
-        let draw_w = pdf_w * scale;
-        
-        let offset_x = if area_w > draw_w { (area_w - draw_w) / 2.0 } else { 0.0 };
-        let offset_y = 20.0;
-        
-        let pdf_x = (x - offset_x) / scale;
-        let pdf_y = (y - offset_y) / scale;
+        // 連続表示・見開きで別のページを右クリックしたら、そのページを現在のページにする
+        let (pdf_x, pdf_y) = main_content::focus_page_at(x, y, &mut eng, ui.scale, area_click.width() as f64);
+        *click_pos_click.borrow_mut() = (pdf_x, pdf_y);
 
         // --- 当たり判定 ---
         if let Some(hit_id) = eng.hit_test_annotation(pdf_x, pdf_y) {
@@ -70,6 +163,9 @@ pub fn setup(
             *target_id_click.borrow_mut() = None;
             btn_click.set_label(" ➕ Add Annotation ");
         }
+        markup_box_click.set_visible(target_id_click.borrow().is_none() && eng.has_text_selection());
+        annot_box_click.set_visible(target_id_click.borrow().is_some());
+        note_btn_click.set_visible(target_id_click.borrow().is_none());
 
         // Popoverを表示
         let rect = gtk4::gdk::Rectangle::new(x as i32, y as i32, 1, 1);
@@ -98,50 +194,82 @@ pub fn setup(
         
         // 座標変換
         let eng = engine_add.borrow();
-        let scale = ui.scale;
-        let area_w = area_add.width() as f64;
-        let (pdf_w, _) = eng.get_page_size().unwrap_or((0.0, 0.0));
-        let draw_w = pdf_w * scale;
-        let offset_x = if area_w > draw_w { (area_w - draw_w) / 2.0 } else { 0.0 };
-        let offset_y = 20.0;
-        
-        let pdf_x = (click_x - offset_x) / scale;
-        let pdf_y = (click_y - offset_y) / scale;
+        let (_, pdf_x, pdf_y) = main_content::convert_to_pdf_coords(click_x, click_y, &eng, ui.scale, area_add.width() as f64);
 
-        // 編集モードなら既存のテキストを取得
+        // 編集モードなら既存のテキストと見た目を取得 (新規なら前回選んだ見た目)
         let target_id = target_id_action.borrow().clone();
-        let initial_text = if let Some(ref id) = target_id {
-            eng.annotations.iter().find(|a| &a.id == id).map(|a| a.content.clone()).unwrap_or_default()
-        } else {
-            String::new()
+        let target = target_id.as_ref().and_then(|id| eng.annotations.iter().find(|a| &a.id == id));
+        let initial_text = target.map(|a| a.content.clone()).unwrap_or_default();
+        let initial_style = match target {
+            Some(a) if matches!(a.kind, AnnotationKind::Note { .. }) => None,
+            Some(a) => Some((a.font_size.unwrap_or(14.0), a.text_style.clone())),
+            None => Some((ui.note_font_size, ui.note_style.clone())),
         };
 
         // Engineの借用を解除してからダイアログを表示
         drop(eng);
+        drop(ui);
 
         let parent = window_weak.upgrade().unwrap();
         show_annotation_dialog(
             &parent, 
             engine_add.clone(), 
+            ui_add.clone(),
             area_add.clone(), 
             pdf_x, 
             pdf_y, 
             target_id,     // IDを渡す (Noneなら新規作成)
-            &initial_text  // 初期テキスト
+            &initial_text, // 初期テキスト
+            initial_style,
+        );
+    });
+
+    // 付箋は右クリックした位置にアイコンを置く
+    let engine_note = engine.clone();
+    let ui_note = ui_state.clone();
+    let area_note = drawing_area.clone();
+    let popover_note = popover.clone();
+    let window_note = window.downgrade();
+    let click_pos_note = click_pdf_pos.clone();
+    note_btn.connect_clicked(move |_| {
+        popover_note.popdown();
+        let (pdf_x, pdf_y) = *click_pos_note.borrow();
+        let Some(parent) = window_note.upgrade() else { return };
+        show_annotation_dialog(
+            &parent,
+            engine_note.clone(),
+            ui_note.clone(),
+            area_note.clone(),
+            pdf_x,
+            pdf_y,
+            None,
+            "",
+            None,
         );
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 fn show_annotation_dialog(
     parent: &ApplicationWindow,
     engine: Rc<RefCell<PdfEngine>>,
+    ui_state: Rc<RefCell<UiState>>,
     drawing_area: DrawingArea,
     x: f64,
     y: f64,
     target_id: Option<String>,
     initial_text: &str,
+    // テキスト注釈の文字サイズと見た目。None なら付箋 (見た目の設定は出さない)
+    initial_style: Option<(f32, TextStyle)>,
 ) {
-    let title = if target_id.is_some() { "Edit Annotation" } else { "Add Annotation" };
+    let is_note = initial_style.is_none();
+    let (initial_font_size, initial_style) = initial_style.unwrap_or((14.0, TextStyle::default()));
+    let title = match (is_note, target_id.is_some()) {
+        (true, true) => "Edit Sticky Note",
+        (true, false) => "Add Sticky Note",
+        (false, true) => "Edit Annotation",
+        (false, false) => "Add Annotation",
+    };
     
     let dialog = ApplicationWindow::builder()
         .title(title)
@@ -180,8 +308,57 @@ fn show_annotation_dialog(
 
     btn_box.append(&btn_cancel);
     btn_box.append(&btn_ok);
-    vbox.append(&Label::new(Some("Enter text (Ctrl+Enter for newline, $...$ for MathJax):")));
+
+    // 見た目の設定 (書体・文字サイズ・文字色・背景・枠線・不透明度)
+    let style_grid = Grid::builder().row_spacing(6).column_spacing(10).build();
+    let rgba = |(r, g, b): (f64, f64, f64)| gdk::RGBA::new(r as f32, g as f32, b as f32, 1.0);
+
+    let font_family = DropDown::from_strings(&FontFamily::ALL.map(|f| f.label()));
+    let family_index = FontFamily::ALL.iter().position(|f| *f == initial_style.font_family).unwrap_or(0);
+    font_family.set_selected(family_index as u32);
+    let font_size = SpinButton::with_range(6.0, 72.0, 1.0);
+    font_size.set_value(initial_font_size as f64);
+    #[allow(deprecated)]
+    let text_color = ColorButton::with_rgba(&rgba(initial_style.text_color));
+    let background_check = CheckButton::with_label("Fill");
+    background_check.set_active(initial_style.background.is_some());
+    #[allow(deprecated)]
+    let background_color = ColorButton::with_rgba(&rgba(initial_style.background.unwrap_or(DEFAULT_BACKGROUND)));
+    let border_width = SpinButton::with_range(0.0, 10.0, 0.5);
+    border_width.set_value(initial_style.border_width);
+    let opacity = SpinButton::with_range(0.1, 1.0, 0.1);
+    opacity.set_value(initial_style.opacity.unwrap_or(1.0));
+    // 不透明度は、指定済みか、ここで変えたときだけ保存する (それまでは既定の半透明の背景のまま)
+    let opacity_set = Rc::new(Cell::new(initial_style.opacity.is_some()));
+    let opacity_changed = opacity_set.clone();
+    opacity.connect_value_changed(move |_| opacity_changed.set(true));
+
+    let font_box = GtkBox::new(Orientation::Horizontal, 6);
+    font_box.append(&font_family);
+    font_box.append(&font_size);
+    let background_box = GtkBox::new(Orientation::Horizontal, 6);
+    background_box.append(&background_check);
+    background_box.append(&background_color);
+
+    let rows: [(&str, &gtk4::Widget); 5] = [
+        ("Font", font_box.upcast_ref()),
+        ("Text color", text_color.upcast_ref()),
+        ("Background", background_box.upcast_ref()),
+        ("Border width", border_width.upcast_ref()),
+        ("Opacity", opacity.upcast_ref()),
+    ];
+    for (row, (label, widget)) in rows.into_iter().enumerate() {
+        let label = Label::new(Some(label));
+        label.set_halign(gtk4::Align::Start);
+        style_grid.attach(&label, 0, row as i32, 1, 1);
+        style_grid.attach(widget, 1, row as i32, 1, 1);
+    }
+
+    let hint = if is_note { "Enter note:" } else { "Enter text (Ctrl+Enter for newline, $...$ for MathJax):" };
+    vbox.append(&Label::new(Some(hint)));
     vbox.append(&scroll);
+    style_grid.set_visible(!is_note);
+    vbox.append(&style_grid);
     vbox.append(&btn_box);
     dialog.set_child(Some(&vbox));
 
@@ -195,18 +372,51 @@ fn show_annotation_dialog(
     btn_ok.connect_clicked(move |_| {
         let bounds = buffer_clone.bounds();
         let text = buffer_clone.text(&bounds.0, &bounds.1, false).trim().to_string();
+
+        #[allow(deprecated)]
+        let color = |btn: &ColorButton| {
+            let c = btn.rgba();
+            (c.red() as f64, c.green() as f64, c.blue() as f64)
+        };
+        let size = font_size.value() as f32;
+        let style = TextStyle {
+            font_family: FontFamily::ALL.get(font_family.selected() as usize).copied().unwrap_or(FontFamily::Serif),
+            text_color: color(&text_color),
+            background: background_check.is_active().then(|| color(&background_color)),
+            border_width: border_width.value(),
+            opacity: opacity_set.get().then(|| opacity.value()),
+        };
         
         if !text.is_empty() {
             let mut eng = engine.borrow_mut();
+            // 本文と見た目の変更は、まとめて1回で元に戻せるようにする
+            eng.begin_edit_group();
             if let Some(ref id) = target_id {
                 eng.active_annotation_id = Some(id.clone());
                 eng.update_active_annotation_content(&text);
+            } else if is_note {
+                eng.add_note(&text, x, y);
             } else {
                 if let Err(e) = eng.add_annotation(&text, x, y) {
                     eprintln!("Error: {}", e);
                 }
             }
+            if is_note {
+                eng.end_edit_group();
+                drawing_area.queue_draw();
+                dialog_ok.close();
+                return;
+            }
+            if let Some(id) = eng.active_annotation_id.clone() {
+                eng.set_annotation_style(&id, size, style.clone());
+            }
+            eng.end_edit_group();
             drawing_area.queue_draw();
+
+            // 次に作るテキスト注釈も同じ見た目で始める
+            let mut ui = ui_state.borrow_mut();
+            ui.note_font_size = size;
+            ui.note_style = style;
         }
         dialog_ok.close();
     });
@@ -1,15 +1,52 @@
 use gtk4::prelude::*;
 use gtk4::{
-    Box as GtkBox, Label, ListBox, ListBoxRow, Orientation, ScrolledWindow, 
+    Box as GtkBox, Button, DrawingArea, DropDown, Entry, Label, ListBox, ListBoxRow, Orientation, ScrolledWindow, 
     SelectionMode, Align
 };
 use std::rc::Rc;
 use std::cell::RefCell;
 use crate::engine::PdfEngine;
+use crate::annotations::{AnnotationData, AnnotationKind, Reply, ReviewState};
+use chrono::{DateTime, FixedOffset, Local};
 
 pub struct AnnotationWidget {
     pub list: ListBox,
     pub scroll: ScrolledWindow,
+    // 返信欄から注釈を変更するために持っておく
+    engine: Rc<RefCell<PdfEngine>>,
+    drawing_area: DrawingArea,
+    // 今の一覧の中身。変わったときだけ作り直す (入力途中の返信を消さないため)
+    shown: Rc<RefCell<Option<Vec<RowKey>>>>,
+}
+
+// 一覧の1行に出している値
+#[derive(PartialEq)]
+struct RowKey {
+    id: String,
+    page: u32,
+    y: f64,
+    heading: String,
+    content: String,
+    author: Option<String>,
+    created: Option<DateTime<FixedOffset>>,
+    modified: Option<DateTime<FixedOffset>>,
+    replies: Vec<Reply>,
+}
+
+impl RowKey {
+    fn new(ann: &AnnotationData) -> Self {
+        Self {
+            id: ann.id.clone(),
+            page: ann.page,
+            y: ann.y,
+            heading: heading(ann),
+            content: ann.content.clone(),
+            author: ann.author.clone(),
+            created: ann.created,
+            modified: ann.modified,
+            replies: ann.replies.clone(),
+        }
+    }
 }
 
 impl AnnotationWidget {
@@ -39,41 +76,195 @@ impl AnnotationWidget {
             }
         });
 
-        Self { list, scroll }
+        Self { list, scroll, engine, drawing_area: drawing_area.clone(), shown: Rc::new(RefCell::new(None)) }
     }
 
     pub fn update_annotations(&self, engine: &PdfEngine) {
-        while let Some(child) = self.list.first_child() {
-            self.list.remove(&child);
+        fill_list(&self.list, &self.shown, engine, &self.engine, &self.drawing_area);
+    }
+}
+
+fn heading(ann: &AnnotationData) -> String {
+    match &ann.kind {
+        AnnotationKind::FreeText => format!("Page {}", ann.page),
+        AnnotationKind::Markup { style, .. } => format!("Page {} · {}", ann.page, style.label()),
+        AnnotationKind::Ink { .. } => format!("Page {} · Ink", ann.page),
+        AnnotationKind::Shape { shape, .. } => format!("Page {} · {}", ann.page, shape.label()),
+        AnnotationKind::Note { .. } => format!("Page {} · Note", ann.page),
+    }
+}
+
+fn fill_list(
+    list: &ListBox,
+    shown: &Rc<RefCell<Option<Vec<RowKey>>>>,
+    engine: &PdfEngine,
+    engine_rc: &Rc<RefCell<PdfEngine>>,
+    drawing_area: &DrawingArea,
+) {
+    let keys: Vec<RowKey> = engine.annotations.iter().map(RowKey::new).collect();
+    if shown.borrow().as_ref() == Some(&keys) {
+        return;
+    }
+    *shown.borrow_mut() = Some(keys);
+
+    while let Some(child) = list.first_child() {
+        list.remove(&child);
+    }
+
+    if engine.annotations.is_empty() {
+        let l = Label::new(Some("No annotations"));
+        l.set_margin_top(10);
+        list.append(&l);
+        return;
+    }
+
+    for ann in &engine.annotations {
+        let row = ListBoxRow::new();
+        // クリック用のメタデータ (pageは内部0-basedに変換)
+        row.set_widget_name(&format!("{},{}", ann.page as i32 - 1, ann.y));
+
+        let vbox = GtkBox::new(Orientation::Vertical, 2);
+
+        let page_lbl = Label::new(Some(&heading(ann)));
+        page_lbl.set_halign(Align::Start);
+        page_lbl.add_css_class("caption-heading");
+
+        let content_lbl = Label::new(Some(&ann.content));
+        content_lbl.set_halign(Align::Start);
+        content_lbl.set_ellipsize(gtk4::pango::EllipsizeMode::End);
+        content_lbl.set_max_width_chars(20);
+
+        vbox.append(&page_lbl);
+        vbox.append(&content_lbl);
+
+        // 作成者と日時 (更新されていれば更新日時も)
+        let mut meta: Vec<String> = Vec::new();
+        if let Some(author) = &ann.author {
+            meta.push(author.clone());
+        }
+        if let Some(created) = ann.created {
+            meta.push(format_date(&created));
+        }
+        if let Some(modified) = ann.modified.filter(|m| Some(*m) != ann.created) {
+            meta.push(format!("edited {}", format_date(&modified)));
+        }
+        if !meta.is_empty() {
+            let meta_lbl = Label::new(Some(&meta.join(" · ")));
+            meta_lbl.set_halign(Align::Start);
+            meta_lbl.set_ellipsize(gtk4::pango::EllipsizeMode::End);
+            meta_lbl.set_max_width_chars(20);
+            meta_lbl.add_css_class("dim-label");
+            meta_lbl.add_css_class("caption");
+            vbox.append(&meta_lbl);
         }
 
-        if engine.annotations.is_empty() {
-            let l = Label::new(Some("No annotations"));
-            l.set_margin_top(10);
-            self.list.append(&l);
-            return;
+        if let Some(state) = ann.review_state() {
+            let state_lbl = Label::new(Some(&format!("Status: {}", state.label())));
+            state_lbl.set_halign(Align::Start);
+            state_lbl.add_css_class("accent");
+            vbox.append(&state_lbl);
         }
 
-        for ann in &engine.annotations {
-            let row = ListBoxRow::new();
-            // クリック用のメタデータ (pageは内部0-basedに変換)
-            row.set_widget_name(&format!("{},{}", ann.page as i32 - 1, ann.y));
+        for reply in &ann.replies {
+            vbox.append(&reply_view(reply));
+        }
+        vbox.append(&reply_box(list, shown, engine_rc, drawing_area, &ann.id));
 
-            let vbox = GtkBox::new(Orientation::Vertical, 2);
+        row.set_child(Some(&vbox));
+        list.append(&row);
+    }
+}
 
-            let page_lbl = Label::new(Some(&format!("Page {}", ann.page)));
-            page_lbl.set_halign(Align::Start);
-            page_lbl.add_css_class("caption-heading");
+// スレッドの返信1件 (作成者・日時と、状態の変更・本文)
+fn reply_view(reply: &Reply) -> GtkBox {
+    let vbox = GtkBox::new(Orientation::Vertical, 0);
+    vbox.set_margin_start(12);
+    vbox.set_margin_top(4);
 
-            let content_lbl = Label::new(Some(&ann.content));
-            content_lbl.set_halign(Align::Start);
-            content_lbl.set_ellipsize(gtk4::pango::EllipsizeMode::End);
-            content_lbl.set_max_width_chars(20);
+    let mut header = vec![reply.author.clone().unwrap_or_else(|| "Unknown".to_string())];
+    if let Some(created) = reply.created {
+        header.push(format_date(&created));
+    }
+    let header_lbl = Label::new(Some(&header.join(" · ")));
+    header_lbl.set_halign(Align::Start);
+    header_lbl.set_ellipsize(gtk4::pango::EllipsizeMode::End);
+    header_lbl.set_max_width_chars(20);
+    header_lbl.add_css_class("dim-label");
+    header_lbl.add_css_class("caption");
+    vbox.append(&header_lbl);
 
-            vbox.append(&page_lbl);
-            vbox.append(&content_lbl);
-            row.set_child(Some(&vbox));
-            self.list.append(&row);
-        }
+    let mut body = Vec::new();
+    if let Some(state) = reply.state {
+        body.push(format!("Set status to {}", state.label()));
+    }
+    if !reply.content.is_empty() {
+        body.push(reply.content.clone());
     }
-}
\ No newline at end of file
+    let body_lbl = Label::new(Some(&body.join("\n")));
+    body_lbl.set_halign(Align::Start);
+    body_lbl.set_xalign(0.0);
+    body_lbl.set_wrap(true);
+    body_lbl.set_max_width_chars(20);
+    vbox.append(&body_lbl);
+    vbox
+}
+
+// 返信欄 (本文と、変更するなら状態を選んで送る)
+fn reply_box(
+    list: &ListBox,
+    shown: &Rc<RefCell<Option<Vec<RowKey>>>>,
+    engine: &Rc<RefCell<PdfEngine>>,
+    drawing_area: &DrawingArea,
+    id: &str,
+) -> GtkBox {
+    let vbox = GtkBox::new(Orientation::Vertical, 4);
+    vbox.set_margin_start(12);
+    vbox.set_margin_top(4);
+
+    let entry = Entry::new();
+    entry.set_placeholder_text(Some("Reply…"));
+    // 先頭は「状態を変えない」
+    let states: Vec<&str> = std::iter::once("Status…")
+        .chain(ReviewState::REVIEW.iter().map(|s| s.label()))
+        .collect();
+    let state = DropDown::from_strings(&states);
+    let btn_reply = Button::with_label("Reply");
+
+    let hbox = GtkBox::new(Orientation::Horizontal, 4);
+    hbox.append(&state);
+    hbox.append(&btn_reply);
+    vbox.append(&entry);
+    vbox.append(&hbox);
+
+    // 行が一覧を持ち続けないよう、一覧は弱参照で持つ
+    let list_weak = list.downgrade();
+    let shown = shown.clone();
+    let engine = engine.clone();
+    let area = drawing_area.clone();
+    let id = id.to_string();
+    let entry_send = entry.clone();
+    let send = move || {
+        let text = entry_send.text().trim().to_string();
+        let selected = match state.selected() {
+            0 => None,
+            i => ReviewState::REVIEW.get(i as usize - 1).copied(),
+        };
+        if text.is_empty() && selected.is_none() {
+            return;
+        }
+        engine.borrow_mut().add_reply(&id, &text, selected);
+        area.queue_draw();
+        if let Some(list) = list_weak.upgrade() {
+            fill_list(&list, &shown, &engine.borrow(), &engine, &area);
+        }
+    };
+    let send_enter = send.clone();
+    entry.connect_activate(move |_| send_enter());
+    btn_reply.connect_clicked(move |_| send());
+    vbox
+}
+
+// 一覧に出す日時 (この PC の時刻に直して表示する)
+fn format_date(date: &DateTime<FixedOffset>) -> String {
+    date.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()
+}
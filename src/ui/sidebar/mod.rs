@@ -5,6 +5,7 @@ use gtk4::{
 use std::rc::Rc;
 use std::cell::RefCell;
 use crate::engine::PdfEngine;
+use crate::ui::UiState;
 
 // サブモジュールの公開
 pub mod thumbnail;
@@ -34,6 +35,7 @@ pub struct SidebarWidgets {
 
 pub fn build(
     engine: Rc<RefCell<PdfEngine>>,
+    ui_state: Rc<RefCell<UiState>>,
     drawing_area: &gtk4::DrawingArea,
 ) -> SidebarWidgets {
     let container = GtkBox::new(Orientation::Vertical, 0);
@@ -65,13 +67,13 @@ pub fn build(
 
     // 各ウィジェットの初期化
     let thumbnails = ThumbnailWidget::new(engine.clone(), drawing_area);
-    let outline = OutlineWidget::new(engine.clone(), drawing_area);
+    let outline = OutlineWidget::new(engine.clone(), ui_state, drawing_area);
     let annotations = AnnotationWidget::new(engine.clone(), drawing_area);
     let search = SearchWidget::new(engine.clone(), drawing_area);
 
     // スタックに追加 (各ウィジェットはスクロールウィンドウやコンテナを持っている)
     stack.add_named(&thumbnails.scroll, Some("thumbs"));
-    stack.add_named(&outline.scroll, Some("outline"));
+    stack.add_named(&outline.box_container, Some("outline"));
     stack.add_named(&annotations.scroll, Some("annots"));
     stack.add_named(&search.box_container, Some("search"));
 
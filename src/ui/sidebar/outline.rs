@@ -1,67 +1,206 @@
 use gtk4::prelude::*;
 use gtk4::{
-    Box as GtkBox, Label, ListBox, ListBoxRow, Orientation, ScrolledWindow, 
-    Align, SelectionMode
+    Box as GtkBox, Button, DrawingArea, Entry, Label, ListBox, ListBoxRow, Orientation, ScrolledWindow,
+    Align, SelectionMode, DragSource, DropTarget, EventControllerKey, gdk
 };
 use std::rc::Rc;
 use std::cell::RefCell;
 use crate::engine::PdfEngine;
+use crate::ui::{main_content, UiState};
 
-// 目次データの構造体
-#[derive(Clone, Debug)]
-pub struct OutlineItem {
-    pub title: String,
-    pub page_index: Option<i32>, // ページ番号 (ない場合もある)
-    pub level: i32,              // 階層レベル (0, 1, 2...)
-}
+// 目次データの構造体 (PDF との読み書きは crate::outline)
+pub use crate::outline::OutlineItem;
 
 pub struct OutlineWidget {
+    pub box_container: GtkBox,
     pub list: ListBox,
-    pub scroll: ScrolledWindow,
-    // データを保持しておく (クリック時のページジャンプ用)
-    items: RefCell<Vec<OutlineItem>>, 
+    view: OutlineView,
+}
+
+// 行を作り直すのに必要なもの (ボタンやドラッグのハンドラで使い回す)
+#[derive(Clone)]
+struct OutlineView {
+    list: ListBox,
+    engine: Rc<RefCell<PdfEngine>>,
+    // 推測した目次を表示している間だけ出す案内 (受け入れる・消す)
+    suggestion_bar: GtkBox,
 }
 
 impl OutlineWidget {
     pub fn new(
         engine: Rc<RefCell<PdfEngine>>,
-        drawing_area: &gtk4::DrawingArea,
+        ui_state: Rc<RefCell<UiState>>,
+        drawing_area: &DrawingArea,
     ) -> Self {
+        let box_container = GtkBox::new(Orientation::Vertical, 5);
+
         let list = ListBox::new();
         list.set_selection_mode(SelectionMode::Single);
-        
+
         let scroll = ScrolledWindow::builder()
             .child(&list)
             .vexpand(true)
             .build();
 
+        // --- 推測した目次の案内 ---
+        let suggestion_bar = GtkBox::new(Orientation::Vertical, 5);
+        suggestion_bar.set_margin_start(5);
+        suggestion_bar.set_margin_end(5);
+        let suggestion_label = Label::new(Some("Suggested outline from heading sizes. Accept it to save it into the file."));
+        suggestion_label.set_wrap(true);
+        suggestion_label.add_css_class("dim-label");
+        let btn_accept = Button::with_label("Accept");
+        let btn_dismiss = Button::with_label("Dismiss");
+        let suggestion_buttons = GtkBox::new(Orientation::Horizontal, 5);
+        suggestion_buttons.set_halign(Align::Center);
+        suggestion_buttons.append(&btn_accept);
+        suggestion_buttons.append(&btn_dismiss);
+        suggestion_bar.append(&suggestion_label);
+        suggestion_bar.append(&suggestion_buttons);
+        suggestion_bar.set_visible(false);
+
+        let view = OutlineView {
+            list: list.clone(),
+            engine: engine.clone(),
+            suggestion_bar: suggestion_bar.clone(),
+        };
+
+        let view_accept = view.clone();
+        btn_accept.connect_clicked(move |_| {
+            view_accept.engine.borrow_mut().accept_suggested_outline();
+            view_accept.rebuild(None);
+        });
+        let view_dismiss = view.clone();
+        btn_dismiss.connect_clicked(move |_| {
+            view_dismiss.engine.borrow_mut().dismiss_suggested_outline();
+            view_dismiss.rebuild(None);
+        });
+
+        // --- 編集ボタン (しおりの追加・階層・名前の変更・削除) ---
+        // 操作の対象は、リストで選択している項目
+        let btn_add_page = Button::with_label("🔖 Page");
+        btn_add_page.set_tooltip_text(Some("Bookmark this page"));
+        let btn_add_heading = Button::with_label("🔖 Selection");
+        btn_add_heading.set_tooltip_text(Some("Bookmark the selected text as a heading"));
+        let btn_outdent = Button::with_label("⇤");
+        btn_outdent.set_tooltip_text(Some("Outdent"));
+        let btn_indent = Button::with_label("⇥");
+        btn_indent.set_tooltip_text(Some("Indent"));
+        let btn_rename = Button::with_label("✎");
+        btn_rename.set_tooltip_text(Some("Rename (F2)"));
+        let btn_remove = Button::with_label("✕");
+        btn_remove.set_tooltip_text(Some("Remove bookmark (Delete)"));
+
+        let add_row = GtkBox::new(Orientation::Horizontal, 5);
+        add_row.set_halign(Align::Center);
+        add_row.set_margin_top(5);
+        add_row.append(&btn_add_page);
+        add_row.append(&btn_add_heading);
+
+        let edit_row = GtkBox::new(Orientation::Horizontal, 0);
+        edit_row.add_css_class("linked");
+        edit_row.set_halign(Align::Center);
+        for btn in [&btn_outdent, &btn_indent, &btn_rename, &btn_remove] {
+            edit_row.append(btn);
+        }
+
+        box_container.append(&add_row);
+        box_container.append(&edit_row);
+        box_container.append(&suggestion_bar);
+        box_container.append(&scroll);
+
         // --- Click Logic ---
         let eng_outline = engine.clone();
         let area_outline = drawing_area.clone();
-        
+
         list.connect_row_activated(move |_, row| {
-            let name = row.widget_name();
-            if let Ok(page_idx) = name.as_str().parse::<i32>() {
-                if let Ok(mut eng) = eng_outline.try_borrow_mut() {
-                    if eng.jump_to_page(page_idx) {
-                        area_outline.queue_draw();
-                    }
+            if let Ok(mut eng) = eng_outline.try_borrow_mut() {
+                // 章タイトルだけでリンクがない項目もある
+                let Some(page_idx) = eng.outline().get(row.index() as usize).and_then(|item| item.page_index) else { return };
+                // 目次から移動したら、戻るで元の位置に戻れるようにする
+                let scroll = main_content::scroll_offset(&area_outline);
+                if eng.navigate_to(page_idx, scroll, ui_state.borrow().scale) {
+                    area_outline.queue_draw();
+                }
+            }
+        });
+
+        // --- 編集 ---
+        // edit は、変更したら作り直した後に選択する項目の位置を返す
+        let connect_edit = |btn: &Button, edit: fn(&mut PdfEngine, Option<usize>) -> Option<usize>| {
+            let view = view.clone();
+            btn.connect_clicked(move |_| {
+                let selected = view.list.selected_row().map(|row| row.index() as usize);
+                let changed = edit(&mut view.engine.borrow_mut(), selected);
+                if let Some(index) = changed {
+                    view.rebuild(Some(index));
                 }
+            });
+        };
+        connect_edit(&btn_add_page, |eng, _| eng.add_page_bookmark());
+        connect_edit(&btn_add_heading, |eng, _| eng.add_selection_bookmark());
+        connect_edit(&btn_outdent, |eng, selected| {
+            selected.filter(|&index| eng.indent_outline_item(index, -1))
+        });
+        connect_edit(&btn_indent, |eng, selected| {
+            selected.filter(|&index| eng.indent_outline_item(index, 1))
+        });
+        connect_edit(&btn_remove, |eng, selected| {
+            let index = selected.filter(|&index| eng.remove_outline_item(index))?;
+            Some(index.min(eng.outline().len().saturating_sub(1)))
+        });
+
+        let view_rename = view.clone();
+        btn_rename.connect_clicked(move |_| {
+            if let Some(row) = view_rename.list.selected_row() {
+                view_rename.start_rename(&row);
             }
         });
 
-        Self { 
-            list, 
-            scroll,
-            items: RefCell::new(Vec::new()),
+        // F2 で名前の変更、Delete で削除
+        let key_ctrl = EventControllerKey::new();
+        let view_key = view.clone();
+        let btn_remove_key = btn_remove.clone();
+        key_ctrl.connect_key_pressed(move |_, keyval, _, _| {
+            let Some(row) = view_key.list.selected_row() else { return gtk4::glib::Propagation::Proceed };
+            match keyval {
+                gdk::Key::F2 => view_key.start_rename(&row),
+                gdk::Key::Delete | gdk::Key::KP_Delete => btn_remove_key.emit_clicked(),
+                _ => return gtk4::glib::Propagation::Proceed,
+            }
+            gtk4::glib::Propagation::Stop
+        });
+        list.add_controller(key_ctrl);
+
+        Self {
+            box_container,
+            list,
+            view,
         }
     }
 
-    pub fn set_outline(&self, items: Vec<OutlineItem>) {
-        // 1. データを保存
-        *self.items.borrow_mut() = items.clone();
+    pub fn update_outline(&self, engine: &PdfEngine) {
+        self.view.fill(engine);
+        highlight_current(&self.list, engine);
+    }
 
-        // 2. リストをクリア
+    // 現在のページを含む章の行を強調する
+    pub fn highlight_page(&self, engine: &PdfEngine) {
+        highlight_current(&self.list, engine);
+    }
+}
+
+// 開閉ボタンの幅 (子項目のない行は同じ幅だけ空ける)
+const EXPANDER_WIDTH: i32 = 20;
+// 現在の章の行のタイトルに付けるスタイル (太字)
+const CURRENT_CSS_CLASS: &str = "heading";
+
+impl OutlineView {
+    fn fill(&self, engine: &PdfEngine) {
+        let items = engine.outline();
+        self.suggestion_bar.set_visible(engine.is_outline_suggested());
+
+        // 1. リストをクリア
         while let Some(child) = self.list.first_child() {
             self.list.remove(&child);
         }
@@ -73,31 +212,179 @@ impl OutlineWidget {
             return;
         }
 
-        // 3. リストを構築
-        for item in items {
+        // 2. リストを構築 (行の並びは items と同じ)
+        for (index, item) in items.iter().enumerate() {
             let row = ListBoxRow::new();
-            
-            // ページ番号があれば埋め込む、なければ空文字
-            // (章タイトルだけでリンクがない場合もあるため)
-            if let Some(page) = item.page_index {
-                row.set_widget_name(&page.to_string());
-            }
 
             let hbox = GtkBox::new(Orientation::Horizontal, 5);
             hbox.set_margin_top(5);
             hbox.set_margin_bottom(5);
-            
+
             // 階層レベルに応じたインデント
-            hbox.set_margin_start(item.level * 20); 
+            hbox.set_margin_start(item.level * 20);
+
+            // 子項目があれば開閉ボタン、なければ同じ幅の空白 (タイトルの位置を揃える)
+            if has_children(items, index) {
+                let toggle = Button::with_label(if item.expanded { "▾" } else { "▸" });
+                toggle.set_has_frame(false);
+                toggle.set_width_request(EXPANDER_WIDTH);
+                let view = self.clone();
+                toggle.connect_clicked(move |btn| {
+                    let mut eng = view.engine.borrow_mut();
+                    let Some(expanded) = eng.outline().get(index).map(|item| !item.expanded) else { return };
+                    eng.set_outline_expanded(index, expanded);
+                    btn.set_label(if expanded { "▾" } else { "▸" });
+                    update_visibility(&view.list, eng.outline());
+                    highlight_current(&view.list, &eng);
+                });
+                hbox.append(&toggle);
+            } else {
+                let spacer = GtkBox::new(Orientation::Horizontal, 0);
+                spacer.set_width_request(EXPANDER_WIDTH);
+                hbox.append(&spacer);
+            }
 
             let label = Label::new(Some(&item.title));
             label.set_halign(Align::Start);
             label.set_ellipsize(gtk4::pango::EllipsizeMode::End);
-            
+
             hbox.append(&label);
             row.set_child(Some(&hbox));
-            
+
+            // ドラッグで並べ替え (子項目ごと、落とした行の位置へ動かす)
+            let drag = DragSource::new();
+            drag.set_actions(gdk::DragAction::MOVE);
+            drag.set_content(Some(&gdk::ContentProvider::for_value(&(index as u32).to_value())));
+            row.add_controller(drag);
+
+            let drop = DropTarget::new(u32::static_type(), gdk::DragAction::MOVE);
+            let view = self.clone();
+            drop.connect_drop(move |_, value, _, _| {
+                let Ok(from) = value.get::<u32>() else { return false };
+                let from = from as usize;
+                let Some(moved) = view.engine.borrow_mut().move_outline_item(from, index) else { return false };
+                // ドロップを処理している行を作り直さないよう、後で反映する
+                let view = view.clone();
+                gtk4::glib::idle_add_local_once(move || view.rebuild(Some(moved)));
+                true
+            });
+            row.add_controller(drop);
+
             self.list.append(&row);
         }
+        update_visibility(&self.list, items);
+    }
+
+    // 編集後に作り直して、編集した項目を選択する
+    fn rebuild(&self, select: Option<usize>) {
+        {
+            let eng = self.engine.borrow();
+            self.fill(&eng);
+            highlight_current(&self.list, &eng);
+        }
+        if let Some(row) = select.and_then(|index| self.list.row_at_index(index as i32)) {
+            self.list.select_row(Some(&row));
+            row.grab_focus();
+        }
     }
-}
\ No newline at end of file
+
+    // タイトルをその場で入力欄に置き換える (Enter で確定、Escape で取り消し)
+    fn start_rename(&self, row: &ListBoxRow) {
+        let index = row.index() as usize;
+        let Some(hbox) = row.child().and_downcast::<GtkBox>() else { return };
+        let Some(label) = hbox.last_child().and_downcast::<Label>() else { return };
+
+        let entry = Entry::new();
+        entry.set_text(&label.text());
+        entry.set_hexpand(true);
+        hbox.remove(&label);
+        hbox.append(&entry);
+        entry.grab_focus();
+
+        let view = self.clone();
+        entry.connect_activate(move |entry| {
+            view.engine.borrow_mut().rename_outline_item(index, entry.text().trim());
+            // 入力欄のシグナル処理中に行を作り直さないよう、後で反映する
+            let view = view.clone();
+            gtk4::glib::idle_add_local_once(move || view.rebuild(Some(index)));
+        });
+
+        let key_ctrl = EventControllerKey::new();
+        let view = self.clone();
+        key_ctrl.connect_key_pressed(move |_, keyval, _, _| {
+            if keyval != gdk::Key::Escape {
+                return gtk4::glib::Propagation::Proceed;
+            }
+            let view = view.clone();
+            gtk4::glib::idle_add_local_once(move || view.rebuild(Some(index)));
+            gtk4::glib::Propagation::Stop
+        });
+        entry.add_controller(key_ctrl);
+    }
+}
+
+fn has_children(items: &[OutlineItem], index: usize) -> bool {
+    items.get(index + 1).is_some_and(|next| next.level > items[index].level)
+}
+
+fn parent_of(items: &[OutlineItem], index: usize) -> Option<usize> {
+    let level = items[index].level;
+    items[..index].iter().rposition(|item| item.level < level)
+}
+
+// 現在のページを含む章: 行き先がそのページ以前の項目のうち、いちばん後ろのページを指すもの
+// (同じページに複数あれば、文書順で後ろの項目。小見出しがあればそちらを選ぶ)
+fn current_section(items: &[OutlineItem], page_index: i32) -> Option<usize> {
+    let mut best: Option<(usize, i32)> = None;
+    for (index, item) in items.iter().enumerate() {
+        let Some(page) = item.page_index else { continue };
+        if page <= page_index && best.is_none_or(|(_, best_page)| page >= best_page) {
+            best = Some((index, page));
+        }
+    }
+    best.map(|(index, _)| index)
+}
+
+// 現在の章の行のタイトルを太字にする (閉じた項目の中にあるときは、表示されている親を太字にする)
+fn highlight_current(list: &ListBox, engine: &PdfEngine) {
+    let items = engine.outline();
+    let mut current = current_section(items, engine.get_current_page_number());
+    while let Some(index) = current {
+        if list.row_at_index(index as i32).is_some_and(|row| row.is_visible()) {
+            break;
+        }
+        current = parent_of(items, index);
+    }
+
+    for index in 0..items.len() {
+        let Some(label) = list.row_at_index(index as i32)
+            .and_then(|row| row.child())
+            .and_then(|hbox| hbox.last_child())
+            .and_downcast::<Label>()
+        else {
+            continue;
+        };
+        if current == Some(index) {
+            label.add_css_class(CURRENT_CSS_CLASS);
+        } else {
+            label.remove_css_class(CURRENT_CSS_CLASS);
+        }
+    }
+}
+
+// 閉じている項目の子孫の行を隠す
+fn update_visibility(list: &ListBox, items: &[OutlineItem]) {
+    // 閉じている祖先のうち、いちばん浅いもののレベル
+    let mut collapsed_level: Option<i32> = None;
+    for (index, item) in items.iter().enumerate() {
+        if collapsed_level.is_some_and(|level| item.level <= level) {
+            collapsed_level = None;
+        }
+        if let Some(row) = list.row_at_index(index as i32) {
+            row.set_visible(collapsed_level.is_none());
+        }
+        if collapsed_level.is_none() && !item.expanded && has_children(items, index) {
+            collapsed_level = Some(item.level);
+        }
+    }
+}
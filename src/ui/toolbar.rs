@@ -1,7 +1,12 @@
 use gtk4::prelude::*;
 use gtk4::{
-    Box as GtkBox, Button, Label, Orientation, Separator
+    gdk, Box as GtkBox, Button, CheckButton, DropDown, Entry, Label, Orientation, Separator, SpinButton,
+    ToggleButton
 };
+// ColorButton は GTK 4.10 で非推奨になったが、まだ ColorDialogButton に置き換えていない
+#[allow(deprecated)]
+use gtk4::ColorButton;
+use crate::engine::ViewMode;
 
 
 // 戻り値を「構造体」にして整理してもいいですが、
@@ -12,11 +17,31 @@ pub struct ToolbarWidgets {
     pub btn_open: Button,
     pub btn_save: Button,
     pub btn_save_as: Button,
+    pub incremental_save: CheckButton,
+    pub btn_back: Button,
+    pub btn_forward: Button,
     pub btn_prev: Button,
     pub btn_next: Button,
     pub btn_zoom_in: Button,
     pub btn_zoom_out: Button,
+    pub view_mode: DropDown,
     pub label_page: Label,
+    pub btn_tool_select: ToggleButton,
+    pub btn_tool_ink: ToggleButton,
+    pub btn_tool_eraser: ToggleButton,
+    pub btn_tool_rectangle: ToggleButton,
+    pub btn_tool_ellipse: ToggleButton,
+    pub btn_tool_line: ToggleButton,
+    pub btn_tool_arrow: ToggleButton,
+    pub btn_tool_polygon: ToggleButton,
+    #[allow(deprecated)]
+    pub stroke_color: ColorButton,
+    pub stroke_width: SpinButton,
+    pub fill_check: CheckButton,
+    #[allow(deprecated)]
+    pub fill_color: ColorButton,
+    pub opacity: SpinButton,
+    pub author: Entry,
 }
 
 pub fn build(filename_label: &Label) -> ToolbarWidgets {
@@ -35,36 +60,130 @@ pub fn build(filename_label: &Label) -> ToolbarWidgets {
     toolbar.append(&spacer);
 
     // --- ボタン作成 ---
+    // リンクや目次で移動する前の位置に戻る・進む
+    let btn_back = Button::with_label("⮌");
+    let btn_forward = Button::with_label("⮎");
+    btn_back.set_tooltip_text(Some("Back (Alt+Left)"));
+    btn_forward.set_tooltip_text(Some("Forward (Alt+Right)"));
     let btn_prev = Button::with_label("◀");
     let label_page = Label::new(Some(" - / - "));
     let btn_next = Button::with_label("▶");
     let btn_open = Button::with_label("📂 Open");
     let btn_save = Button::with_label("💾 Save");
     let btn_save_as = Button::with_label("💾 Save As");
+    // 上書き保存・別名保存のどちらにも使う (初期値は button_event で Engine から入れる)
+    let incremental_save = CheckButton::with_label("Incremental");
+    incremental_save.set_tooltip_text(Some("Append changes only when saving (signed PDFs are always saved this way)"));
     let btn_zoom_in = Button::with_label("🔍 Zoom In");
     let btn_zoom_out = Button::with_label("🔍 Zoom Out");
 
+    // ページの並べ方 (並びは ViewMode::ALL と同じ)
+    let view_mode_labels: Vec<&str> = ViewMode::ALL.iter().map(|mode| mode.label()).collect();
+    let view_mode = DropDown::from_strings(&view_mode_labels);
+    view_mode.set_tooltip_text(Some("Page layout"));
+
+    // ツール (どれか1つだけ選択される)
+    let btn_tool_select = ToggleButton::with_label("↖ Select");
+    let btn_tool_ink = ToggleButton::with_label("✏ Ink");
+    let btn_tool_eraser = ToggleButton::with_label("🧽 Eraser");
+    let btn_tool_rectangle = ToggleButton::with_label("▭");
+    let btn_tool_ellipse = ToggleButton::with_label("◯");
+    let btn_tool_line = ToggleButton::with_label("╱");
+    let btn_tool_arrow = ToggleButton::with_label("➚");
+    let btn_tool_polygon = ToggleButton::with_label("⬠");
+    btn_tool_rectangle.set_tooltip_text(Some("Rectangle"));
+    btn_tool_ellipse.set_tooltip_text(Some("Ellipse"));
+    btn_tool_line.set_tooltip_text(Some("Line"));
+    btn_tool_arrow.set_tooltip_text(Some("Arrow"));
+    btn_tool_polygon.set_tooltip_text(Some("Polygon (double-click to finish an open polyline)"));
+    for btn in [
+        &btn_tool_ink, &btn_tool_eraser, &btn_tool_rectangle, &btn_tool_ellipse,
+        &btn_tool_line, &btn_tool_arrow, &btn_tool_polygon,
+    ] {
+        btn.set_group(Some(&btn_tool_select));
+    }
+    btn_tool_select.set_active(true);
+
+    // 線の色と太さ、塗りと不透明度 (UiState の初期値と合わせる)
+    #[allow(deprecated)]
+    let stroke_color = ColorButton::with_rgba(&gdk::RGBA::new(0.85, 0.1, 0.1, 1.0));
+    stroke_color.set_tooltip_text(Some("Stroke color"));
+    let stroke_width = SpinButton::with_range(0.5, 20.0, 0.5);
+    stroke_width.set_value(2.0);
+    stroke_width.set_tooltip_text(Some("Line width"));
+    let fill_check = CheckButton::with_label("Fill");
+    #[allow(deprecated)]
+    let fill_color = ColorButton::with_rgba(&gdk::RGBA::new(1.0, 1.0, 0.6, 1.0));
+    fill_color.set_tooltip_text(Some("Fill color"));
+    let opacity = SpinButton::with_range(0.1, 1.0, 0.1);
+    opacity.set_value(1.0);
+    opacity.set_tooltip_text(Some("Opacity"));
+
+    // 新しく作る注釈の作成者 (初期値は button_event で Engine から入れる)
+    let author = Entry::new();
+    author.set_placeholder_text(Some("Author"));
+    author.set_tooltip_text(Some("Author of new annotations"));
+    author.set_width_chars(12);
+
     // 配置
     toolbar.append(&btn_open);
     toolbar.append(&btn_save);
     toolbar.append(&btn_save_as);
+    toolbar.append(&incremental_save);
     toolbar.append(&Separator::new(Orientation::Vertical));
+    toolbar.append(&btn_back);
+    toolbar.append(&btn_forward);
     toolbar.append(&btn_prev);
     toolbar.append(&label_page);
     toolbar.append(&btn_next);
     toolbar.append(&Separator::new(Orientation::Vertical));
     toolbar.append(&btn_zoom_out);
     toolbar.append(&btn_zoom_in);
+    toolbar.append(&view_mode);
+    toolbar.append(&Separator::new(Orientation::Vertical));
+    toolbar.append(&btn_tool_select);
+    toolbar.append(&btn_tool_ink);
+    toolbar.append(&btn_tool_eraser);
+    toolbar.append(&btn_tool_rectangle);
+    toolbar.append(&btn_tool_ellipse);
+    toolbar.append(&btn_tool_line);
+    toolbar.append(&btn_tool_arrow);
+    toolbar.append(&btn_tool_polygon);
+    toolbar.append(&stroke_color);
+    toolbar.append(&stroke_width);
+    toolbar.append(&fill_check);
+    toolbar.append(&fill_color);
+    toolbar.append(&opacity);
+    toolbar.append(&Separator::new(Orientation::Vertical));
+    toolbar.append(&author);
 
     ToolbarWidgets {
         container: toolbar,
         btn_open,
         btn_save,
         btn_save_as,
+        incremental_save,
+        btn_back,
+        btn_forward,
         btn_prev,
         btn_next,
         btn_zoom_in,
         btn_zoom_out,
+        view_mode,
         label_page,
+        btn_tool_select,
+        btn_tool_ink,
+        btn_tool_eraser,
+        btn_tool_rectangle,
+        btn_tool_ellipse,
+        btn_tool_line,
+        btn_tool_arrow,
+        btn_tool_polygon,
+        stroke_color,
+        stroke_width,
+        fill_check,
+        fill_color,
+        opacity,
+        author,
     }
 }